@@ -3,16 +3,36 @@
 //! Compares extracted registry references against the set of valid paths,
 //! identifies broken references, and attempts to suggest corrections using
 //! explicit rename mappings or a leaf-name heuristic.
+//!
+//! References guarded by an `or` default, a `?` check or `attrByPath` never
+//! fail evaluation when broken; they silently fall back instead. These are
+//! reported as [`Severity::Critical`] and listed before everything else.
 
-use crate::scanner::RegistryRef;
+use crate::registry;
+use crate::renames::Rename;
+use crate::scanner::{ParseError, ReadError, RefKind, RegistryRef, SkippedRef};
 use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 
+/// How urgently a broken reference needs fixing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Evaluation fails at the reference, so the breakage is visible.
+    Error,
+    /// Evaluation silently takes a fallback, so the breakage goes unnoticed.
+    Critical,
+}
+
 /// A broken registry reference with optional fix suggestion.
 #[derive(Debug, Clone, Serialize)]
 pub struct BrokenRef {
     #[serde(flatten)]
     pub reference: RegistryRef,
+    /// True when the reference is guarded and its breakage never fails
+    /// evaluation.
+    pub silent: bool,
+    pub severity: Severity,
     /// Suggested replacement path if one could be determined.
     pub suggestion: Option<String>,
     /// Explanation when no suggestion exists.
@@ -28,21 +48,53 @@ pub struct Diagnostics {
     pub broken_refs: usize,
     pub suggestions_found: usize,
     pub unsuggestable: usize,
+    /// Broken references guarded by `or`, `?` or `attrByPath`.
+    pub silent_refs: usize,
+    /// Selects on a locally shadowed registry name.
+    pub skipped_refs: usize,
+    /// Syntax errors across all scanned files.
+    pub parse_errors: usize,
+    /// Files and directories that could not be read.
+    pub read_errors: usize,
+    /// Registry paths mentioned in comments and docs that were checked.
+    pub total_mentions: usize,
+    /// Mentions of paths that no longer exist.
+    pub broken_mentions: usize,
 }
 
 /// Complete detection results.
 #[derive(Debug, Serialize)]
 pub struct DetectionResult {
     pub broken: Vec<BrokenRef>,
+    /// References that were not checked, with the reason.
+    pub skipped: Vec<SkippedRef>,
+    /// Syntax errors in scanned files. References outside the broken syntax
+    /// are still checked.
+    pub parse_errors: Vec<ParseError>,
+    /// Files and directories that were left out because they could not be
+    /// read.
+    pub read_errors: Vec<ReadError>,
+    /// Stale registry paths in comments and Markdown `nix` blocks, when
+    /// requested. They never affect evaluation.
+    pub mentions: Vec<BrokenRef>,
+    /// Renames inferred with `--auto-renames`, already applied to the
+    /// suggestions.
+    pub renames: Vec<Rename>,
+    /// Renames derived from registry files git detected as moved, used
+    /// ahead of inferred renames.
+    pub moves: Vec<Rename>,
     pub diagnostics: Diagnostics,
 }
 
 /// Analyzes references against valid registry paths.
 ///
 /// Returns broken references with suggestions where possible. For each reference:
-/// 1. Checks if path exists in `valid_paths`
+/// 1. Checks if it is valid (see [`is_valid`])
 /// 2. For broken refs, tries `rename_map` (longest prefix wins)
 /// 3. Falls back to leaf-name matching if rename map fails
+///
+/// Silent (guarded) broken references come first; otherwise input order is
+/// kept.
 pub fn analyze(
     refs: &[RegistryRef],
     valid_paths: &HashSet<String>,
@@ -52,7 +104,7 @@ pub fn analyze(
     let mut valid_count = 0;
 
     for reference in refs {
-        if valid_paths.contains(&reference.path) {
+        if is_valid(reference, valid_paths) {
             valid_count += 1;
         } else {
             let suggestion = suggest_path(&reference.path, valid_paths, rename_map);
@@ -61,17 +113,36 @@ pub fn analyze(
             } else {
                 None
             };
+            let severity = if reference.guarded {
+                Severity::Critical
+            } else {
+                Severity::Error
+            };
             broken.push(BrokenRef {
                 reference: reference.clone(),
+                silent: reference.guarded,
+                severity,
                 suggestion,
                 reason,
             });
         }
     }
 
+    broken.sort_by_key(|b| std::cmp::Reverse(b.severity));
     (broken, valid_count)
 }
 
+/// Returns true if `reference` still points at something.
+///
+/// Path imports are checked against the filesystem, since that is what Nix
+/// resolves them against; every other form is checked against `valid_paths`.
+pub fn is_valid(reference: &RegistryRef, valid_paths: &HashSet<String>) -> bool {
+    match &reference.kind {
+        RefKind::Path { target, .. } => target.exists(),
+        _ => valid_paths.contains(&reference.path),
+    }
+}
+
 /// Attempts to find a valid replacement for `old_path`.
 ///
 /// First applies explicit renames from `rename_map`, selecting the longest matching
@@ -83,15 +154,29 @@ pub fn suggest_path(
     valid_paths: &HashSet<String>,
     rename_map: &HashMap<String, String>,
 ) -> Option<String> {
-    if let Some(new_path) = apply_rename_map(rename_map, old_path) {
-        if valid_paths.contains(&new_path) {
-            return Some(new_path);
-        }
+    if let Some(new_path) = apply_rename_map(rename_map, old_path)
+        && valid_paths.contains(&new_path)
+    {
+        return Some(new_path);
     }
 
     suggest_by_leaf(old_path, valid_paths)
 }
 
+/// Registry paths that must be known to analyze `refs`: each reference's
+/// path and where `rename_map` sends it. Path imports are checked on disk and
+/// need none.
+pub fn queried_paths(
+    refs: &[RegistryRef],
+    rename_map: &HashMap<String, String>,
+) -> Vec<Vec<String>> {
+    refs.iter()
+        .filter(|r| !matches!(r.kind, RefKind::Path { .. }))
+        .flat_map(|r| std::iter::once(r.path.clone()).chain(apply_rename_map(rename_map, &r.path)))
+        .map(|path| registry::split_attrs(&path))
+        .collect()
+}
+
 /// Applies rename mappings using longest-prefix-wins semantics.
 ///
 /// If `old_path` starts with a key from `rename_map`, substitutes that prefix
@@ -160,7 +245,8 @@ fn failure_reason(path: &str, valid_paths: &HashSet<String>) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::scanner::{RegistryRef, extract_registry_refs};
+    use crate::scanner::{PathSegment, RefKind, RegistryRef, extract_registry_refs};
+    use crate::span::Span;
     use std::path::PathBuf;
 
     fn paths(items: &[&str]) -> HashSet<String> {
@@ -176,12 +262,22 @@ mod tests {
 
     fn make_ref(path: &str) -> RegistryRef {
         RegistryRef {
+            base: "registry".to_string(),
             path: path.to_string(),
+            segments: path
+                .split('.')
+                .map(|s| PathSegment::Static(s.to_string()))
+                .collect(),
             file: PathBuf::from("test.nix"),
             line: 1,
             column: 1,
+            end_line: 1,
+            end_column: 1,
+            span: Span::default(),
             start_offset: 0,
             end_offset: 0,
+            guarded: false,
+            kind: RefKind::Select,
         }
     }
 
@@ -470,6 +566,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn queried_paths_include_rename_targets() {
+        let path_import = RegistryRef {
+            kind: RefKind::Path {
+                target: PathBuf::from("registry/svc"),
+                prefix: "../registry".to_string(),
+                suffix: String::new(),
+            },
+            ..make_ref("svc")
+        };
+        let refs = vec![make_ref("home.alice"), make_ref("lib.x"), path_import];
+        let queried = queried_paths(&refs, &renames(&[("home", "users")]));
+        let expected: Vec<Vec<String>> = vec![
+            vec!["home".into(), "alice".into()],
+            vec!["users".into(), "alice".into()],
+            vec!["lib".into(), "x".into()],
+        ];
+        assert_eq!(queried, expected);
+    }
+
+    #[test]
+    fn path_imports_are_checked_on_disk() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("registry/users/alice")).unwrap();
+        let path_ref = |path: &str, target: &str| RegistryRef {
+            kind: RefKind::Path {
+                target: tmp.path().join(target),
+                prefix: "../registry".to_string(),
+                suffix: String::new(),
+            },
+            ..make_ref(path)
+        };
+
+        // The evaluated registry is not consulted for path imports
+        let valid = paths(&["home.alice"]);
+        let refs = vec![
+            path_ref("users.alice", "registry/users/alice"),
+            path_ref("home.alice", "registry/home/alice"),
+        ];
+        let (broken, valid_count) = analyze(&refs, &valid, &HashMap::new());
+        assert_eq!(valid_count, 1);
+        assert_eq!(broken[0].reference.path, "home.alice");
+    }
+
+    #[test]
+    fn guarded_refs_are_silent_and_listed_first() {
+        let valid = paths(&["users.alice", "users.bob"]);
+        let guarded = RegistryRef {
+            guarded: true,
+            ..make_ref("home.bob")
+        };
+        let refs = vec![make_ref("home.alice"), guarded];
+        let (broken, _) = analyze(&refs, &valid, &HashMap::new());
+
+        assert_eq!(broken[0].reference.path, "home.bob");
+        assert!(broken[0].silent);
+        assert_eq!(broken[0].severity, Severity::Critical);
+        assert!(!broken[1].silent);
+        assert_eq!(broken[1].severity, Severity::Error);
+
+        let json = serde_json::to_value(&broken[0]).unwrap();
+        assert_eq!(json["silent"], true);
+        assert_eq!(json["severity"], "critical");
+    }
+
     // ==========================================================================
     // Fixture-based integration tests
     // ==========================================================================
@@ -478,7 +639,7 @@ mod tests {
     fn fixture_deep_nesting_all_suggestions_found() {
         let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests/fixtures/complex-renames/files/deep-nesting.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
         let valid = complex_registry_paths();
         let (broken, _) = analyze(&refs, &valid, &HashMap::new());
 
@@ -498,7 +659,7 @@ mod tests {
     fn fixture_ambiguous_unique_leaves_get_suggestions() {
         let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests/fixtures/complex-renames/files/ambiguous.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
         let valid = complex_registry_paths();
         let (broken, _) = analyze(&refs, &valid, &HashMap::new());
 
@@ -530,7 +691,7 @@ mod tests {
     fn fixture_partial_valid_correct_counts() {
         let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests/fixtures/complex-renames/files/partial-valid.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
         let valid = complex_registry_paths();
         let (broken, valid_count) = analyze(&refs, &valid, &HashMap::new());
 
@@ -545,7 +706,7 @@ mod tests {
     fn fixture_all_valid_no_broken() {
         let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests/fixtures/complex-renames/files/all-valid.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
         let valid = complex_registry_paths();
         let (broken, valid_count) = analyze(&refs, &valid, &HashMap::new());
 
@@ -0,0 +1,245 @@
+//! Persistent cache of per-file scan results.
+//!
+//! Parsing dominates `detect` on large trees, yet most files are unchanged
+//! between runs. Each file's [`FileScan`] is stored alongside a SHA-256 of its
+//! contents and the registry identifiers it was scanned for, so later runs only
+//! re-parse files whose contents or identifiers changed.
+//!
+//! The cache lives in `$XDG_CACHE_HOME/imp-refactor/` (or `~/.cache/imp-refactor/`),
+//! one JSON file per project directory. The whole file is discarded when the
+//! tool version or [`scanner::EXTRACTION_VERSION`] differs from the one that
+//! wrote it.
+
+use crate::scanner::{self, FileScan};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A cached scan of one file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// SHA-256 of the file contents, hex-encoded.
+    pub hash: String,
+    /// Registry identifiers the file was scanned for.
+    pub names: Vec<String>,
+    /// Registry directory path imports were resolved against.
+    pub registry_dir: Option<PathBuf>,
+    /// Whether registry-like module arguments were checked too; see
+    /// [`scanner::RegistryNames::set_infer_args`].
+    pub infer_args: bool,
+    pub scan: FileScan,
+}
+
+/// On-disk representation of the cache.
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFile {
+    version: String,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+/// Scan results keyed by file path, loaded from and saved to a JSON file.
+#[derive(Debug)]
+pub struct ScanCache {
+    path: PathBuf,
+    file: CacheFile,
+}
+
+impl ScanCache {
+    /// Loads the cache at `path`.
+    ///
+    /// A missing, unreadable or outdated cache yields an empty one; the cache
+    /// is only an optimization, so it never fails a scan.
+    pub fn open(path: PathBuf) -> Self {
+        let file = std::fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<CacheFile>(&bytes).ok())
+            .filter(|file| file.version == version())
+            .unwrap_or_else(|| CacheFile {
+                version: version(),
+                entries: HashMap::new(),
+            });
+        Self { path, file }
+    }
+
+    /// Returns the cached scan of `file` if its contents hash to `hash` and it
+    /// was scanned for the same registry identifiers, directory and argument
+    /// inference.
+    pub fn get(
+        &self,
+        file: &Path,
+        hash: &str,
+        names: &[&str],
+        registry_dir: Option<&Path>,
+        infer_args: bool,
+    ) -> Option<FileScan> {
+        self.file
+            .entries
+            .get(file)
+            .filter(|entry| {
+                entry.hash == hash
+                    && entry.names == names
+                    && entry.registry_dir.as_deref() == registry_dir
+                    && entry.infer_args == infer_args
+            })
+            .map(|entry| entry.scan.clone())
+    }
+
+    /// Records the scan of `file`, replacing any previous entry.
+    pub fn insert(&mut self, file: PathBuf, entry: CacheEntry) {
+        self.file.entries.insert(file, entry);
+    }
+
+    /// Writes the cache back to disk, dropping entries for deleted files.
+    pub fn save(&mut self) -> Result<()> {
+        self.file.entries.retain(|file, _| file.exists());
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        let json = serde_json::to_vec(&self.file).context("Failed to serialize scan cache")?;
+
+        // Write then rename so an interrupted run never leaves a torn cache
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, json).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Default cache file for the project in the current directory.
+///
+/// Falls back to `.cache/imp-refactor/` in the current directory when neither
+/// `XDG_CACHE_HOME` nor `HOME` is set.
+pub fn default_path() -> PathBuf {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(|| PathBuf::from(".cache"));
+
+    let project = std::env::current_dir()
+        .and_then(|dir| dir.canonicalize())
+        .unwrap_or_default();
+    let id = content_hash(project.to_string_lossy().as_bytes());
+
+    base.join("imp-refactor")
+        .join(format!("{}.json", &id[..16]))
+}
+
+/// Hex-encoded SHA-256 of `bytes`.
+pub fn content_hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn version() -> String {
+    format!(
+        "{}+{}",
+        env!("CARGO_PKG_VERSION"),
+        scanner::EXTRACTION_VERSION
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn entry(hash: &str, names: &[&str]) -> CacheEntry {
+        let source = "{ x = registry.users.alice; }";
+        CacheEntry {
+            hash: hash.to_string(),
+            names: names.iter().map(|n| n.to_string()).collect(),
+            registry_dir: None,
+            infer_args: false,
+            scan: scanner::scan_source(source, Path::new("a.nix"), names),
+        }
+    }
+
+    #[test]
+    fn round_trips_through_disk() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.nix");
+        std::fs::write(&file, "{}").unwrap();
+        let path = tmp.path().join("cache/scan.json");
+
+        let mut cache = ScanCache::open(path.clone());
+        cache.insert(file.clone(), entry("abc", &["registry"]));
+        cache.save().unwrap();
+
+        let cache = ScanCache::open(path);
+        let scan = cache.get(&file, "abc", &["registry"], None, false).unwrap();
+        assert_eq!(scan.refs[0].path, "users.alice");
+    }
+
+    #[test]
+    fn misses_on_changed_contents_or_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut cache = ScanCache::open(tmp.path().join("scan.json"));
+        let file = PathBuf::from("a.nix");
+        cache.insert(file.clone(), entry("abc", &["registry"]));
+
+        assert!(
+            cache
+                .get(&file, "abc", &["registry"], None, false)
+                .is_some()
+        );
+        assert!(
+            cache
+                .get(&file, "def", &["registry"], None, false)
+                .is_none()
+        );
+        assert!(
+            cache
+                .get(&file, "abc", &["registry", "reg"], None, false)
+                .is_none()
+        );
+        assert!(
+            cache
+                .get(
+                    &file,
+                    "abc",
+                    &["registry"],
+                    Some(Path::new("registry")),
+                    false
+                )
+                .is_none()
+        );
+        assert!(cache.get(&file, "abc", &["registry"], None, true).is_none());
+    }
+
+    #[test]
+    fn discards_cache_from_other_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.nix");
+        let path = tmp.path().join("scan.json");
+        let stale = CacheFile {
+            version: "0.0.0+0".to_string(),
+            entries: HashMap::from([(file.clone(), entry("abc", &["registry"]))]),
+        };
+        std::fs::write(&path, serde_json::to_vec(&stale).unwrap()).unwrap();
+
+        let cache = ScanCache::open(path);
+        assert!(
+            cache
+                .get(&file, "abc", &["registry"], None, false)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn save_drops_deleted_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("scan.json");
+        let mut cache = ScanCache::open(path.clone());
+        cache.insert(tmp.path().join("gone.nix"), entry("abc", &["registry"]));
+        cache.save().unwrap();
+
+        let cache = ScanCache::open(path);
+        assert!(cache.file.entries.is_empty());
+    }
+}
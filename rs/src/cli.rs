@@ -2,9 +2,10 @@
 //!
 //! Defines the argument parser and subcommands using clap's derive API.
 //! Each subcommand corresponds to a distinct operation: detecting broken
-//! references, applying fixes, inspecting the registry, or listing scan targets.
+//! references, applying fixes, inspecting or diffing the registry, or listing
+//! scan targets.
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 /// Detect and fix broken registry references in Nix projects.
@@ -28,13 +29,54 @@ pub enum Commands {
         #[arg(short, long)]
         exclude: Vec<String>,
 
+        /// Glob patterns for extra files to scan regardless of extension
+        /// (e.g., "lib/generated/*").
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// File extensions to scan, comma-separated (e.g., "nix,nix.in").
+        #[arg(long, value_delimiter = ',', default_value = "nix")]
+        extensions: Vec<String>,
+
         /// Disable default exclusion of `.` and `_` prefixed entries.
         #[arg(long)]
         no_default_excludes: bool,
 
-        /// Registry attribute name in flake outputs.
-        #[arg(long, default_value = "registry")]
-        registry_name: String,
+        /// Do not honor `.gitignore`, `.git/info/exclude` or `.imp-refactor-ignore`.
+        #[arg(long)]
+        no_ignore: bool,
+
+        /// Follow symlinked files and directories instead of skipping them.
+        #[arg(long)]
+        follow_symlinks: bool,
+
+        /// Registry attribute name in flake outputs. Also the base looked
+        /// for in every scanned file, which may be dotted (e.g., `self.registry`).
+        /// Defaults to `registry`, plus any registry arguments detected in
+        /// module functions and `flake.nix`.
+        #[arg(long)]
+        registry_name: Option<String>,
+
+        /// Additional identifier bound to the registry, as `name` or
+        /// `glob=name` to limit it to matching files (e.g., `home/**=reg`).
+        /// May be dotted (e.g., `config.imp.registry`).
+        #[arg(long = "registry-ident", value_parser = parse_registry_ident)]
+        registry_idents: Vec<(Option<String>, String)>,
+
+        /// Directory the registry is built from. Relative path imports into it,
+        /// such as `../registry/home/alice`, are checked against the filesystem.
+        #[arg(long)]
+        registry_dir: Option<PathBuf>,
+
+        /// Where the registry comes from: `nix` evaluates the flake, `fs:<dir>`
+        /// builds it from the registry directory without Nix.
+        #[arg(long, value_parser = parse_registry_source, default_value = "nix")]
+        registry_source: RegistryBackend,
+
+        /// Read the registry from a JSON snapshot instead, such as saved
+        /// `nix eval --json .#registry` output. `-` reads standard input.
+        #[arg(long, value_name = "PATH", conflicts_with = "registry_source")]
+        registry_json: Option<PathBuf>,
 
         /// Git ref to evaluate registry from (e.g., HEAD, HEAD^, main).
         /// Compares working tree files against registry at this ref.
@@ -45,6 +87,42 @@ pub enum Commands {
         #[arg(long, value_parser = parse_rename)]
         rename: Vec<(String, String)>,
 
+        /// Infer renames by diffing the registry at this git ref (default
+        /// HEAD) against the one being checked. `--rename` entries win.
+        #[arg(long, value_name = "REF", num_args = 0..=1, default_missing_value = "HEAD")]
+        auto_renames: Option<String>,
+
+        /// Do not derive renames from registry files git detects as moved
+        /// since `--git-ref` (default HEAD).
+        #[arg(long)]
+        no_git_renames: bool,
+
+        /// Only evaluate the referenced registry paths, their parents and
+        /// their siblings instead of the whole registry. Suggestions then
+        /// only consider paths in that partial view.
+        #[arg(long, conflicts_with = "auto_renames")]
+        lazy: bool,
+
+        /// Number of files to parse in parallel. Defaults to one per CPU core.
+        #[arg(short, long, value_parser = parse_jobs)]
+        jobs: Option<usize>,
+
+        /// Parse every file instead of reusing cached results from earlier runs.
+        #[arg(long)]
+        no_cache: bool,
+
+        /// Fail if any scanned file has syntax errors.
+        #[arg(long)]
+        strict: bool,
+
+        /// Also check registry paths mentioned in Nix comments.
+        #[arg(long)]
+        include_comments: bool,
+
+        /// Also check registry paths in fenced `nix` blocks of Markdown files.
+        #[arg(long)]
+        include_docs: bool,
+
         /// Emit JSON instead of human-readable output.
         #[arg(long)]
         json: bool,
@@ -69,13 +147,54 @@ pub enum Commands {
         #[arg(short, long)]
         exclude: Vec<String>,
 
+        /// Glob patterns for extra files to scan regardless of extension
+        /// (e.g., "lib/generated/*").
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// File extensions to scan, comma-separated (e.g., "nix,nix.in").
+        #[arg(long, value_delimiter = ',', default_value = "nix")]
+        extensions: Vec<String>,
+
         /// Disable default exclusion of `.` and `_` prefixed entries.
         #[arg(long)]
         no_default_excludes: bool,
 
-        /// Registry attribute name in flake outputs.
-        #[arg(long, default_value = "registry")]
-        registry_name: String,
+        /// Do not honor `.gitignore`, `.git/info/exclude` or `.imp-refactor-ignore`.
+        #[arg(long)]
+        no_ignore: bool,
+
+        /// Follow symlinked files and directories instead of skipping them.
+        #[arg(long)]
+        follow_symlinks: bool,
+
+        /// Registry attribute name in flake outputs. Also the base looked
+        /// for in every scanned file, which may be dotted (e.g., `self.registry`).
+        /// Defaults to `registry`, plus any registry arguments detected in
+        /// module functions and `flake.nix`.
+        #[arg(long)]
+        registry_name: Option<String>,
+
+        /// Additional identifier bound to the registry, as `name` or
+        /// `glob=name` to limit it to matching files (e.g., `home/**=reg`).
+        /// May be dotted (e.g., `config.imp.registry`).
+        #[arg(long = "registry-ident", value_parser = parse_registry_ident)]
+        registry_idents: Vec<(Option<String>, String)>,
+
+        /// Directory the registry is built from. Relative path imports into it,
+        /// such as `../registry/home/alice`, are checked against the filesystem.
+        #[arg(long)]
+        registry_dir: Option<PathBuf>,
+
+        /// Where the registry comes from: `nix` evaluates the flake, `fs:<dir>`
+        /// builds it from the registry directory without Nix.
+        #[arg(long, value_parser = parse_registry_source, default_value = "nix")]
+        registry_source: RegistryBackend,
+
+        /// Read the registry from a JSON snapshot instead, such as saved
+        /// `nix eval --json .#registry` output. `-` reads standard input.
+        #[arg(long, value_name = "PATH", conflicts_with = "registry_source")]
+        registry_json: Option<PathBuf>,
 
         /// Git ref to evaluate registry from (e.g., HEAD, HEAD^, main).
         #[arg(long)]
@@ -84,14 +203,68 @@ pub enum Commands {
         /// Explicit rename mappings in `old=new` format.
         #[arg(long, value_parser = parse_rename)]
         rename: Vec<(String, String)>,
+
+        /// Infer renames by diffing the registry at this git ref (default
+        /// HEAD) against the one being checked. `--rename` entries win.
+        #[arg(long, value_name = "REF", num_args = 0..=1, default_missing_value = "HEAD")]
+        auto_renames: Option<String>,
+
+        /// Do not derive renames from registry files git detects as moved
+        /// since `--git-ref` (default HEAD).
+        #[arg(long)]
+        no_git_renames: bool,
+
+        /// Only evaluate the referenced registry paths, their parents and
+        /// their siblings instead of the whole registry. Suggestions then
+        /// only consider paths in that partial view.
+        #[arg(long, conflicts_with = "auto_renames")]
+        lazy: bool,
+
+        /// Number of files to parse in parallel. Defaults to one per CPU core.
+        #[arg(short, long, value_parser = parse_jobs)]
+        jobs: Option<usize>,
+
+        /// Parse every file instead of reusing cached results from earlier runs.
+        #[arg(long)]
+        no_cache: bool,
+
+        /// Fail if any scanned file has syntax errors.
+        #[arg(long)]
+        strict: bool,
+
+        /// Also check registry paths mentioned in Nix comments.
+        #[arg(long)]
+        include_comments: bool,
+
+        /// Also check registry paths in fenced `nix` blocks of Markdown files.
+        #[arg(long)]
+        include_docs: bool,
+
+        /// How to rewrite broken path imports into `--registry-dir`.
+        #[arg(long, value_enum, default_value_t = PathStyle::Relative)]
+        path_style: PathStyle,
     },
 
-    /// Print the registry's attribute tree.
+    /// Print the registry's attribute tree, or compare it across git refs.
+    #[command(args_conflicts_with_subcommands = true)]
     Registry {
+        #[command(subcommand)]
+        command: Option<RegistryCommand>,
+
         /// Registry attribute name in flake outputs.
         #[arg(long, default_value = "registry")]
         registry_name: String,
 
+        /// Where the registry comes from: `nix` evaluates the flake, `fs:<dir>`
+        /// builds it from the registry directory without Nix.
+        #[arg(long, value_parser = parse_registry_source, default_value = "nix")]
+        registry_source: RegistryBackend,
+
+        /// Read the registry from a JSON snapshot instead, such as saved
+        /// `nix eval --json .#registry` output. `-` reads standard input.
+        #[arg(long, value_name = "PATH", conflicts_with = "registry_source")]
+        registry_json: Option<PathBuf>,
+
         /// Git ref to evaluate registry from (e.g., HEAD, HEAD^, main).
         #[arg(long)]
         git_ref: Option<String>,
@@ -99,6 +272,11 @@ pub enum Commands {
         /// Maximum tree depth to display.
         #[arg(long)]
         depth: Option<usize>,
+
+        /// Print the registry as JSON, e.g. to save a snapshot for
+        /// `--registry-json`.
+        #[arg(long)]
+        json: bool,
     },
 
     /// List files that would be scanned without processing them.
@@ -112,12 +290,81 @@ pub enum Commands {
         #[arg(short, long)]
         exclude: Vec<String>,
 
+        /// Glob patterns for extra files to scan regardless of extension
+        /// (e.g., "lib/generated/*").
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// File extensions to scan, comma-separated (e.g., "nix,nix.in").
+        #[arg(long, value_delimiter = ',', default_value = "nix")]
+        extensions: Vec<String>,
+
         /// Disable default exclusion of `.` and `_` prefixed entries.
         #[arg(long)]
         no_default_excludes: bool,
+
+        /// Do not honor `.gitignore`, `.git/info/exclude` or `.imp-refactor-ignore`.
+        #[arg(long)]
+        no_ignore: bool,
+
+        /// Follow symlinked files and directories instead of skipping them.
+        #[arg(long)]
+        follow_symlinks: bool,
     },
 }
 
+#[derive(Debug, Subcommand)]
+pub enum RegistryCommand {
+    /// Compare the registry at two git refs and infer renames.
+    Diff {
+        /// Git ref of the old registry.
+        from: String,
+
+        /// Git ref of the new registry. Defaults to the working tree.
+        to: Option<String>,
+
+        /// Registry attribute name in flake outputs.
+        #[arg(long, default_value = "registry")]
+        registry_name: String,
+
+        /// Where the registry comes from: `nix` evaluates the flake, `fs:<dir>`
+        /// builds it from the registry directory without Nix.
+        #[arg(long, value_parser = parse_registry_source, default_value = "nix")]
+        registry_source: RegistryBackend,
+
+        /// Emit JSON instead of human-readable output.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Replacement form for broken path imports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PathStyle {
+    /// A relative path to the new location.
+    Relative,
+    /// The equivalent `registry.X.Y` select.
+    Registry,
+}
+
+/// Where the registry's attribute tree comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryBackend {
+    /// `nix eval` of the flake's registry attribute.
+    Nix,
+    /// The directory the registry is built from, mapped without Nix.
+    Fs(PathBuf),
+    /// A JSON snapshot file, or standard input for `-`.
+    Json(PathBuf),
+}
+
+impl RegistryBackend {
+    /// The backend selected by `--registry-source` and `--registry-json`.
+    pub fn select(source: Self, json: Option<PathBuf>) -> Self {
+        json.map_or(source, Self::Json)
+    }
+}
+
 fn parse_rename(s: &str) -> Result<(String, String), String> {
     let parts: Vec<&str> = s.splitn(2, '=').collect();
     if parts.len() != 2 {
@@ -125,3 +372,38 @@ fn parse_rename(s: &str) -> Result<(String, String), String> {
     }
     Ok((parts[0].to_string(), parts[1].to_string()))
 }
+
+fn parse_jobs(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) | Err(_) => Err(format!(
+            "Invalid job count '{}', expected a positive integer",
+            s
+        )),
+        Ok(n) => Ok(n),
+    }
+}
+
+fn parse_registry_ident(s: &str) -> Result<(Option<String>, String), String> {
+    let (glob, name) = match s.rsplit_once('=') {
+        Some((glob, name)) => (Some(glob.to_string()), name),
+        None => (None, s),
+    };
+    if name.is_empty() || glob.as_deref() == Some("") {
+        return Err(format!(
+            "Invalid registry identifier '{}', expected 'name' or 'glob=name'",
+            s
+        ));
+    }
+    Ok((glob, name.to_string()))
+}
+
+fn parse_registry_source(s: &str) -> Result<RegistryBackend, String> {
+    match s.split_once(':') {
+        None if s == "nix" => Ok(RegistryBackend::Nix),
+        Some(("fs", dir)) if !dir.is_empty() => Ok(RegistryBackend::Fs(PathBuf::from(dir))),
+        _ => Err(format!(
+            "Invalid registry source '{}', expected 'nix' or 'fs:<dir>'",
+            s
+        )),
+    }
+}
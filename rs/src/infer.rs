@@ -0,0 +1,305 @@
+//! Registry identifiers inferred from how the registry is passed to modules.
+//!
+//! imp modules usually receive the registry as a function argument:
+//! `{ registry, ... }:`. When `--registry-name` is not given, names bound to
+//! `self.registry` in the argument sets declared in `flake.nix`
+//! (`specialArgs`, `extraSpecialArgs`, `_module.args` and `imp.args`) are
+//! checked everywhere, so a project that calls it `reg` is still checked
+//! instead of reporting no references. A module argument whose name merely
+//! looks like a registry is only a guess, so it is checked in the module that
+//! declares it and nowhere else.
+
+use crate::scanner::{static_attr_name, unparen};
+use rnix::{SyntaxKind, SyntaxNode};
+use std::path::PathBuf;
+
+/// Attribute paths whose value is a set of extra module arguments.
+const ARG_SETS: &[&[&str]] = &[
+    &["specialArgs"],
+    &["extraSpecialArgs"],
+    &["_module", "args"],
+    &["imp", "args"],
+];
+
+/// Returns true if a module argument's name suggests it holds the registry,
+/// such as `registry`, `reg` or `homeRegistry`.
+pub fn looks_like_registry(name: &str) -> bool {
+    name == "reg" || name.to_ascii_lowercase().contains("registry")
+}
+
+/// Formal arguments of a module's top-level function, following curried
+/// functions: `{ registry, ... }: { pkgs, ... }:` yields both sets, with or
+/// without parentheses around each function. `root` is the root node of the
+/// parsed file.
+pub fn module_args(root: &SyntaxNode) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut node = root.first_child().map(|n| unparen(&n));
+    while let Some(lambda) = node.filter(|n| n.kind() == SyntaxKind::NODE_LAMBDA) {
+        let mut children = lambda.children();
+        if let Some(param) = children.next()
+            && param.kind() == SyntaxKind::NODE_PATTERN
+        {
+            args.extend(
+                param
+                    .children()
+                    .filter(|c| c.kind() == SyntaxKind::NODE_PAT_ENTRY)
+                    .filter_map(|c| c.first_child())
+                    .map(|ident| ident.text().to_string()),
+            );
+        }
+        node = children.next().map(|n| unparen(&n));
+    }
+    args
+}
+
+/// The `flake.nix` of the project `roots` were scanned from: the first one
+/// found in a root or any of its parents, the way `nix` looks for a flake.
+pub fn find_flake(roots: &[PathBuf]) -> Option<PathBuf> {
+    roots.iter().find_map(|root| {
+        let root = root.canonicalize().ok()?;
+        let dir = if root.is_dir() {
+            root.as_path()
+        } else {
+            root.parent()?
+        };
+        dir.ancestors()
+            .map(|dir| dir.join("flake.nix"))
+            .find(|flake| flake.is_file())
+    })
+}
+
+/// Names bound to the registry attribute `registry_attr` of the flake itself
+/// in the module argument sets of a `flake.nix`, e.g. `reg` in
+/// `specialArgs = { reg = self.registry; };` and `registry` in
+/// `specialArgs = { inherit (self) registry; };`. The value must be exactly
+/// `self.<registry_attr>` or `inputs.self.<registry_attr>`.
+pub fn flake_args(source: &str, registry_attr: &str) -> Vec<String> {
+    let root = rnix::Root::parse(source).syntax();
+    let attr: Vec<&str> = registry_attr.split('.').collect();
+    let mut names = Vec::new();
+
+    for entry in root
+        .descendants()
+        .filter(|n| n.kind() == SyntaxKind::NODE_ATTRPATH_VALUE)
+    {
+        let key = key_path(&entry);
+        if !ARG_SETS.iter().any(|set| key.ends_with(&owned(set))) {
+            continue;
+        }
+        let Some(set) = entry
+            .children()
+            .nth(1)
+            .filter(|v| v.kind() == SyntaxKind::NODE_ATTR_SET)
+        else {
+            continue;
+        };
+        for name in bound_names(&set, &attr) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    names
+}
+
+fn owned(names: &[&str]) -> Vec<String> {
+    names.iter().map(|s| s.to_string()).collect()
+}
+
+/// Returns true if `path` is `self` or `inputs.self`.
+fn is_self(path: &[String]) -> bool {
+    path == ["self"] || path == ["inputs", "self"]
+}
+
+/// Names in an argument set whose value is the registry attribute.
+fn bound_names(set: &SyntaxNode, attr: &[&str]) -> Vec<String> {
+    let mut names = Vec::new();
+    for child in set.children() {
+        match child.kind() {
+            SyntaxKind::NODE_ATTRPATH_VALUE => {
+                let mut parts = child.children();
+                let (Some(attrpath), Some(value)) = (parts.next(), parts.next()) else {
+                    continue;
+                };
+                let path = attrpath_names(&attrpath);
+                if let [name] = path.as_slice()
+                    && let Some(value) = static_path(&value)
+                    && value.ends_with(&owned(attr))
+                    && is_self(&value[..value.len() - attr.len()])
+                {
+                    names.push(name.clone());
+                }
+            }
+            // `inherit (self) registry;` binds the argument under the
+            // attribute's own name
+            SyntaxKind::NODE_INHERIT if attr.len() == 1 => {
+                let from_self = child
+                    .children()
+                    .find(|c| c.kind() == SyntaxKind::NODE_INHERIT_FROM)
+                    .and_then(|from| from.first_child())
+                    .and_then(|source| static_path(&source))
+                    .is_some_and(|source| is_self(&source));
+                if !from_self {
+                    continue;
+                }
+                names.extend(
+                    child
+                        .children()
+                        .filter(|c| c.kind() != SyntaxKind::NODE_INHERIT_FROM)
+                        .filter_map(|c| static_attr_name(&c))
+                        .filter(|name| name == attr[0]),
+                );
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+/// The full attribute path an attrpath-value defines, including the names of
+/// the attribute sets it is nested in: `imp = { args = ...; };` gives
+/// `imp.args`.
+fn key_path(entry: &SyntaxNode) -> Vec<String> {
+    let mut key = entry
+        .first_child()
+        .map(|attrpath| attrpath_names(&attrpath))
+        .unwrap_or_default();
+    let mut node = entry.clone();
+    while let Some(set) = node
+        .parent()
+        .filter(|p| p.kind() == SyntaxKind::NODE_ATTR_SET)
+        && let Some(outer) = set
+            .parent()
+            .filter(|p| p.kind() == SyntaxKind::NODE_ATTRPATH_VALUE)
+    {
+        let mut outer_key = outer
+            .first_child()
+            .map(|attrpath| attrpath_names(&attrpath))
+            .unwrap_or_default();
+        outer_key.append(&mut key);
+        key = outer_key;
+        node = outer;
+    }
+    key
+}
+
+fn attrpath_names(attrpath: &SyntaxNode) -> Vec<String> {
+    attrpath
+        .children()
+        .map(|attr| static_attr_name(&attr).unwrap_or_default())
+        .collect()
+}
+
+/// The names of an identifier or a select with static segments:
+/// `inputs.self.registry` gives `["inputs", "self", "registry"]`.
+fn static_path(node: &SyntaxNode) -> Option<Vec<String>> {
+    match node.kind() {
+        SyntaxKind::NODE_IDENT => Some(vec![node.text().to_string()]),
+        SyntaxKind::NODE_SELECT => {
+            let mut children = node.children();
+            let (Some(base), Some(attrpath)) = (children.next(), children.next()) else {
+                return None;
+            };
+            if children.next().is_some() {
+                return None;
+            }
+            let mut path = static_path(&base)?;
+            for attr in attrpath.children() {
+                path.push(static_attr_name(&attr)?);
+            }
+            Some(path)
+        }
+        SyntaxKind::NODE_PAREN => static_path(&node.first_child()?),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(source: &str) -> Vec<String> {
+        module_args(&rnix::Root::parse(source).syntax())
+    }
+
+    #[test]
+    fn module_args_follow_curried_functions() {
+        let source = "{ reg, lib, ... }: { pkgs ? null }: args: { x = reg.a; }";
+        assert_eq!(args(source), vec!["reg", "lib", "pkgs"]);
+        assert!(args("{ x = 1; }").is_empty());
+        assert!(args("registry: registry.a").is_empty());
+    }
+
+    #[test]
+    fn module_args_look_through_parens() {
+        assert_eq!(args("({ reg, ... }: { x = reg.a; })"), vec!["reg"]);
+        assert_eq!(args("{ reg, ... }: ({ pkgs }: { })"), vec!["reg", "pkgs"]);
+    }
+
+    #[test]
+    fn looks_like_registry_names() {
+        assert!(looks_like_registry("registry"));
+        assert!(looks_like_registry("reg"));
+        assert!(looks_like_registry("homeRegistry"));
+        assert!(!looks_like_registry("regex"));
+        assert!(!looks_like_registry("pkgs"));
+    }
+
+    #[test]
+    fn flake_args_finds_bound_names() {
+        let source = r#"
+{
+  outputs = { self, nixpkgs, ... }: {
+    nixosConfigurations.host = nixpkgs.lib.nixosSystem {
+      specialArgs = { reg = self.registry; inherit (self) registry; pkgs = null; };
+    };
+    homeConfigurations.me = hm.lib.homeManagerConfiguration {
+      extraSpecialArgs = { hmReg = inputs.self.registry; other = self.packages; };
+    };
+    imp = {
+      args = { r = (self.registry); };
+    };
+  };
+}
+"#;
+        assert_eq!(
+            flake_args(source, "registry"),
+            vec!["reg", "registry", "hmReg", "r"]
+        );
+    }
+
+    #[test]
+    fn flake_args_ignores_other_sets() {
+        let source = "{ config = { reg = self.registry; }; specialArgs = self.registry; }";
+        assert!(flake_args(source, "registry").is_empty());
+    }
+
+    #[test]
+    fn flake_args_require_the_flakes_own_registry() {
+        let source = r#"
+{
+  specialArgs = {
+    nixReg = config.nix.registry;
+    other = inputs.dep.registry;
+    inherit registry;
+    inherit (config.nix) registry;
+  };
+}
+"#;
+        assert!(flake_args(source, "registry").is_empty());
+    }
+
+    #[test]
+    fn find_flake_searches_parent_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("hosts/server")).unwrap();
+        std::fs::write(root.join("flake.nix"), "{ }").unwrap();
+        assert_eq!(
+            find_flake(&[root.join("hosts/server")]),
+            Some(root.join("flake.nix"))
+        );
+        assert_eq!(find_flake(&[root.join("missing")]), None);
+    }
+}
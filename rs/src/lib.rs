@@ -15,10 +15,11 @@
 //! use std::path::PathBuf;
 //!
 //! // Collect files and extract references
-//! let files = scanner::collect_nix_files(&[PathBuf::from("./nix")], &[], true).unwrap();
+//! let options = scanner::CollectOptions::default();
+//! let found = scanner::collect_nix_files(&[PathBuf::from("./nix")], &options).unwrap();
 //! let mut refs = Vec::new();
-//! for file in &files {
-//!     refs.extend(scanner::extract_registry_refs(file, "registry").unwrap());
+//! for file in found.paths() {
+//!     refs.extend(scanner::extract_registry_refs(&file, &["registry"]).unwrap());
 //! }
 //!
 //! // Evaluate the registry (None = current working tree, Some("HEAD") = committed state)
@@ -33,10 +34,16 @@
 //! ```
 
 pub mod analyzer;
+pub mod cache;
+pub mod infer;
+pub mod mentions;
 pub mod registry;
+pub mod renames;
 pub mod rewriter;
 pub mod scanner;
+pub mod source;
+pub mod span;
 
 // Re-export commonly used types at crate root
 pub use analyzer::{BrokenRef, DetectionResult, Diagnostics};
-pub use scanner::RegistryRef;
+pub use scanner::{FileScan, ParseError, PathSegment, ReadError, RefKind, RegistryRef, SkippedRef};
@@ -9,14 +9,20 @@ use anyhow::Result;
 use clap::Parser;
 use colored::Colorize;
 use dialoguer::Select;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
-use cli::{Args, Commands};
+use cli::{Args, Commands, PathStyle, RegistryBackend, RegistryCommand};
 use imp_refactor::{
-    BrokenRef, DetectionResult, Diagnostics, RegistryRef, analyzer, registry, rewriter, scanner,
+    BrokenRef, DetectionResult, Diagnostics, FileScan, ParseError, ReadError, RefKind, RegistryRef,
+    SkippedRef, analyzer,
+    cache::{self, ScanCache},
+    infer, mentions, registry, renames, rewriter, scanner, source,
 };
 
+/// Registry attribute and identifier used when `--registry-name` is not given.
+const DEFAULT_REGISTRY_NAME: &str = "registry";
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
@@ -24,19 +30,54 @@ fn main() -> Result<()> {
         Commands::Detect {
             paths,
             exclude,
+            include,
+            extensions,
             no_default_excludes,
+            no_ignore,
+            follow_symlinks,
             registry_name,
+            registry_idents,
+            registry_dir,
+            registry_source,
+            registry_json,
             git_ref,
             rename,
+            auto_renames,
+            no_git_renames,
+            lazy,
+            jobs,
+            no_cache,
+            strict,
+            include_comments,
+            include_docs,
             json,
             verbose,
         } => cmd_detect(
             paths,
-            &exclude,
-            !no_default_excludes,
-            &registry_name,
+            &collect_options(
+                exclude,
+                include,
+                extensions,
+                no_default_excludes,
+                no_ignore,
+                follow_symlinks,
+            ),
+            registry_name.as_deref(),
+            &registry_idents,
+            registry_dir.as_deref(),
+            &RegistryBackend::select(registry_source, registry_json),
             git_ref.as_deref(),
             rename,
+            auto_renames.as_deref(),
+            !no_git_renames,
+            lazy,
+            jobs,
+            !no_cache,
+            strict,
+            MentionScope {
+                comments: include_comments,
+                docs: include_docs,
+            },
             json,
             verbose,
         ),
@@ -45,88 +86,219 @@ fn main() -> Result<()> {
             interactive,
             paths,
             exclude,
+            include,
+            extensions,
             no_default_excludes,
+            no_ignore,
+            follow_symlinks,
             registry_name,
+            registry_idents,
+            registry_dir,
+            registry_source,
+            registry_json,
             git_ref,
             rename,
+            auto_renames,
+            no_git_renames,
+            lazy,
+            jobs,
+            no_cache,
+            strict,
+            include_comments,
+            include_docs,
+            path_style,
         } => cmd_apply(
             interactive,
             paths,
-            &exclude,
-            !no_default_excludes,
-            &registry_name,
+            &collect_options(
+                exclude,
+                include,
+                extensions,
+                no_default_excludes,
+                no_ignore,
+                follow_symlinks,
+            ),
+            registry_name.as_deref(),
+            &registry_idents,
+            registry_dir.as_deref(),
+            &RegistryBackend::select(registry_source, registry_json),
             git_ref.as_deref(),
             rename,
+            auto_renames.as_deref(),
+            !no_git_renames,
+            lazy,
+            jobs,
+            !no_cache,
+            strict,
+            MentionScope {
+                comments: include_comments,
+                docs: include_docs,
+            },
+            match path_style {
+                PathStyle::Relative => rewriter::PathStyle::Relative,
+                PathStyle::Registry => rewriter::PathStyle::Registry,
+            },
         ),
 
         Commands::Registry {
+            command:
+                Some(RegistryCommand::Diff {
+                    from,
+                    to,
+                    registry_name,
+                    registry_source,
+                    json,
+                }),
+            ..
+        } => cmd_registry_diff(&registry_source, &registry_name, &from, to.as_deref(), json),
+
+        Commands::Registry {
+            command: None,
             registry_name,
+            registry_source,
+            registry_json,
             git_ref,
             depth,
-        } => cmd_registry(&registry_name, git_ref.as_deref(), depth),
+            json,
+        } => cmd_registry(
+            &RegistryBackend::select(registry_source, registry_json),
+            &registry_name,
+            git_ref.as_deref(),
+            depth,
+            json,
+        ),
 
         Commands::Scan {
             paths,
             exclude,
+            include,
+            extensions,
             no_default_excludes,
-        } => cmd_scan(paths, &exclude, !no_default_excludes),
+            no_ignore,
+            follow_symlinks,
+        } => cmd_scan(
+            paths,
+            &collect_options(
+                exclude,
+                include,
+                extensions,
+                no_default_excludes,
+                no_ignore,
+                follow_symlinks,
+            ),
+        ),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn cmd_detect(
     paths: Option<Vec<PathBuf>>,
-    exclude: &[String],
-    use_default_excludes: bool,
-    registry_name: &str,
+    collect: &scanner::CollectOptions,
+    registry_name: Option<&str>,
+    registry_idents: &[(Option<String>, String)],
+    registry_dir: Option<&Path>,
+    registry_source: &RegistryBackend,
     git_ref: Option<&str>,
     rename_map: Vec<(String, String)>,
+    auto_renames: Option<&str>,
+    git_renames: bool,
+    lazy: bool,
+    jobs: Option<usize>,
+    use_cache: bool,
+    strict: bool,
+    mentions: MentionScope,
     json_output: bool,
     verbose: bool,
 ) -> Result<()> {
     let scan_paths = paths.unwrap_or_else(|| vec![PathBuf::from(".")]);
-
-    let files = scanner::collect_nix_files(&scan_paths, exclude, use_default_excludes)?;
+    let collected = collect_files(&scan_paths, collect, mentions)?;
+    let files = collected.paths();
     if verbose {
         eprintln!(
-            "{} Found {} .nix files to scan",
+            "{} Found {} files to scan",
             "info:".blue().bold(),
             files.len()
         );
-        if let Some(ref r) = git_ref {
-            eprintln!(
-                "{} Evaluating registry from git ref '{}'",
-                "info:".blue().bold(),
-                r
-            );
-        }
+        let name = registry_name.unwrap_or(DEFAULT_REGISTRY_NAME);
+        eprintln!(
+            "{} Loading registry from {}",
+            "info:".blue().bold(),
+            registry_at(registry_source, name, git_ref)?.describe()
+        );
     }
 
-    let reg = registry::evaluate(registry_name, git_ref)?;
+    let names = resolve_registry_names(
+        registry_name,
+        registry_idents,
+        registry_dir,
+        &scan_paths,
+        verbose,
+    )?;
+    let registry_name = registry_name.unwrap_or(DEFAULT_REGISTRY_NAME);
+
+    let rename_map: HashMap<String, String> = rename_map.into_iter().collect();
+    let moves = if git_renames {
+        git_moves(registry_dir, registry_source, git_ref, verbose)
+    } else {
+        Vec::new()
+    };
+    let rename_map = renames::merge(&rename_map, &moves);
+
+    let (reg, scans) = scan_with_registry(
+        &files,
+        &names,
+        jobs,
+        use_cache,
+        registry_source,
+        registry_name,
+        git_ref,
+        lazy.then_some(&rename_map),
+        mentions,
+    )?;
+    report_module_args(&names, &files, &scans, verbose);
     let valid_paths = registry::flatten_paths(&reg, "");
     if verbose {
         eprintln!(
-            "{} Registry contains {} valid paths",
+            "{} Registry {} {} valid paths",
             "info:".blue().bold(),
+            if lazy { "view contains" } else { "contains" },
             valid_paths.len()
         );
     }
 
     let mut all_refs = Vec::new();
-    for file in &files {
-        let refs = scanner::extract_registry_refs(file, registry_name)?;
-        all_refs.extend(refs);
+    let mut skipped = Vec::new();
+    let mut parse_errors = Vec::new();
+    let mut read_errors = collected.errors;
+    let mut all_mentions = Vec::new();
+    for (file, scan) in files.iter().zip(scans) {
+        all_refs.extend(scan.refs);
+        skipped.extend(scan.skipped);
+        parse_errors.extend(scan.parse_errors);
+        read_errors.extend(scan.read_errors);
+        if mentions.includes(file) {
+            all_mentions.extend(scan.mentions);
+        }
     }
 
     if verbose {
         eprintln!(
-            "{} Extracted {} registry references",
+            "{} Extracted {} registry references ({} skipped)",
             "info:".blue().bold(),
-            all_refs.len()
+            all_refs.len(),
+            skipped.len()
         );
     }
 
-    let rename_map: HashMap<String, String> = rename_map.into_iter().collect();
+    let (rename_map, inferred) = auto_rename_map(
+        rename_map,
+        auto_renames,
+        registry_source,
+        registry_name,
+        &reg,
+    )?;
     let (broken, valid_count) = analyzer::analyze(&all_refs, &valid_paths, &rename_map);
+    let (broken_mentions, _) = analyzer::analyze(&all_mentions, &valid_paths, &rename_map);
 
     let diagnostics = Diagnostics {
         files_scanned: files.len(),
@@ -135,10 +307,22 @@ fn cmd_detect(
         broken_refs: broken.len(),
         suggestions_found: broken.iter().filter(|b| b.suggestion.is_some()).count(),
         unsuggestable: broken.iter().filter(|b| b.suggestion.is_none()).count(),
+        silent_refs: broken.iter().filter(|b| b.silent).count(),
+        skipped_refs: skipped.len(),
+        parse_errors: parse_errors.len(),
+        read_errors: read_errors.len(),
+        total_mentions: all_mentions.len(),
+        broken_mentions: broken_mentions.len(),
     };
 
     let result = DetectionResult {
         broken,
+        skipped,
+        parse_errors,
+        read_errors,
+        mentions: broken_mentions,
+        renames: inferred,
+        moves,
         diagnostics,
     };
 
@@ -148,38 +332,106 @@ fn cmd_detect(
         print_detection_result(&result, verbose);
     }
 
+    if strict {
+        check_parse_errors(&result.parse_errors)?;
+    }
+
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn cmd_apply(
     interactive: bool,
     paths: Option<Vec<PathBuf>>,
-    exclude: &[String],
-    use_default_excludes: bool,
-    registry_name: &str,
+    collect: &scanner::CollectOptions,
+    registry_name: Option<&str>,
+    registry_idents: &[(Option<String>, String)],
+    registry_dir: Option<&Path>,
+    registry_source: &RegistryBackend,
     git_ref: Option<&str>,
     rename_map: Vec<(String, String)>,
+    auto_renames: Option<&str>,
+    git_renames: bool,
+    lazy: bool,
+    jobs: Option<usize>,
+    use_cache: bool,
+    strict: bool,
+    mentions: MentionScope,
+    path_style: rewriter::PathStyle,
 ) -> Result<()> {
     let scan_paths = paths.unwrap_or_else(|| vec![PathBuf::from(".")]);
-    let files = scanner::collect_nix_files(&scan_paths, exclude, use_default_excludes)?;
-    let reg = registry::evaluate(registry_name, git_ref)?;
-    let valid_paths = registry::flatten_paths(&reg, "");
+    let collected = collect_files(&scan_paths, collect, mentions)?;
+    let files = collected.paths();
+    let names = resolve_registry_names(
+        registry_name,
+        registry_idents,
+        registry_dir,
+        &scan_paths,
+        false,
+    )?;
+    let registry_name = registry_name.unwrap_or(DEFAULT_REGISTRY_NAME);
     let rename_map: HashMap<String, String> = rename_map.into_iter().collect();
+    let moves = if git_renames {
+        git_moves(registry_dir, registry_source, git_ref, false)
+    } else {
+        Vec::new()
+    };
+    let rename_map = renames::merge(&rename_map, &moves);
+    let (reg, scans) = scan_with_registry(
+        &files,
+        &names,
+        jobs,
+        use_cache,
+        registry_source,
+        registry_name,
+        git_ref,
+        lazy.then_some(&rename_map),
+        mentions,
+    )?;
+    report_module_args(&names, &files, &scans, false);
+    let valid_paths = registry::flatten_paths(&reg, "");
+    let (rename_map, _) = auto_rename_map(
+        rename_map,
+        auto_renames,
+        registry_source,
+        registry_name,
+        &reg,
+    )?;
+
+    let read_errors = collected
+        .errors
+        .iter()
+        .chain(scans.iter().flat_map(|s| &s.read_errors));
+    for err in read_errors {
+        print_read_error(err);
+    }
+    let parse_errors: Vec<ParseError> = scans.iter().flat_map(|s| s.parse_errors.clone()).collect();
+    for err in &parse_errors {
+        print_parse_error(err);
+    }
+    if strict {
+        check_parse_errors(&parse_errors)?;
+    }
 
     let mut changes_by_file: HashMap<PathBuf, Vec<(RegistryRef, String)>> = HashMap::new();
 
-    for file in &files {
-        let refs = scanner::extract_registry_refs(file, registry_name)?;
-        for reference in refs {
-            if !valid_paths.contains(&reference.path) {
-                if let Some(new_path) =
+    for (file, scan) in files.iter().zip(scans) {
+        let mentioned = if mentions.includes(file) {
+            scan.mentions
+        } else {
+            Vec::new()
+        };
+        for reference in scan.refs.into_iter().chain(mentioned) {
+            // Dynamic references are only reported, never rewritten
+            if !reference.is_dynamic()
+                && !analyzer::is_valid(&reference, &valid_paths)
+                && let Some(new_path) =
                     analyzer::suggest_path(&reference.path, &valid_paths, &rename_map)
-                {
-                    changes_by_file
-                        .entry(file.clone())
-                        .or_default()
-                        .push((reference, new_path));
-                }
+            {
+                changes_by_file
+                    .entry(file.clone())
+                    .or_default()
+                    .push((reference, new_path));
             }
         }
     }
@@ -216,11 +468,12 @@ fn cmd_apply(
 
         for (reference, new_path) in changes {
             println!(
-                "  {}:{}: {} -> {}",
+                "  {}:{}: {} -> {}{}",
                 reference.line,
                 reference.column,
-                format!("{}.{}", registry_name, reference.path).red(),
-                format!("{}.{}", registry_name, new_path).green()
+                format!("{}.{}", reference.base, reference.path).red(),
+                format!("{}.{}", reference.base, new_path).green(),
+                ref_kind_note(reference)
             );
         }
 
@@ -228,14 +481,12 @@ fn cmd_apply(
             let choice = prompt_file_action(changes.len())?;
             match choice {
                 FileAction::Apply => {
-                    rewriter::apply_changes(file, registry_name, changes)?;
-                    println!(
-                        "  {} Applied {} change(s)\n",
-                        "ok:".green().bold(),
-                        changes.len()
-                    );
+                    let unrewritten = rewriter::apply_changes(file, changes, path_style)?;
+                    print_unrewritten(changes, &unrewritten);
+                    let applied = changes.len() - unrewritten.len();
+                    println!("  {} Applied {} change(s)\n", "ok:".green().bold(), applied);
                     applied_files += 1;
-                    applied_changes += changes.len();
+                    applied_changes += applied;
                 }
                 FileAction::Skip => {
                     println!("  {} Skipped\n", "info:".blue().bold());
@@ -247,9 +498,10 @@ fn cmd_apply(
                 }
             }
         } else {
-            rewriter::apply_changes(file, registry_name, changes)?;
+            let unrewritten = rewriter::apply_changes(file, changes, path_style)?;
+            print_unrewritten(changes, &unrewritten);
             applied_files += 1;
-            applied_changes += changes.len();
+            applied_changes += changes.len() - unrewritten.len();
         }
 
         // Add blank line between files in non-interactive mode
@@ -272,6 +524,365 @@ fn cmd_apply(
     Ok(())
 }
 
+/// Which registry paths outside code to check (`--include-comments`,
+/// `--include-docs`).
+#[derive(Debug, Clone, Copy)]
+struct MentionScope {
+    comments: bool,
+    docs: bool,
+}
+
+impl MentionScope {
+    /// Returns true if mentions found in `file` should be checked.
+    fn includes(&self, file: &Path) -> bool {
+        if mentions::is_markdown(file) {
+            self.docs
+        } else {
+            self.comments
+        }
+    }
+}
+
+/// Collects the files selected by `collect`, plus Markdown files when
+/// documentation mentions are checked.
+fn collect_files(
+    paths: &[PathBuf],
+    collect: &scanner::CollectOptions,
+    mentions: MentionScope,
+) -> Result<scanner::NixFiles> {
+    let mut collected = scanner::collect_nix_files(paths, collect)?;
+    if mentions.docs {
+        let docs = scanner::CollectOptions {
+            include: Vec::new(),
+            extensions: vec!["md".to_string(), "markdown".to_string()],
+            ..collect.clone()
+        };
+        let found = scanner::collect_nix_files(paths, &docs)?;
+        collected.files.extend(found.files);
+        // Both walks report the same unreadable directories
+        for err in found.errors {
+            if !collected.errors.contains(&err) {
+                collected.errors.push(err);
+            }
+        }
+    }
+    Ok(collected)
+}
+
+/// Builds the file selection from the `detect`, `apply` and `scan` flags.
+fn collect_options(
+    exclude: Vec<String>,
+    include: Vec<String>,
+    extensions: Vec<String>,
+    no_default_excludes: bool,
+    no_ignore: bool,
+    follow_symlinks: bool,
+) -> scanner::CollectOptions {
+    scanner::CollectOptions {
+        exclude,
+        include,
+        extensions,
+        use_default_excludes: !no_default_excludes,
+        use_ignore_files: !no_ignore,
+        follow_symlinks,
+    }
+}
+
+/// Builds the identifier set from `--registry-name`, `--registry-ident` and
+/// `--registry-dir`, with scoped identifiers matched relative to `roots`.
+fn registry_names(
+    registry_name: &str,
+    registry_idents: &[(Option<String>, String)],
+    registry_dir: Option<&Path>,
+    roots: &[PathBuf],
+) -> Result<scanner::RegistryNames> {
+    let mut names = scanner::RegistryNames::new(registry_name);
+    names.set_roots(roots);
+    if let Some(dir) = registry_dir {
+        names.set_dir(dir);
+    }
+    for (glob, name) in registry_idents {
+        match glob {
+            Some(glob) => names.add_scoped(glob, name)?,
+            None => names.add(name),
+        }
+    }
+    Ok(names)
+}
+
+/// Builds the identifier set like [`registry_names`].
+///
+/// Without `--registry-name`, the names bound to the registry in the
+/// project's `flake.nix` are added to the set, and every module's
+/// registry-like arguments are checked in that module; see
+/// [`report_module_args`].
+fn resolve_registry_names(
+    registry_name: Option<&str>,
+    registry_idents: &[(Option<String>, String)],
+    registry_dir: Option<&Path>,
+    roots: &[PathBuf],
+    verbose: bool,
+) -> Result<scanner::RegistryNames> {
+    let mut names = registry_names(
+        registry_name.unwrap_or(DEFAULT_REGISTRY_NAME),
+        registry_idents,
+        registry_dir,
+        roots,
+    )?;
+    if registry_name.is_some() {
+        return Ok(names);
+    }
+
+    names.set_infer_args(true);
+    let Some(flake) = infer::find_flake(roots) else {
+        return Ok(names);
+    };
+    let detected = std::fs::read_to_string(&flake)
+        .map(|source| infer::flake_args(&source, registry::flake_attr(DEFAULT_REGISTRY_NAME)))
+        .unwrap_or_default();
+    for name in detected {
+        if names.contains(&name) {
+            continue;
+        }
+        names.add(&name);
+        if verbose {
+            eprintln!(
+                "{} Detected registry identifier `{}` ({})",
+                "info:".blue().bold(),
+                name,
+                flake.display()
+            );
+        }
+    }
+    Ok(names)
+}
+
+/// Reports the registry-like top-level arguments of the scanned modules.
+///
+/// With argument inference on, `--verbose` lists them as detected. Otherwise
+/// those still not checked in a file are reported as warnings, since their
+/// references would be missed without a trace.
+fn report_module_args(
+    names: &scanner::RegistryNames,
+    files: &[PathBuf],
+    scans: &[FileScan],
+    verbose: bool,
+) {
+    let mut found: BTreeMap<&str, Vec<&Path>> = BTreeMap::new();
+    for (file, scan) in files.iter().zip(scans) {
+        let checked = names.for_file(file);
+        for arg in &scan.module_args {
+            if infer::looks_like_registry(arg) && !checked.contains(&arg.as_str()) {
+                found.entry(arg).or_default().push(file);
+            }
+        }
+    }
+
+    for (name, files) in found {
+        if names.infers_args() {
+            if verbose {
+                eprintln!(
+                    "{} Detected registry identifier `{}` (argument in {} module(s))",
+                    "info:".blue().bold(),
+                    name,
+                    files.len()
+                );
+            }
+            continue;
+        }
+        let more = match files.len() {
+            1 => String::new(),
+            n => format!(" (and {} more)", n - 1),
+        };
+        eprintln!(
+            "{} argument `{}` in {}{} looks like a registry but is not checked; pass --registry-ident {}",
+            "warn:".yellow().bold(),
+            name,
+            files[0].display(),
+            more,
+            name
+        );
+    }
+}
+
+/// The registry source selected by `--registry-source` or `--registry-json`,
+/// at `git_ref` if given.
+fn registry_at(
+    backend: &RegistryBackend,
+    registry_name: &str,
+    git_ref: Option<&str>,
+) -> Result<Box<dyn source::RegistrySource>> {
+    let name = registry_name.to_string();
+    Ok(match (backend, git_ref) {
+        (RegistryBackend::Nix, None) => Box::new(source::FlakeEval { name }),
+        (RegistryBackend::Nix, Some(r)) => Box::new(source::GitRefEval {
+            name,
+            git_ref: r.to_string(),
+        }),
+        (RegistryBackend::Fs(dir), git_ref) => Box::new(source::FsLayout {
+            dir: dir.clone(),
+            git_ref: git_ref.map(str::to_string),
+        }),
+        (RegistryBackend::Json(_), Some(r)) => anyhow::bail!(
+            "A registry snapshot from --registry-json cannot be loaded at git ref '{}'",
+            r
+        ),
+        (RegistryBackend::Json(path), None) if path.as_os_str() == "-" => Box::new(source::Stdin),
+        (RegistryBackend::Json(path), None) => Box::new(source::JsonFile { path: path.clone() }),
+    })
+}
+
+/// Loads the registry from the selected source, at `git_ref` if given.
+fn load_registry(
+    backend: &RegistryBackend,
+    registry_name: &str,
+    git_ref: Option<&str>,
+) -> Result<serde_json::Value> {
+    registry_at(backend, registry_name, git_ref)?.load()
+}
+
+/// Registry renames from files git detects as moved below the registry
+/// directory since `git_ref` (or HEAD). The directory comes from
+/// `--registry-dir` or an `fs:` registry source; without one, or outside a
+/// git repository, there are none.
+fn git_moves(
+    registry_dir: Option<&Path>,
+    registry_source: &RegistryBackend,
+    git_ref: Option<&str>,
+    verbose: bool,
+) -> Vec<renames::Rename> {
+    let dir = match (registry_dir, registry_source) {
+        (Some(dir), _) => dir,
+        (None, RegistryBackend::Fs(dir)) => dir.as_path(),
+        (None, RegistryBackend::Nix | RegistryBackend::Json(_)) => return Vec::new(),
+    };
+    let base = git_ref.unwrap_or("HEAD");
+    let moves = match renames::git_moves(dir, base) {
+        Ok(moves) => moves,
+        Err(err) => {
+            if verbose {
+                eprintln!(
+                    "{} Skipping git rename detection: {:#}",
+                    "warn:".yellow().bold(),
+                    err
+                );
+            }
+            return Vec::new();
+        }
+    };
+
+    if verbose && !moves.is_empty() {
+        eprintln!(
+            "{} Git detected {} registry move(s) since '{}':",
+            "info:".blue().bold(),
+            moves.len(),
+            base
+        );
+        for rename in &moves {
+            eprintln!("  --rename {}={}", rename.from, rename.to);
+        }
+    }
+    moves
+}
+
+/// Adds the renames inferred by diffing the registry at `auto_renames`
+/// against `reg` to the explicit `--rename` map, printing them for review.
+fn auto_rename_map(
+    rename_map: HashMap<String, String>,
+    auto_renames: Option<&str>,
+    registry_source: &RegistryBackend,
+    registry_name: &str,
+    reg: &serde_json::Value,
+) -> Result<(HashMap<String, String>, Vec<renames::Rename>)> {
+    let Some(base) = auto_renames else {
+        return Ok((rename_map, Vec::new()));
+    };
+    let old = load_registry(registry_source, registry_name, Some(base))?;
+    let inferred = renames::diff(&old, reg).renames;
+
+    if inferred.is_empty() {
+        eprintln!(
+            "{} No renames inferred from '{}'",
+            "info:".blue().bold(),
+            base
+        );
+    } else {
+        eprintln!(
+            "{} Inferred {} rename(s) from '{}':",
+            "info:".blue().bold(),
+            inferred.len(),
+            base
+        );
+        for rename in &inferred {
+            eprintln!("  --rename {}={}", rename.from, rename.to);
+        }
+    }
+
+    Ok((renames::merge(&rename_map, &inferred), inferred))
+}
+
+/// Scans `files` while the registry is evaluated on another thread.
+///
+/// `nix eval` and parsing are independent and both slow on large trees, so
+/// neither waits for the other. With `use_cache`, unchanged files are served
+/// from the on-disk scan cache, which is updated afterwards.
+///
+/// With `lazy`, files are scanned first and only the paths they reference,
+/// and the paths the rename map sends them to, are evaluated. Mentions count
+/// only where `mentions` checks them.
+#[allow(clippy::too_many_arguments)]
+fn scan_with_registry(
+    files: &[PathBuf],
+    names: &scanner::RegistryNames,
+    jobs: Option<usize>,
+    use_cache: bool,
+    registry_source: &RegistryBackend,
+    registry_name: &str,
+    git_ref: Option<&str>,
+    lazy: Option<&HashMap<String, String>>,
+    mentions: MentionScope,
+) -> Result<(serde_json::Value, Vec<FileScan>)> {
+    let mut cache = use_cache.then(|| ScanCache::open(cache::default_path()));
+
+    let (reg, scans) = match lazy {
+        Some(rename_map) => {
+            let scans = scanner::scan_files(files, names, jobs, cache.as_mut())?;
+            let refs: Vec<RegistryRef> = files
+                .iter()
+                .zip(&scans)
+                .flat_map(|(file, scan)| {
+                    let mentioned = if mentions.includes(file) {
+                        scan.mentions.as_slice()
+                    } else {
+                        &[]
+                    };
+                    scan.refs.iter().chain(mentioned).cloned()
+                })
+                .collect();
+            let paths = analyzer::queried_paths(&refs, rename_map);
+            let reg = registry_at(registry_source, registry_name, git_ref)?.load_paths(&paths)?;
+            (reg, scans)
+        }
+        None => std::thread::scope(|s| {
+            let evaluation = s.spawn(|| load_registry(registry_source, registry_name, git_ref));
+            let scans = scanner::scan_files(files, names, jobs, cache.as_mut());
+            let reg = evaluation
+                .join()
+                .map_err(|_| anyhow::anyhow!("Registry evaluation panicked"))??;
+            anyhow::Ok((reg, scans?))
+        })?,
+    };
+
+    // The cache only saves work, so failing to write it is not fatal
+    if let Some(cache) = &mut cache
+        && let Err(err) = cache.save()
+    {
+        eprintln!("warn: Failed to save scan cache: {:#}", err);
+    }
+
+    Ok((reg, scans))
+}
+
 /// User's choice for handling a file's changes.
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum FileAction {
@@ -297,31 +908,137 @@ fn prompt_file_action(change_count: usize) -> Result<FileAction> {
     })
 }
 
-fn cmd_registry(registry_name: &str, git_ref: Option<&str>, depth: Option<usize>) -> Result<()> {
-    let reg = registry::evaluate(registry_name, git_ref)?;
-    registry::print_tree(&reg, depth.unwrap_or(usize::MAX), 0);
+fn cmd_registry(
+    registry_source: &RegistryBackend,
+    registry_name: &str,
+    git_ref: Option<&str>,
+    depth: Option<usize>,
+    json_output: bool,
+) -> Result<()> {
+    let reg = load_registry(registry_source, registry_name, git_ref)?;
+    if json_output {
+        println!("{}", serde_json::to_string_pretty(&reg)?);
+    } else {
+        registry::print_tree(&reg, depth.unwrap_or(usize::MAX), 0);
+    }
     Ok(())
 }
 
-fn cmd_scan(
-    paths: Option<Vec<PathBuf>>,
-    exclude: &[String],
-    use_default_excludes: bool,
+fn cmd_registry_diff(
+    registry_source: &RegistryBackend,
+    registry_name: &str,
+    from: &str,
+    to: Option<&str>,
+    json_output: bool,
 ) -> Result<()> {
+    let old = load_registry(registry_source, registry_name, Some(from))?;
+    let new = load_registry(registry_source, registry_name, to)?;
+    let diff = renames::diff(&old, &new);
+
+    if json_output {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+
+    if diff.renames.is_empty() && diff.removed.is_empty() && diff.added.is_empty() {
+        println!("{}", "No differences".green());
+        return Ok(());
+    }
+    if !diff.renames.is_empty() {
+        println!("{}", "Inferred renames:".bold());
+        for rename in &diff.renames {
+            println!("  --rename {}={}", rename.from, rename.to);
+        }
+    }
+    if !diff.removed.is_empty() {
+        println!("{}", "Removed:".bold());
+        for path in &diff.removed {
+            println!("  {}", format!("- {}", path).red());
+        }
+    }
+    if !diff.added.is_empty() {
+        println!("{}", "Added:".bold());
+        for path in &diff.added {
+            println!("  {}", format!("+ {}", path).green());
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_scan(paths: Option<Vec<PathBuf>>, collect: &scanner::CollectOptions) -> Result<()> {
     let scan_paths = paths.unwrap_or_else(|| vec![PathBuf::from(".")]);
-    let files = scanner::collect_nix_files(&scan_paths, exclude, use_default_excludes)?;
+    let collected = scanner::collect_nix_files(&scan_paths, collect)?;
 
-    println!("Would scan {} files:", files.len());
-    for file in files {
-        println!("  {}", file.display());
+    for err in &collected.errors {
+        print_read_error(err);
+    }
+    println!("Would scan {} files:", collected.files.len());
+    for (file, rule) in &collected.files {
+        println!("  {} {}", file.display(), format!("({})", rule).dimmed());
     }
 
     Ok(())
 }
 
+/// Fails the run if any file had syntax errors (`--strict`).
+fn check_parse_errors(parse_errors: &[ParseError]) -> Result<()> {
+    if !parse_errors.is_empty() {
+        anyhow::bail!(
+            "{} syntax error(s) in scanned files (--strict)",
+            parse_errors.len()
+        );
+    }
+    Ok(())
+}
+
+fn print_parse_error(err: &ParseError) {
+    eprintln!(
+        "{} {}:{}:{}: {}",
+        "warn:".yellow().bold(),
+        err.file.display(),
+        err.line,
+        err.column,
+        err.message
+    );
+}
+
+/// Warns about changes `apply` listed but could not write, which need
+/// fixing by hand.
+fn print_unrewritten(changes: &[(RegistryRef, String)], unrewritten: &[usize]) {
+    for &index in unrewritten {
+        let (reference, _) = &changes[index];
+        eprintln!(
+            "{} {}:{}:{}: could not rewrite {}.{}, left unchanged",
+            "warn:".yellow().bold(),
+            reference.file.display(),
+            reference.line,
+            reference.column,
+            reference.base,
+            reference.path
+        );
+    }
+}
+
+fn print_read_error(err: &ReadError) {
+    eprintln!(
+        "{} {}: skipped, {}",
+        "warn:".yellow().bold(),
+        err.file.display(),
+        err.message
+    );
+}
+
 fn print_detection_result(result: &DetectionResult, verbose: bool) {
     let d = &result.diagnostics;
 
+    for err in &result.read_errors {
+        print_read_error(err);
+    }
+    for err in &result.parse_errors {
+        print_parse_error(err);
+    }
+
     if verbose {
         println!(
             "\n{} Files: {}, Refs: {} ({} valid, {} broken)",
@@ -332,24 +1049,60 @@ fn print_detection_result(result: &DetectionResult, verbose: bool) {
             d.broken_refs
         );
         println!(
-            "             Suggestions: {}, Unsuggestable: {}",
-            d.suggestions_found, d.unsuggestable
+            "             Suggestions: {}, Unsuggestable: {}, Silent: {}, Skipped: {}, Parse errors: {}, Read errors: {}",
+            d.suggestions_found,
+            d.unsuggestable,
+            d.silent_refs,
+            d.skipped_refs,
+            d.parse_errors,
+            d.read_errors
         );
+        if d.total_mentions > 0 {
+            println!(
+                "             Mentions: {} ({} stale)",
+                d.total_mentions, d.broken_mentions
+            );
+        }
+
+        for skipped in &result.skipped {
+            print_skipped_ref(skipped);
+        }
     }
 
     if result.broken.is_empty() {
         println!("{} No broken references found", "ok:".green().bold());
-        return;
+    } else {
+        println!(
+            "\n{} {} broken reference(s):\n",
+            "Found".red().bold(),
+            result.broken.len()
+        );
+
+        for broken in &result.broken {
+            print_broken_ref(broken);
+        }
     }
 
-    println!(
-        "\n{} {} broken reference(s):\n",
-        "Found".red().bold(),
-        result.broken.len()
-    );
+    if !result.mentions.is_empty() {
+        println!(
+            "\n{} {} stale mention(s) in comments and docs:\n",
+            "Found".yellow().bold(),
+            result.mentions.len()
+        );
 
-    for broken in &result.broken {
-        print_broken_ref(broken);
+        for broken in &result.mentions {
+            print_broken_ref(broken);
+        }
+    }
+}
+
+/// Marks references written as relative path imports or mentions rather than
+/// selects.
+fn ref_kind_note(reference: &RegistryRef) -> String {
+    match reference.kind {
+        RefKind::Path { .. } => format!(" {}", "(path import)".dimmed()),
+        RefKind::Mention => format!(" {}", "(mention)".dimmed()),
+        _ => String::new(),
     }
 }
 
@@ -361,16 +1114,53 @@ fn print_broken_ref(broken: &BrokenRef) {
         broken.reference.column
     );
 
-    if let Some(ref suggestion) = broken.suggestion {
-        println!("  {} {}", loc.dimmed(), broken.reference.path.red());
+    let loc = if broken.silent {
+        format!("{} {}", "silent".red().bold(), loc.dimmed())
+    } else {
+        loc.dimmed().to_string()
+    };
+
+    if broken.reference.is_dynamic() {
+        println!(
+            "  {} {} {}",
+            loc,
+            broken.reference.display_path().red(),
+            format!(
+                "(dynamic: static prefix '{}' not in registry)",
+                broken.reference.path
+            )
+            .dimmed()
+        );
+    } else if let Some(ref suggestion) = broken.suggestion {
+        println!(
+            "  {} {}{}",
+            loc,
+            broken.reference.path.red(),
+            ref_kind_note(&broken.reference)
+        );
         println!("    {} {}", "->".green(), suggestion.green());
     } else {
         let reason = broken.reason.as_deref().unwrap_or("no suggestion");
         println!(
             "  {} {} {}",
-            loc.dimmed(),
+            loc,
             broken.reference.path.red(),
             format!("({})", reason).dimmed()
         );
     }
 }
+
+fn print_skipped_ref(skipped: &SkippedRef) {
+    let loc = format!(
+        "{}:{}:{}",
+        skipped.reference.file.display(),
+        skipped.reference.line,
+        skipped.reference.column
+    );
+    println!(
+        "  {} {} {}",
+        loc.dimmed(),
+        skipped.reference.display_path(),
+        format!("(skipped: {})", skipped.reason).dimmed()
+    );
+}
@@ -0,0 +1,292 @@
+//! Registry paths mentioned in prose rather than code.
+//!
+//! Comments such as `# see registry.home.alice` and fenced ```` ```nix ````
+//! blocks in Markdown documentation go stale after a rename just like code
+//! does, but they are never evaluated. They are found by matching text rather
+//! than walking the AST, and reported as [`RefKind::Mention`] so callers can
+//! keep them apart from real references.
+//!
+//! A mention is a registry name followed by one or more `.attr` segments,
+//! where an attribute is an identifier or a plain double-quoted string. The
+//! name must not be preceded by an identifier character or `.`, so
+//! `nix.registry.nixpkgs` is not a mention of `registry`.
+
+use crate::scanner::{PathSegment, RefKind, RegistryRef};
+use crate::span::LineIndex;
+use rnix::SyntaxKind;
+use std::path::Path;
+
+/// A registry path found in text, with byte offsets into that text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Mention {
+    start: usize,
+    end: usize,
+    base: String,
+    names: Vec<String>,
+}
+
+/// Returns true if `file` is scanned as Markdown rather than Nix.
+pub fn is_markdown(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|ext| ext == "md" || ext == "markdown")
+}
+
+/// Mentions inside the comments of a parsed Nix file.
+pub fn comment_mentions(
+    root: &rnix::SyntaxNode,
+    lines: &LineIndex,
+    file: &Path,
+    registry_names: &[&str],
+) -> Vec<RegistryRef> {
+    root.descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| token.kind() == SyntaxKind::TOKEN_COMMENT)
+        .flat_map(|token| {
+            let offset: usize = token.text_range().start().into();
+            find_mentions(token.text(), registry_names)
+                .into_iter()
+                .map(move |m| to_ref(m, offset, lines, file))
+        })
+        .collect()
+}
+
+/// Mentions inside the fenced `nix` code blocks of a Markdown file.
+pub fn markdown_mentions(source: &str, file: &Path, registry_names: &[&str]) -> Vec<RegistryRef> {
+    let lines = LineIndex::new(source);
+    nix_blocks(source)
+        .into_iter()
+        .flat_map(|(start, end)| {
+            find_mentions(&source[start..end], registry_names)
+                .into_iter()
+                .map(move |m| (m, start))
+        })
+        .map(|(m, offset)| to_ref(m, offset, &lines, file))
+        .collect()
+}
+
+fn to_ref(mention: Mention, offset: usize, lines: &LineIndex, file: &Path) -> RegistryRef {
+    let start = offset + mention.start;
+    let end = offset + mention.end;
+    let span = lines.span(start, end);
+    RegistryRef {
+        base: mention.base,
+        path: crate::registry::join_attrs(&mention.names),
+        segments: mention.names.into_iter().map(PathSegment::Static).collect(),
+        file: file.to_path_buf(),
+        line: span.start.line,
+        column: span.start.column,
+        end_line: span.end.line,
+        end_column: span.end.column,
+        span,
+        start_offset: start,
+        end_offset: end,
+        guarded: false,
+        kind: RefKind::Mention,
+    }
+}
+
+/// Byte ranges of the contents of fenced code blocks whose info string
+/// starts with `nix`.
+fn nix_blocks(source: &str) -> Vec<(usize, usize)> {
+    let mut blocks = Vec::new();
+    // Fence character, fence length, content start and whether it is `nix`
+    // for the block currently open
+    let mut open: Option<(char, usize, usize, bool)> = None;
+    let mut offset = 0;
+
+    for line in source.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+
+        let trimmed = line.trim_start_matches(' ');
+        if line.len() - trimmed.len() > 3 {
+            continue;
+        }
+        let Some(fence_char) = trimmed.chars().next().filter(|c| *c == '`' || *c == '~') else {
+            continue;
+        };
+        let fence_len = trimmed.chars().take_while(|c| *c == fence_char).count();
+        if fence_len < 3 {
+            continue;
+        }
+        let info = trimmed[fence_len..].trim();
+
+        match open {
+            None => {
+                let is_nix = info.split_whitespace().next() == Some("nix");
+                open = Some((fence_char, fence_len, offset, is_nix));
+            }
+            Some((c, len, content_start, is_nix))
+                if c == fence_char && fence_len >= len && info.is_empty() =>
+            {
+                if is_nix {
+                    blocks.push((content_start, line_start));
+                }
+                open = None;
+            }
+            Some(_) => {}
+        }
+    }
+
+    blocks
+}
+
+/// Finds every mention of one of `registry_names` in `text`. Where names
+/// overlap, the longest one starting at a position wins.
+fn find_mentions(text: &str, registry_names: &[&str]) -> Vec<Mention> {
+    let mut mentions = Vec::new();
+    let mut pos = 0;
+
+    while let Some((start, name)) = registry_names
+        .iter()
+        .filter_map(|name| text[pos..].find(name).map(|i| (pos + i, *name)))
+        .min_by_key(|(i, name)| (*i, std::cmp::Reverse(name.len())))
+    {
+        let after = start + name.len();
+        let at_boundary = text[..start]
+            .chars()
+            .next_back()
+            .is_none_or(|c| !is_attr_char(c) && c != '.');
+        let (names, end) = attrs_at(text, after);
+
+        if at_boundary && !names.is_empty() {
+            mentions.push(Mention {
+                start,
+                end,
+                base: name.to_string(),
+                names,
+            });
+            pos = end;
+        } else {
+            pos = after;
+        }
+    }
+
+    mentions
+}
+
+/// Parses `.a.b."c"` starting at `pos`, returning the names and where they end.
+fn attrs_at(text: &str, mut pos: usize) -> (Vec<String>, usize) {
+    let mut names = Vec::new();
+    while let Some(rest) = text[pos..].strip_prefix('.') {
+        let Some((name, len)) = attr(rest) else {
+            break;
+        };
+        names.push(name);
+        pos += 1 + len;
+    }
+    (names, pos)
+}
+
+/// An identifier or plain string at the start of `text` and its length.
+fn attr(text: &str) -> Option<(String, usize)> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let end = rest.find('"')?;
+        let name = &rest[..end];
+        if name.is_empty() || name.contains('\\') || name.contains("${") {
+            return None;
+        }
+        return Some((name.to_string(), end + 2));
+    }
+
+    let first = text.chars().next()?;
+    if !(first.is_ascii_alphabetic() || first == '_') {
+        return None;
+    }
+    let len = text.find(|c: char| !is_attr_char(c)).unwrap_or(text.len());
+    // A trailing `-` is punctuation in prose, not part of the name
+    let len = text[..len].trim_end_matches('-').len();
+    Some((text[..len].to_string(), len))
+}
+
+fn is_attr_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(text: &str, names: &[&str]) -> Vec<String> {
+        find_mentions(text, names)
+            .into_iter()
+            .map(|m| format!("{}:{}", m.base, m.names.join(".")))
+            .collect()
+    }
+
+    #[test]
+    fn finds_mentions_in_prose() {
+        assert_eq!(
+            paths(
+                "See registry.home.alice. Also registry.\"web-app\".nginx, not nix.registry.x",
+                &["registry"]
+            ),
+            vec!["registry:home.alice", "registry:web-app.nginx"]
+        );
+    }
+
+    #[test]
+    fn requires_a_segment_and_a_boundary() {
+        assert!(paths("the registry. myregistry.a registryX.a", &["registry"]).is_empty());
+        assert!(paths("registry.${name}", &["registry"]).is_empty());
+    }
+
+    #[test]
+    fn longest_overlapping_name_wins() {
+        assert_eq!(
+            paths("self.registry.users.bob", &["self", "self.registry"]),
+            vec!["self.registry:users.bob"]
+        );
+    }
+
+    #[test]
+    fn offsets_cover_the_mention() {
+        let text = "# uses registry.users.alice here";
+        let m = &find_mentions(text, &["registry"])[0];
+        assert_eq!(&text[m.start..m.end], "registry.users.alice");
+    }
+
+    #[test]
+    fn comment_mentions_in_nix_source() {
+        let source =
+            "{\n  # was registry.home.alice\n  x = registry.users.bob; /* registry.svc.db */\n}";
+        let root = rnix::Root::parse(source).syntax();
+        let lines = LineIndex::new(source);
+        let refs = comment_mentions(&root, &lines, Path::new("a.nix"), &["registry"]);
+        let found: Vec<_> = refs.iter().map(|r| (r.path.as_str(), r.line)).collect();
+        assert_eq!(found, vec![("home.alice", 2), ("svc.db", 3)]);
+        assert!(refs.iter().all(|r| matches!(r.kind, RefKind::Mention)));
+    }
+
+    #[test]
+    fn markdown_mentions_only_in_nix_blocks() {
+        let source = "\
+Intro mentions registry.prose.only.
+
+```nix
+{ imports = [ registry.home.alice ]; }
+```
+
+```sh
+echo registry.shell.block
+```
+
+~~~~nix title
+registry.users.bob
+~~~~
+";
+        let refs = markdown_mentions(source, Path::new("README.md"), &["registry"]);
+        let found: Vec<_> = refs.iter().map(|r| (r.path.as_str(), r.line)).collect();
+        assert_eq!(found, vec![("home.alice", 4), ("users.bob", 12)]);
+        assert_eq!(
+            &source[refs[0].start_offset..refs[0].end_offset],
+            "registry.home.alice"
+        );
+    }
+
+    #[test]
+    fn unterminated_block_is_ignored() {
+        let source = "```nix\nregistry.home.alice\n";
+        assert!(markdown_mentions(source, Path::new("a.md"), &["registry"]).is_empty());
+    }
+}
@@ -1,39 +1,335 @@
 //! Registry evaluation and traversal.
 //!
 //! Shells out to `nix eval --json .#registry` to obtain the current registry
-//! structure, then provides utilities for flattening it into a set of valid
-//! attribute paths and printing it as a tree.
+//! structure, or builds the same tree from the registry directory without
+//! Nix, then provides utilities for flattening it into a set of valid
+//! attribute paths and printing it as a tree. Evaluation only forces
+//! attribute names, recording the [`Kind`] of each leaf, and can be narrowed
+//! to the paths a scan referenced with [`evaluate_paths`].
 //!
 //! Supports evaluating against a specific git ref (e.g., HEAD, HEAD^, main)
 //! to compare working tree changes against the committed registry state.
 
 use anyhow::{Context, Result};
 use colored::Colorize;
-use std::collections::HashSet;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 
+/// Nix bindings shared by the generated registry expressions.
+///
+/// `kindOf` classifies a value without serialising it, so module functions,
+/// derivations and entries that `throw` never fail the evaluation.
+/// Attributes starting with `__` are imp metadata and left out by `names`.
+const NIX_HELPERS: &str = r#"
+  kindOf =
+    value:
+    let
+      result = builtins.tryEval value;
+      v = result.value;
+    in
+    if !result.success then
+      "error"
+    else if builtins.isFunction v then
+      "function"
+    else if builtins.isPath v then
+      "path"
+    else if builtins.isAttrs v then
+      if (builtins.tryEval (v.type or null)).value == "derivation" then
+        "derivation"
+      else if v ? __functor then
+        "function"
+      else
+        "attrset"
+    else
+      "value";
+  names = v: builtins.filter (name: builtins.substring 0 2 name != "__") (builtins.attrNames v);
+"#;
+
+/// Nix function applied to the registry so that evaluation only forces
+/// attribute names.
+///
+/// Attrsets are walked one level at a time; everything else becomes a
+/// `{ __kind = ...; }` marker.
+fn names_only() -> String {
+    [
+        "let",
+        NIX_HELPERS,
+        r#"
+  walk =
+    value:
+    let
+      kind = kindOf value;
+    in
+    if kind == "attrset" then
+      builtins.listToAttrs (
+        map (name: {
+          inherit name;
+          value = walk value.${name};
+        }) (names value)
+      )
+    else
+      { __kind = kind; };
+in
+walk
+"#,
+    ]
+    .concat()
+}
+
+/// Nix function applied to the registry that answers, for each of `paths`,
+/// whether it exists and what is there, as a list in the same order.
+///
+/// An existing path yields `{ kind; names; }`, with `names` listing the
+/// attributes of an attrset and `null` for anything else; a missing one
+/// yields `null`. Only the attrsets along the queried paths are forced.
+fn path_query(paths: &[Vec<String>]) -> String {
+    let list: Vec<String> = paths
+        .iter()
+        .map(|path| {
+            let names: Vec<String> = path.iter().map(|n| nix_string(n) + " ").collect();
+            format!("[ {}]", names.concat())
+        })
+        .collect();
+    [
+        "let",
+        NIX_HELPERS,
+        r#"
+  # Like lib.hasAttrByPath, without descending into leaves
+  hasAttrByPath =
+    path: set:
+    path == [ ]
+    || (
+      kindOf set == "attrset"
+      && builtins.substring 0 2 (builtins.head path) != "__"
+      && set ? ${builtins.head path}
+      && hasAttrByPath (builtins.tail path) set.${builtins.head path}
+    );
+  query =
+    registry: path:
+    if hasAttrByPath path registry then
+      let
+        value = builtins.foldl' (set: name: set.${name}) registry path;
+        kind = kindOf value;
+      in
+      {
+        inherit kind;
+        names = if kind == "attrset" then names value else null;
+      }
+    else
+      null;
+in
+registry: map (query registry) [ "#,
+        &list.join(" "),
+        " ]\n",
+    ]
+    .concat()
+}
+
+/// Kind of value at a registry path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A namespace whose attributes are registry paths too.
+    Attrset,
+    Function,
+    Path,
+    Derivation,
+    /// Evaluating the entry threw.
+    Error,
+    /// Any other value.
+    Value,
+}
+
+impl Kind {
+    /// Kind of a node in a registry tree.
+    ///
+    /// Trees from names-only evaluation mark leaves as `{ "__kind": ... }`.
+    /// Plain objects are attrsets, and strings are the paths that
+    /// `nix eval --json` and [`build`] produce for files.
+    pub fn of(value: &serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Object(map) => match map.get(KIND_KEY).and_then(|k| k.as_str()) {
+                None | Some("attrset") => Kind::Attrset,
+                Some("function") => Kind::Function,
+                Some("path") => Kind::Path,
+                Some("derivation") => Kind::Derivation,
+                Some("error") => Kind::Error,
+                Some(_) => Kind::Value,
+            },
+            serde_json::Value::String(_) => Kind::Path,
+            _ => Kind::Value,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Attrset => "attrset",
+            Kind::Function => "function",
+            Kind::Path => "path",
+            Kind::Derivation => "derivation",
+            Kind::Error => "error",
+            Kind::Value => "value",
+        }
+    }
+}
+
+/// Key marking a leaf's kind in names-only evaluation output.
+const KIND_KEY: &str = "__kind";
+
+/// Returns the attributes of a namespace node, or `None` for leaves.
+pub fn children(value: &serde_json::Value) -> Option<&serde_json::Map<String, serde_json::Value>> {
+    match value {
+        serde_json::Value::Object(map) if !map.contains_key(KIND_KEY) => Some(map),
+        _ => None,
+    }
+}
+
 /// Evaluates the flake's registry attribute by invoking `nix eval --json`.
 ///
 /// If `git_ref` is provided, evaluates the registry from that git ref using
 /// `builtins.getFlake`. Otherwise evaluates from the current working tree.
 ///
-/// Returns the parsed JSON value representing the registry's nested attrset structure.
-/// Fails if `nix eval` returns non-zero or produces invalid JSON.
+/// `name` may be a dotted base as written in source, such as `self.registry`;
+/// see [`flake_attr`].
+///
+/// Only attribute names are forced (see [`Kind`]): the result is a tree of
+/// attrsets whose leaves record the kind of value found there. Fails if
+/// `nix eval` returns non-zero or produces invalid JSON.
 pub fn evaluate(name: &str, git_ref: Option<&str>) -> Result<serde_json::Value> {
+    let output = nix_eval(name, git_ref, &names_only())?;
+    serde_json::from_slice(&output).context("Failed to parse registry JSON")
+}
+
+/// What a targeted query found at an existing registry path.
+#[derive(Debug, Clone, Deserialize)]
+struct PathInfo {
+    kind: String,
+    /// Attribute names, for attrsets.
+    names: Option<Vec<String>>,
+}
+
+/// Evaluates only what is needed to check `paths`, for registries too large
+/// to evaluate whole.
+///
+/// `nix eval` checks every path and each of its parents with
+/// `hasAttrByPath`, listing the attribute names of the attrsets among them.
+/// Paths are sent in batches so each generated expression stays below the
+/// operating system's limit on the length of a single argument.
+/// The result is a partial registry tree in the shape [`evaluate`] returns:
+/// it contains the queried paths that exist and the siblings of every path
+/// along the way, but nothing else, so leaf-name suggestions only consider
+/// paths in this view.
+pub fn evaluate_paths(
+    name: &str,
+    git_ref: Option<&str>,
+    paths: &[Vec<String>],
+) -> Result<serde_json::Value> {
+    let queries = query_paths(paths);
+    let mut answers: Vec<Option<PathInfo>> = Vec::with_capacity(queries.len());
+    for batch in query_batches(&queries, MAX_QUERY_BYTES) {
+        let output = nix_eval(name, git_ref, &path_query(batch))?;
+        let batch_answers: Vec<Option<PathInfo>> =
+            serde_json::from_slice(&output).context("Failed to parse registry JSON")?;
+        if batch_answers.len() != batch.len() {
+            anyhow::bail!(
+                "nix eval answered {} of {} registry paths",
+                batch_answers.len(),
+                batch.len()
+            );
+        }
+        answers.extend(batch_answers);
+    }
+    Ok(partial_tree(&queries, &answers))
+}
+
+/// Upper bound on the quoted paths in one [`path_query`], well below Linux's
+/// 128 KiB limit on a single command-line argument.
+const MAX_QUERY_BYTES: usize = 64 * 1024;
+
+/// Splits `queries` into consecutive batches whose paths take at most
+/// `max_bytes` once quoted. A path longer than that gets a batch of its own.
+fn query_batches(queries: &[Vec<String>], max_bytes: usize) -> Vec<&[Vec<String>]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (i, path) in queries.iter().enumerate() {
+        let len: usize = path.iter().map(|n| nix_string(n).len() + 1).sum::<usize>() + 4;
+        if i > start && size + len > max_bytes {
+            batches.push(&queries[start..i]);
+            start = i;
+            size = 0;
+        }
+        size += len;
+    }
+    if start < queries.len() {
+        batches.push(&queries[start..]);
+    }
+    batches
+}
+
+/// `paths` and all of their parents, including the registry itself, in an
+/// order where every parent precedes its children.
+fn query_paths(paths: &[Vec<String>]) -> Vec<Vec<String>> {
+    let mut queries = BTreeSet::new();
+    for path in paths {
+        for len in 0..=path.len() {
+            queries.insert(path[..len].to_vec());
+        }
+    }
+    queries.into_iter().collect()
+}
+
+/// Assembles the answers to [`query_paths`] into a registry tree. Attributes
+/// that were listed but not queried become empty attrsets.
+fn partial_tree(queries: &[Vec<String>], answers: &[Option<PathInfo>]) -> serde_json::Value {
+    let mut root = serde_json::Value::Object(serde_json::Map::new());
+    for (path, info) in queries.iter().zip(answers) {
+        let Some(info) = info else {
+            continue;
+        };
+        let Some(node) = path.iter().try_fold(&mut root, |node, name| {
+            Some(
+                node.as_object_mut()?
+                    .entry(name.clone())
+                    .or_insert_with(|| serde_json::json!({})),
+            )
+        }) else {
+            continue;
+        };
+        match &info.names {
+            Some(names) => {
+                if let Some(map) = node.as_object_mut() {
+                    for name in names {
+                        map.entry(name.clone())
+                            .or_insert_with(|| serde_json::json!({}));
+                    }
+                }
+            }
+            None => *node = serde_json::json!({ KIND_KEY: info.kind }),
+        }
+    }
+    root
+}
+
+/// Runs `nix eval --json` on the registry attribute `name` with `apply`
+/// applied, at `git_ref` or in the working tree, returning its output.
+fn nix_eval(name: &str, git_ref: Option<&str>, apply: &str) -> Result<Vec<u8>> {
+    let name = flake_attr(name);
     let output = match git_ref {
         Some(r) => {
             // Resolve git ref to commit hash for use in flake URL
             let commit = resolve_git_ref(r)?;
             let expr = format!("(builtins.getFlake \"git+file:.?rev={}\").{}", commit, name);
             Command::new("nix")
-                .args(["eval", "--json", "--expr", &expr])
+                .args(["eval", "--json", "--expr", &expr, "--apply", apply])
                 .output()
                 .context("Failed to run nix eval")?
         }
         None => {
             let attr = format!(".#{}", name);
             Command::new("nix")
-                .args(["eval", "--json", &attr])
+                .args(["eval", "--json", &attr, "--apply", apply])
                 .output()
                 .context("Failed to run nix eval")?
         }
@@ -44,11 +340,209 @@ pub fn evaluate(name: &str, git_ref: Option<&str>) -> Result<serde_json::Value>
         anyhow::bail!("nix eval failed: {}", stderr);
     }
 
-    serde_json::from_slice(&output.stdout).context("Failed to parse registry JSON")
+    Ok(output.stdout)
+}
+
+/// Builds the registry from the directory it is generated from, without
+/// invoking Nix.
+///
+/// Applies imp's mapping rules: `home/alice.nix` becomes `home.alice`, a
+/// directory containing `default.nix` becomes a single leaf, and entries
+/// whose names start with `_` or `.` are skipped along with anything that is
+/// not a `.nix` file. Leaves hold the path of their file or directory, the
+/// way `nix eval` renders paths.
+///
+/// If `git_ref` is provided, the directory is read from that commit's tree
+/// instead of the working tree.
+pub fn build(dir: &Path, git_ref: Option<&str>) -> Result<serde_json::Value> {
+    let files = match git_ref {
+        Some(r) => git_files(dir, r)?,
+        None => {
+            if !dir.is_dir() {
+                anyhow::bail!("Registry directory not found: {}", dir.display());
+            }
+            let mut files = Vec::new();
+            walk_dir(dir, Path::new(""), &mut files)?;
+            files
+        }
+    };
+    Ok(build_tree(dir, &files))
+}
+
+/// Collects the `.nix` files below `dir`, relative to the registry root,
+/// skipping `_` and `.` entries.
+fn walk_dir(root: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let dir = root.join(relative);
+    let entries = std::fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read registry directory {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("Failed to read registry directory {}", dir.display()))?;
+        let name = entry.file_name();
+        if is_hidden(&name.to_string_lossy()) {
+            continue;
+        }
+        let path = relative.join(&name);
+        if root.join(&path).is_dir() {
+            walk_dir(root, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Lists the files below `dir` in the tree of `git_ref`, relative to `dir`.
+fn git_files(dir: &Path, git_ref: &str) -> Result<Vec<PathBuf>> {
+    let commit = resolve_git_ref(git_ref)?;
+    let relative = relative_dir(dir)?;
+
+    // `rev:./path` is resolved against the current directory, and
+    // `--full-tree` stops ls-tree from filtering by it a second time
+    let tree = format!("{}:./{}", commit, relative.display());
+    let output = Command::new("git")
+        .args(["ls-tree", "-r", "-z", "--full-tree", "--name-only", &tree])
+        .output()
+        .context("Failed to run git ls-tree")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Registry directory not found at '{}': {}",
+            git_ref,
+            dir.display()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split('\0')
+        .filter(|name| !name.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// `dir` relative to the current directory, which git resolves paths
+/// against.
+pub(crate) fn relative_dir(dir: &Path) -> Result<PathBuf> {
+    let cwd = std::env::current_dir().context("Failed to read current directory")?;
+    relative_to(dir, &cwd)
+}
+
+/// `dir` relative to `base`, without `.` components, so `./registry` can be
+/// stripped from the paths git prints just like `registry`.
+pub(crate) fn relative_to(dir: &Path, base: &Path) -> Result<PathBuf> {
+    let relative = if dir.is_absolute() {
+        dir.strip_prefix(base).with_context(|| {
+            format!(
+                "Registry directory {} is outside {}",
+                dir.display(),
+                base.display()
+            )
+        })?
+    } else {
+        dir
+    };
+    Ok(relative
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect())
+}
+
+/// Maps files relative to the registry root onto the registry's attribute
+/// tree.
+fn build_tree(root: &Path, files: &[PathBuf]) -> serde_json::Value {
+    let entries: Vec<(&PathBuf, Vec<String>)> = files
+        .iter()
+        .filter_map(|path| Some((path, file_attrs(path)?)))
+        .collect();
+
+    // A directory with a `default.nix` is a single leaf; nothing below it
+    // is part of the registry
+    let folded: Vec<&[String]> = entries
+        .iter()
+        .filter(|(path, _)| path.file_name().is_some_and(|n| n == "default.nix"))
+        .map(|(_, attrs)| attrs.as_slice())
+        .collect();
+
+    let mut tree = serde_json::Map::new();
+    for (path, attrs) in &entries {
+        let is_below_fold = folded
+            .iter()
+            .any(|dir| attrs.len() > dir.len() && attrs.starts_with(dir));
+        if is_below_fold {
+            continue;
+        }
+        let source = match path.file_name() {
+            Some(name) if name == "default.nix" => path.parent().unwrap_or(path),
+            _ => path.as_path(),
+        };
+        insert_leaf(&mut tree, attrs, root.join(source).display().to_string());
+    }
+
+    serde_json::Value::Object(tree)
+}
+
+/// Registry attribute names of a file, given relative to the registry
+/// directory, by imp's mapping rules: `home/alice.nix` and
+/// `home/alice/default.nix` both give `home.alice`.
+///
+/// Returns `None` for files that are not part of the registry: anything
+/// other than `.nix` files, entries starting with `_` or `.`, and a
+/// `default.nix` at the root.
+pub fn file_attrs(relative: &Path) -> Option<Vec<String>> {
+    let mut names: Vec<String> = relative
+        .components()
+        .filter(|c| !matches!(c, std::path::Component::CurDir))
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    if names.iter().any(|n| is_hidden(n)) {
+        return None;
+    }
+    let file = names.pop()?;
+    if file == "default.nix" {
+        return (!names.is_empty()).then_some(names);
+    }
+    names.push(file.strip_suffix(".nix")?.to_string());
+    Some(names)
+}
+
+fn insert_leaf(
+    tree: &mut serde_json::Map<String, serde_json::Value>,
+    attrs: &[String],
+    leaf: String,
+) {
+    let (name, parents) = attrs.split_last().expect("attrs are non-empty");
+    let mut node = tree;
+    for parent in parents {
+        let child = node
+            .entry(parent.clone())
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        // A directory wins over a file of the same name
+        if !child.is_object() {
+            *child = serde_json::Value::Object(serde_json::Map::new());
+        }
+        node = child.as_object_mut().expect("just made an object");
+    }
+    node.entry(name.clone())
+        .or_insert(serde_json::Value::String(leaf));
+}
+
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('_') || name.starts_with('.')
+}
+
+/// Flake output attribute for a registry name as written in source.
+///
+/// `self` and `inputs.self` refer to the flake's own outputs, so
+/// `self.registry` and `inputs.self.registry` evaluate `.#registry`. Other
+/// names are used as given.
+pub fn flake_attr(name: &str) -> &str {
+    name.strip_prefix("inputs.self.")
+        .or_else(|| name.strip_prefix("self."))
+        .unwrap_or(name)
 }
 
 /// Resolves a git ref (branch, tag, HEAD, HEAD^, etc.) to a full commit hash.
-fn resolve_git_ref(git_ref: &str) -> Result<String> {
+pub(crate) fn resolve_git_ref(git_ref: &str) -> Result<String> {
     let output = Command::new("git")
         .args(["rev-parse", git_ref])
         .output()
@@ -63,19 +557,97 @@ fn resolve_git_ref(git_ref: &str) -> Result<String> {
     Ok(commit)
 }
 
+/// Renders an attribute name as it would appear in a Nix attrpath.
+///
+/// Names that are not bare identifiers, such as `"2fa"` or `"web.app"`, are
+/// quoted so a dotted path stays unambiguous and can be written back to source.
+pub fn format_attr(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "assert", "else", "if", "in", "inherit", "let", "rec", "then", "with",
+    ];
+
+    let mut chars = name.chars();
+    let is_ident = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'))
+        && !KEYWORDS.contains(&name);
+
+    if is_ident {
+        name.to_string()
+    } else {
+        nix_string(name)
+    }
+}
+
+/// Renders `text` as a double-quoted Nix string literal.
+pub(crate) fn nix_string(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{}\"", escaped)
+}
+
+/// Joins attribute names into a dotted path, quoting where needed.
+pub fn join_attrs<S: AsRef<str>>(names: &[S]) -> String {
+    names
+        .iter()
+        .map(|n| format_attr(n.as_ref()))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Splits a dotted path produced by [`join_attrs`] back into attribute names.
+pub fn split_attrs(path: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut chars = path.chars().peekable();
+
+    while chars.peek().is_some() {
+        let mut name = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => name.extend(chars.next()),
+                    '"' => break,
+                    _ => name.push(c),
+                }
+            }
+            // Skip to the separator after the closing quote
+            for c in chars.by_ref() {
+                if c == '.' {
+                    break;
+                }
+            }
+        } else {
+            for c in chars.by_ref() {
+                if c == '.' {
+                    break;
+                }
+                name.push(c);
+            }
+        }
+        names.push(name);
+    }
+
+    names
+}
+
 /// Recursively flattens a registry JSON value into all valid dotted paths.
 ///
 /// Given `{ home = { alice = {}; bob = {}; }; }`, returns the set
-/// `["home", "home.alice", "home.bob"]`.
+/// `["home", "home.alice", "home.bob"]`. Only attrsets are descended into;
+/// a function or derivation leaf is a path, but its attributes are not.
 pub fn flatten_paths(value: &serde_json::Value, prefix: &str) -> HashSet<String> {
     let mut paths = HashSet::new();
 
-    if let serde_json::Value::Object(map) = value {
+    if let Some(map) = children(value) {
         for (key, val) in map {
             let path = if prefix.is_empty() {
-                key.clone()
+                format_attr(key)
             } else {
-                format!("{}.{}", prefix, key)
+                format!("{}.{}", prefix, format_attr(key))
             };
             paths.insert(path.clone());
             paths.extend(flatten_paths(val, &path));
@@ -87,22 +659,29 @@ pub fn flatten_paths(value: &serde_json::Value, prefix: &str) -> HashSet<String>
 
 /// Prints the registry as an indented tree to stdout.
 ///
-/// Leaf nodes (non-objects or empty objects) are dimmed. Recurses up to
-/// `max_depth` levels.
+/// Leaf nodes (anything but a non-empty attrset) are dimmed and followed by
+/// their kind. Recurses up to `max_depth` levels.
 pub fn print_tree(value: &serde_json::Value, max_depth: usize, depth: usize) {
     if depth >= max_depth {
         return;
     }
 
-    if let serde_json::Value::Object(map) = value {
+    if let Some(map) = children(value) {
         let indent = "  ".repeat(depth);
         for (key, val) in map {
-            let is_leaf = !val.is_object() || val.as_object().is_some_and(|m| m.is_empty());
-            if is_leaf {
-                println!("{}{}", indent, key.dimmed());
-            } else {
-                println!("{}{}", indent, key);
-                print_tree(val, max_depth, depth + 1);
+            match children(val) {
+                Some(inner) if !inner.is_empty() => {
+                    println!("{}{}", indent, key);
+                    print_tree(val, max_depth, depth + 1);
+                }
+                _ => {
+                    let kind = Kind::of(val);
+                    let label = match kind {
+                        Kind::Error => kind.as_str().red().to_string(),
+                        _ => kind.as_str().dimmed().to_string(),
+                    };
+                    println!("{}{} ({})", indent, key.dimmed(), label);
+                }
             }
         }
     }
@@ -113,6 +692,203 @@ mod tests {
     use super::*;
     use serde_json::json;
 
+    #[test]
+    fn flake_attr_strips_self() {
+        assert_eq!(flake_attr("registry"), "registry");
+        assert_eq!(flake_attr("self.registry"), "registry");
+        assert_eq!(flake_attr("inputs.self.registry"), "registry");
+        assert_eq!(flake_attr("config.imp.registry"), "config.imp.registry");
+    }
+
+    #[test]
+    fn build_tree_applies_mapping_rules() {
+        let files: Vec<PathBuf> = [
+            "home/alice/default.nix",
+            "home/alice/extra.nix",
+            "users/bob.nix",
+            "users/notes.md",
+            "_private/secret.nix",
+            "svc/.hidden.nix",
+            "default.nix",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        let tree = build_tree(Path::new("registry"), &files);
+        assert_eq!(
+            tree,
+            json!({
+                "home": { "alice": "registry/home/alice" },
+                "users": { "bob": "registry/users/bob.nix" },
+            })
+        );
+    }
+
+    #[test]
+    fn file_attrs_maps_files() {
+        let attrs = |path: &str| file_attrs(Path::new(path));
+        assert_eq!(attrs("home/alice.nix").unwrap(), vec!["home", "alice"]);
+        assert_eq!(
+            attrs("./home/alice/default.nix").unwrap(),
+            vec!["home", "alice"]
+        );
+        assert_eq!(attrs("default.nix"), None);
+        assert_eq!(attrs("home/_draft.nix"), None);
+        assert_eq!(attrs("home/notes.md"), None);
+    }
+
+    #[test]
+    fn build_walks_registry_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("users/alice")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::write(root.join("users/alice/default.nix"), "{}").unwrap();
+        std::fs::write(root.join("users/bob.nix"), "{}").unwrap();
+        std::fs::write(root.join(".git/x.nix"), "{}").unwrap();
+
+        let paths = flatten_paths(&build(root, None).unwrap(), "");
+        let mut paths: Vec<_> = paths.into_iter().collect();
+        paths.sort();
+        assert_eq!(paths, vec!["users", "users.alice", "users.bob"]);
+    }
+
+    #[test]
+    fn build_missing_directory_is_an_error() {
+        let err = build(Path::new("does/not/exist"), None).unwrap_err();
+        assert!(err.to_string().contains("Registry directory not found"));
+    }
+
+    #[test]
+    fn flatten_paths_stops_at_leaves() {
+        let value = json!({
+            "home": {
+                "alice": { "__kind": "function" },
+                "pkg": { "__kind": "derivation" },
+                "broken": { "__kind": "error" }
+            }
+        });
+        let mut paths: Vec<_> = flatten_paths(&value, "").into_iter().collect();
+        paths.sort();
+        assert_eq!(paths, vec!["home", "home.alice", "home.broken", "home.pkg"]);
+    }
+
+    #[test]
+    fn query_paths_include_parents() {
+        let names = |path: &str| split_attrs(path);
+        let queries = query_paths(&[names("home.alice"), names("home.bob"), names("svc")]);
+        let expected: Vec<Vec<String>> = vec![
+            vec![],
+            names("home"),
+            names("home.alice"),
+            names("home.bob"),
+            names("svc"),
+        ];
+        assert_eq!(queries, expected);
+    }
+
+    #[test]
+    fn partial_tree_from_answers() {
+        let info = |kind: &str, names: Option<&[&str]>| {
+            Some(PathInfo {
+                kind: kind.to_string(),
+                names: names.map(|n| n.iter().map(|s| s.to_string()).collect()),
+            })
+        };
+        let queries = query_paths(&[split_attrs("home.alice"), split_attrs("old.bob")]);
+        // Queries are [], home, home.alice, old, old.bob
+        let answers = vec![
+            info("attrset", Some(&["home", "pkgs"])),
+            info("attrset", Some(&["alice", "carol"])),
+            info("function", None),
+            None,
+            None,
+        ];
+        let tree = partial_tree(&queries, &answers);
+        assert_eq!(
+            tree,
+            json!({
+                "home": { "alice": { "__kind": "function" }, "carol": {} },
+                "pkgs": {}
+            })
+        );
+        let mut paths: Vec<_> = flatten_paths(&tree, "").into_iter().collect();
+        paths.sort();
+        assert_eq!(paths, vec!["home", "home.alice", "home.carol", "pkgs"]);
+    }
+
+    #[test]
+    fn path_query_quotes_names() {
+        let query = path_query(&[vec![], split_attrs(r#"home."web-app""#)]);
+        assert!(query.ends_with("[ [ ] [ \"home\" \"web-app\" ] ]\n"));
+    }
+
+    #[test]
+    fn query_batches_stay_under_the_limit() {
+        let queries: Vec<Vec<String>> = (0..100)
+            .map(|i| vec!["home".to_string(), format!("user{:03}", i)])
+            .collect();
+        let batches = query_batches(&queries, 200);
+        assert!(batches.len() > 1);
+        assert!(
+            batches
+                .iter()
+                .all(|batch| path_query(batch).len() < path_query(&[]).len() + 200)
+        );
+        assert_eq!(batches.concat(), queries);
+
+        // A path over the limit still gets queried, on its own
+        let long = vec![vec!["x".repeat(300)], vec!["y".to_string()]];
+        assert_eq!(query_batches(&long, 200).len(), 2);
+    }
+
+    #[test]
+    fn path_query_evaluates_with_nix() {
+        // Only runs where nix is installed
+        if Command::new("nix").arg("--version").output().is_err() {
+            return;
+        }
+        let registry = r#"{ home = { alice = x: x; bob = "b"; }; svc = { }; }"#;
+        let queries = query_paths(&[
+            split_attrs("home.alice.x"),
+            split_attrs("home.carol"),
+            split_attrs("svc"),
+        ]);
+        let mut answers: Vec<Option<PathInfo>> = Vec::new();
+        for batch in query_batches(&queries, 16) {
+            let output = Command::new("nix")
+                .args(["--extra-experimental-features", "nix-command"])
+                .args(["eval", "--json", "--expr", registry])
+                .args(["--apply", &path_query(batch)])
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            answers
+                .extend(serde_json::from_slice::<Vec<Option<PathInfo>>>(&output.stdout).unwrap());
+        }
+        assert_eq!(
+            partial_tree(&queries, &answers),
+            json!({
+                "home": { "alice": { "__kind": "function" }, "bob": {} },
+                "svc": {}
+            })
+        );
+    }
+
+    #[test]
+    fn kinds_of_nodes() {
+        assert_eq!(Kind::of(&json!({ "a": {} })), Kind::Attrset);
+        assert_eq!(Kind::of(&json!({ "__kind": "function" })), Kind::Function);
+        assert_eq!(Kind::of(&json!({ "__kind": "error" })), Kind::Error);
+        assert_eq!(Kind::of(&json!("/nix/store/x-alice.nix")), Kind::Path);
+        assert_eq!(Kind::of(&json!(3)), Kind::Value);
+        assert!(children(&json!({ "__kind": "path" })).is_none());
+    }
+
     #[test]
     fn flatten_paths_empty() {
         let value = json!({});
@@ -155,6 +931,29 @@ mod tests {
         assert_eq!(paths.len(), 2);
     }
 
+    #[test]
+    fn flatten_paths_quotes_non_identifier_keys() {
+        let value = json!({ "services": { "web.app": {}, "2fa": {}, "web-app": {} } });
+        let paths = flatten_paths(&value, "");
+        assert!(paths.contains(r#"services."web.app""#));
+        assert!(paths.contains(r#"services."2fa""#));
+        assert!(paths.contains("services.web-app"));
+    }
+
+    #[test]
+    fn format_attr_escapes_quotes_and_interpolation() {
+        assert_eq!(format_attr("alice"), "alice");
+        assert_eq!(format_attr("if"), r#""if""#);
+        assert_eq!(format_attr(r#"a"b"#), r#""a\"b""#);
+        assert_eq!(format_attr("${x}"), r#""\${x}""#);
+    }
+
+    #[test]
+    fn split_attrs_round_trips_quoted_names() {
+        let names = vec!["services", "web.app", r#"a"b"#, "${x}", "web-app"];
+        assert_eq!(split_attrs(&join_attrs(&names)), names);
+    }
+
     #[test]
     #[ignore] // Requires git repository context (not available in Nix sandbox)
     fn resolve_git_ref_head() {
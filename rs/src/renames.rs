@@ -0,0 +1,610 @@
+//! Rename inference by diffing two registries.
+//!
+//! Paths present in only one registry are gathered as the largest removed and
+//! added subtrees. A removed subtree is paired with an added one when each is
+//! the other's unique best match: subtrees with children are compared by the
+//! attribute paths below them, leaves by name. Subtrees left unpaired are
+//! split into their children and matched again, so moving `home/alice` and
+//! `home/bob` to different parents still yields two renames.
+//!
+//! Renames can also come straight from git: files it detects as moved below
+//! the registry directory are mapped to registry paths, see [`git_moves`].
+
+use crate::registry::{self, join_attrs};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::process::Command;
+
+/// Minimum share of descendant paths two subtrees must have in common to be
+/// considered the same subtree under a new name.
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// A prefix rename from one registry to the other.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+/// Differences between an old and a new registry.
+#[derive(Debug, Default, Serialize)]
+pub struct RegistryDiff {
+    /// Removed subtrees matched to an added subtree.
+    pub renames: Vec<Rename>,
+    /// Paths only in the old registry and not covered by a rename.
+    pub removed: Vec<String>,
+    /// Paths only in the new registry and not covered by a rename.
+    pub added: Vec<String>,
+}
+
+/// A subtree present in only one of the registries.
+#[derive(Debug, Clone)]
+struct Subtree<'a> {
+    names: Vec<String>,
+    value: &'a Value,
+}
+
+impl Subtree<'_> {
+    fn path(&self) -> String {
+        join_attrs(&self.names)
+    }
+
+    fn children(&self) -> Vec<Self> {
+        registry::children(self.value)
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| {
+                let mut names = self.names.clone();
+                names.push(key.clone());
+                Subtree { names, value }
+            })
+            .collect()
+    }
+
+    /// Attribute paths below this subtree, relative to it.
+    fn shape(&self) -> HashSet<String> {
+        registry::flatten_paths(self.value, "")
+    }
+
+    fn is_below(&self, path: &[String]) -> bool {
+        self.names.starts_with(path)
+    }
+}
+
+/// Compares two registry trees, inferring which removed subtrees were
+/// renamed to which added ones.
+pub fn diff(old: &Value, new: &Value) -> RegistryDiff {
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    changes(old, new, &[], &mut removed, &mut added);
+    let top_removed = removed.clone();
+    let top_added = added.clone();
+
+    let mut pairs: Vec<(Subtree, Subtree)> = Vec::new();
+    loop {
+        let matched = match_subtrees(&removed, &added);
+        let taken_old: HashSet<usize> = matched.iter().map(|(r, _)| *r).collect();
+        let taken_new: HashSet<usize> = matched.iter().map(|(_, a)| *a).collect();
+        pairs.extend(
+            matched
+                .iter()
+                .map(|(r, a)| (removed[*r].clone(), added[*a].clone())),
+        );
+
+        let rest_old: Vec<Subtree> = removed
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !taken_old.contains(i))
+            .map(|(_, s)| s.clone())
+            .collect();
+        let rest_new: Vec<Subtree> = added
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !taken_new.contains(i))
+            .map(|(_, s)| s.clone())
+            .collect();
+
+        let has_children = |s: &Subtree| !s.children().is_empty();
+        if !rest_old.iter().any(has_children) && !rest_new.iter().any(has_children) {
+            removed = rest_old;
+            added = rest_new;
+            break;
+        }
+        removed = expand(&rest_old);
+        added = expand(&rest_new);
+    }
+
+    let renamed_old: Vec<&[String]> = pairs.iter().map(|(r, _)| r.names.as_slice()).collect();
+    let renamed_new: Vec<&[String]> = pairs.iter().map(|(_, a)| a.names.as_slice()).collect();
+
+    RegistryDiff {
+        renames: pairs
+            .iter()
+            .map(|(r, a)| Rename {
+                from: r.path(),
+                to: a.path(),
+            })
+            .collect(),
+        removed: unmatched(&top_removed, &removed, &renamed_old),
+        added: unmatched(&top_added, &added, &renamed_new),
+    }
+}
+
+/// Combines explicit `--rename` entries with inferred ones. Explicit entries
+/// win: an inferred rename of a path an explicit entry already covers is
+/// dropped.
+pub fn merge(explicit: &HashMap<String, String>, inferred: &[Rename]) -> HashMap<String, String> {
+    let mut merged = explicit.clone();
+    for rename in inferred {
+        let covered = explicit
+            .keys()
+            .any(|from| rename.from == *from || rename.from.starts_with(&format!("{}.", from)));
+        if !covered {
+            merged.insert(rename.from.clone(), rename.to.clone());
+        }
+    }
+    merged
+}
+
+/// Registry renames derived from the files below `dir` that git detects as
+/// moved between `git_ref` and the working tree, including moves with small
+/// edits and moves to untracked files.
+///
+/// Each moved file is mapped to its registry path. When every moved child of
+/// a directory that no longer exists landed under the same new parent, the
+/// directory itself is renamed too, so `home` follows `home.alice` and
+/// `home.bob` to `users`.
+pub fn git_moves(dir: &Path, git_ref: &str) -> Result<Vec<Rename>> {
+    let cwd = std::env::current_dir().context("Failed to read current directory")?;
+    moves_in(&cwd, dir, git_ref)
+}
+
+/// [`git_moves`] for the repository checked out at `work_dir`, with a
+/// relative `dir` resolved against it.
+fn moves_in(work_dir: &Path, dir: &Path, git_ref: &str) -> Result<Vec<Rename>> {
+    let dir = work_dir.join(dir);
+    let commit = git(work_dir, &["rev-parse", git_ref], None)?;
+    let commit = commit.trim();
+    let relative = registry::relative_to(&dir, work_dir)?;
+    let pathspec = match relative.to_string_lossy() {
+        path if path.is_empty() => ".".to_string(),
+        path => path.into_owned(),
+    };
+
+    // A throwaway index lets untracked files take part in rename detection
+    // without touching the user's staging area. Marking them intent-to-add
+    // records their names without copying their contents into the object
+    // store, and each run gets its own index.
+    let scratch = tempfile::tempdir().context("Failed to create scratch index directory")?;
+    let index = scratch.path().join("index");
+    git(work_dir, &["read-tree", commit], Some(&index))?;
+    git(
+        work_dir,
+        &["add", "--intent-to-add", "--all", "--", &pathspec],
+        Some(&index),
+    )?;
+    let output = git(
+        work_dir,
+        &[
+            "diff",
+            "--find-renames",
+            "--name-status",
+            "-z",
+            "--relative",
+            commit,
+            "--",
+            &pathspec,
+        ],
+        Some(&index),
+    )?;
+
+    let mut moves: Vec<(Vec<String>, Vec<String>)> = Vec::new();
+    let mut fields = output.split('\0');
+    while let Some(status) = fields.next() {
+        if !status.starts_with('R') {
+            fields.next();
+            continue;
+        }
+        let (Some(from), Some(to)) = (fields.next(), fields.next()) else {
+            break;
+        };
+        let attrs = |file: &str| {
+            let file = Path::new(file);
+            registry::file_attrs(file.strip_prefix(&relative).unwrap_or(file))
+        };
+        if let (Some(from), Some(to)) = (attrs(from), attrs(to))
+            && from != to
+            && !moves.iter().any(|(f, _)| *f == from)
+        {
+            moves.push((from, to));
+        }
+    }
+
+    let mut frontier = moves.clone();
+    while !frontier.is_empty() {
+        let lifted = moved_parents(&frontier, &moves, &dir);
+        moves.extend(lifted.iter().cloned());
+        frontier = lifted;
+    }
+
+    Ok(moves
+        .iter()
+        .map(|(from, to)| Rename {
+            from: join_attrs(from),
+            to: join_attrs(to),
+        })
+        .collect())
+}
+
+/// Parents of moved entries that moved as a whole: every moved child kept
+/// its name under the same new parent, and the old directory is gone.
+fn moved_parents(
+    moved: &[(Vec<String>, Vec<String>)],
+    known: &[(Vec<String>, Vec<String>)],
+    dir: &Path,
+) -> Vec<(Vec<String>, Vec<String>)> {
+    let mut parents: Vec<(Vec<String>, Option<Vec<String>>)> = Vec::new();
+    for (from, to) in moved {
+        let (Some((from_leaf, from_parent)), Some((to_leaf, to_parent))) =
+            (from.split_last(), to.split_last())
+        else {
+            continue;
+        };
+        if from_leaf != to_leaf || from_parent.is_empty() || to_parent.is_empty() {
+            continue;
+        }
+        match parents.iter_mut().find(|(p, _)| p == from_parent) {
+            Some((_, target)) => {
+                if target.as_deref() != Some(to_parent) {
+                    *target = None;
+                }
+            }
+            None => parents.push((from_parent.to_vec(), Some(to_parent.to_vec()))),
+        }
+    }
+
+    parents
+        .into_iter()
+        .filter_map(|(from, to)| Some((from, to?)))
+        .filter(|(from, to)| from != to)
+        .filter(|(from, _)| !known.iter().any(|(f, _)| f == from))
+        .filter(|(from, _)| {
+            !from
+                .iter()
+                .fold(dir.to_path_buf(), |p, n| p.join(n))
+                .exists()
+        })
+        .collect()
+}
+
+fn git(work_dir: &Path, args: &[&str], index: Option<&Path>) -> Result<String> {
+    let mut command = Command::new("git");
+    command.current_dir(work_dir).args(args);
+    if let Some(index) = index {
+        command.env("GIT_INDEX_FILE", index);
+    }
+    let output = command
+        .output()
+        .with_context(|| format!("Failed to run git {}", args[0]))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("git {} failed: {}", args[0], stderr.trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Collects the largest subtrees present in only one of `old` and `new`.
+fn changes<'a>(
+    old: &'a Value,
+    new: &'a Value,
+    prefix: &[String],
+    removed: &mut Vec<Subtree<'a>>,
+    added: &mut Vec<Subtree<'a>>,
+) {
+    let (Some(old_map), Some(new_map)) = (registry::children(old), registry::children(new)) else {
+        return;
+    };
+    let subtree = |key: &String, value| {
+        let mut names = prefix.to_vec();
+        names.push(key.clone());
+        Subtree { names, value }
+    };
+
+    for (key, old_value) in old_map {
+        match new_map.get(key) {
+            Some(new_value) => {
+                let mut names = prefix.to_vec();
+                names.push(key.clone());
+                changes(old_value, new_value, &names, removed, added);
+            }
+            None => removed.push(subtree(key, old_value)),
+        }
+    }
+    for (key, new_value) in new_map {
+        if !old_map.contains_key(key) {
+            added.push(subtree(key, new_value));
+        }
+    }
+}
+
+/// Pairs removed and added subtrees that are each other's unique best match,
+/// returning their indices.
+fn match_subtrees(removed: &[Subtree], added: &[Subtree]) -> Vec<(usize, usize)> {
+    let shapes_old: Vec<HashSet<String>> = removed.iter().map(Subtree::shape).collect();
+    let shapes_new: Vec<HashSet<String>> = added.iter().map(Subtree::shape).collect();
+    let scores: Vec<Vec<f64>> = removed
+        .iter()
+        .enumerate()
+        .map(|(r, old)| {
+            added
+                .iter()
+                .enumerate()
+                .map(|(a, new)| similarity(old, &shapes_old[r], new, &shapes_new[a]))
+                .collect()
+        })
+        .collect();
+
+    let best_new = |r: usize| unique_best((0..added.len()).map(|a| scores[r][a]));
+    let best_old = |a: usize| unique_best((0..removed.len()).map(|r| scores[r][a]));
+
+    (0..removed.len())
+        .filter_map(|r| {
+            let a = best_new(r)?;
+            (best_old(a) == Some(r)).then_some((r, a))
+        })
+        .collect()
+}
+
+/// How alike two subtrees are, from 0 (unrelated) to 2 (same shape and
+/// name). Leaves only match leaves of the same name.
+fn similarity(
+    old: &Subtree,
+    old_shape: &HashSet<String>,
+    new: &Subtree,
+    new_shape: &HashSet<String>,
+) -> f64 {
+    let same_name = old.names.last() == new.names.last();
+    if old_shape.is_empty() || new_shape.is_empty() {
+        let both_leaves = old_shape.is_empty() && new_shape.is_empty();
+        return if both_leaves && same_name { 1.0 } else { 0.0 };
+    }
+
+    let shared = old_shape.intersection(new_shape).count() as f64;
+    let total = old_shape.union(new_shape).count() as f64;
+    let overlap = shared / total;
+    if overlap < MIN_SIMILARITY {
+        return 0.0;
+    }
+    overlap + if same_name { 1.0 } else { 0.0 }
+}
+
+/// Index of the highest positive score, if no other score ties it.
+fn unique_best(scores: impl Iterator<Item = f64>) -> Option<usize> {
+    let mut best: Option<(usize, f64)> = None;
+    let mut tied = false;
+    for (i, score) in scores.enumerate() {
+        if score <= 0.0 {
+            continue;
+        }
+        match best {
+            Some((_, top)) if score < top => {}
+            Some((_, top)) if score == top => tied = true,
+            _ => {
+                best = Some((i, score));
+                tied = false;
+            }
+        }
+    }
+    best.filter(|_| !tied).map(|(i, _)| i)
+}
+
+/// Replaces every subtree with children by its children.
+fn expand<'a>(subtrees: &[Subtree<'a>]) -> Vec<Subtree<'a>> {
+    subtrees
+        .iter()
+        .flat_map(|s| {
+            let children = s.children();
+            if children.is_empty() {
+                vec![s.clone()]
+            } else {
+                children
+            }
+        })
+        .collect()
+}
+
+/// Paths of unpaired subtrees, reported by their largest removed or added
+/// ancestor when nothing below that ancestor was renamed.
+fn unmatched(top: &[Subtree], rest: &[Subtree], renamed: &[&[String]]) -> Vec<String> {
+    let mut paths = Vec::new();
+    for root in top {
+        let has_rename = renamed.iter().any(|names| names.starts_with(&root.names));
+        if has_rename {
+            paths.extend(
+                rest.iter()
+                    .filter(|s| s.is_below(&root.names))
+                    .map(Subtree::path),
+            );
+        } else {
+            paths.push(root.path());
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn renames(diff: &RegistryDiff) -> Vec<(&str, &str)> {
+        diff.renames
+            .iter()
+            .map(|r| (r.from.as_str(), r.to.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn renamed_directory_matches_by_shape() {
+        let old = json!({ "home": { "alice": "a", "bob": "b" }, "svc": { "db": "d" } });
+        let new = json!({ "users": { "alice": "a", "bob": "b" }, "svc": { "db": "d" } });
+        let diff = diff(&old, &new);
+        assert_eq!(renames(&diff), vec![("home", "users")]);
+        assert!(diff.removed.is_empty() && diff.added.is_empty());
+    }
+
+    #[test]
+    fn moved_leaves_match_by_name() {
+        let old = json!({ "home": { "alice": "a", "bob": "b" }, "users": {}, "admins": {} });
+        let new = json!({ "home": {}, "users": { "alice": "a" }, "admins": { "bob": "b" } });
+        let diff = diff(&old, &new);
+        assert_eq!(
+            renames(&diff),
+            vec![("home.alice", "users.alice"), ("home.bob", "admins.bob")]
+        );
+    }
+
+    #[test]
+    fn split_directory_is_matched_child_by_child() {
+        let old = json!({ "home": { "alice": "a", "bob": "b" } });
+        let new = json!({ "users": { "alice": "a" }, "admins": { "bob": "b" } });
+        let diff = diff(&old, &new);
+        assert_eq!(
+            renames(&diff),
+            vec![("home.alice", "users.alice"), ("home.bob", "admins.bob")]
+        );
+        assert!(diff.removed.is_empty() && diff.added.is_empty());
+    }
+
+    #[test]
+    fn ambiguous_and_unrelated_changes_are_not_renames() {
+        let old = json!({ "a": { "x": "1" }, "b": { "x": "2" }, "old": "o" });
+        let new = json!({ "c": { "x": "1" }, "new": "n" });
+        let diff = diff(&old, &new);
+        assert!(diff.renames.is_empty());
+        assert_eq!(diff.removed, vec!["a", "b", "old"]);
+        assert_eq!(diff.added, vec!["c", "new"]);
+    }
+
+    #[test]
+    fn kind_leaves_are_not_descended_into() {
+        let function = json!({ "__kind": "function" });
+        let old = json!({ "home": { "alice": function }, "pkgs": { "tool": { "__kind": "derivation" } } });
+        let new =
+            json!({ "users": { "alice": function }, "pkgs": { "tool": { "__kind": "error" } } });
+        let diff = diff(&old, &new);
+        assert_eq!(renames(&diff), vec![("home", "users")]);
+        assert!(diff.removed.is_empty() && diff.added.is_empty());
+    }
+
+    #[test]
+    fn moved_children_move_their_parent() {
+        let names = |path: &str| -> Vec<String> { path.split('.').map(str::to_string).collect() };
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("svc")).unwrap();
+        let moved = vec![
+            (names("home.alice"), names("users.alice")),
+            (names("home.bob"), names("users.bob")),
+            (names("svc.db"), names("services.db")),
+            (names("lib.a"), names("x.a")),
+            (names("lib.b"), names("y.b")),
+        ];
+        let parents = moved_parents(&moved, &moved, tmp.path());
+        // `svc` still exists on disk and `lib` was split up
+        assert_eq!(parents, vec![(names("home"), names("users"))]);
+    }
+
+    #[test]
+    fn git_moves_map_moved_files_to_registry_paths() {
+        // Builds its own repository, so it only needs the git binary
+        if Command::new("git").arg("--version").output().is_err() {
+            return;
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let write = |path: &str, text: &str| {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text.repeat(20)).unwrap();
+        };
+        write("registry/home/alice.nix", "{ name = \"alice\"; }\n");
+        write("registry/home/bob.nix", "{ name = \"bob\"; }\n");
+        write("registry/svc/db.nix", "{ port = 5432; }\n");
+        let run = |args: &[&str]| git(root, args, None).unwrap();
+        run(&["init", "-q"]);
+        run(&["add", "--all"]);
+        run(&[
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "-q",
+            "-m",
+            "init",
+        ]);
+
+        // One tracked move and one move to an untracked file with an edit
+        std::fs::create_dir(root.join("registry/users")).unwrap();
+        run(&["mv", "registry/home/alice.nix", "registry/users/alice.nix"]);
+        std::fs::rename(
+            root.join("registry/home/bob.nix"),
+            root.join("registry/users/bob.nix"),
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("registry/users/bob.nix"),
+            "{ name = \"bob\"; }\n".repeat(20) + "# moved\n",
+        )
+        .unwrap();
+        std::fs::remove_dir(root.join("registry/home")).unwrap();
+
+        let expected = vec![
+            ("home.alice", "users.alice"),
+            ("home.bob", "users.bob"),
+            ("home", "users"),
+        ];
+        for dir in ["registry", "./registry", "registry/"] {
+            let moves = moves_in(root, Path::new(dir), "HEAD").unwrap();
+            let mut moves: Vec<_> = moves
+                .iter()
+                .map(|r| (r.from.as_str(), r.to.as_str()))
+                .collect();
+            moves.sort();
+            let mut expected = expected.clone();
+            expected.sort();
+            assert_eq!(moves, expected, "registry directory {dir}");
+        }
+        let moves = moves_in(root, &root.join("registry"), "HEAD").unwrap();
+        assert_eq!(moves.len(), 3);
+
+        // The user's index is untouched: only the `git mv` is staged
+        let staged = run(&["diff", "--cached", "--no-renames", "--name-only"]);
+        assert_eq!(
+            staged.lines().collect::<Vec<_>>(),
+            vec!["registry/home/alice.nix", "registry/users/alice.nix"]
+        );
+    }
+
+    #[test]
+    fn explicit_renames_take_precedence() {
+        let explicit: HashMap<String, String> = [("home".to_string(), "people".to_string())].into();
+        let inferred = vec![
+            Rename {
+                from: "home.alice".to_string(),
+                to: "users.alice".to_string(),
+            },
+            Rename {
+                from: "svc".to_string(),
+                to: "services".to_string(),
+            },
+        ];
+        let merged = merge(&explicit, &inferred);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged["home"], "people");
+        assert_eq!(merged["svc"], "services");
+    }
+}
@@ -3,11 +3,35 @@
 //! Performs position-aware replacement of broken registry paths using the byte
 //! offsets captured during AST extraction. Changes are sorted by position and
 //! applied in reverse order to preserve offset validity.
+//!
+//! `inherit (registry.a) b c;` statements are rewritten as a unit: the source
+//! expression is updated when every name moved together, and the statement is
+//! split when the names now live under different parents.
+//!
+//! References inside `with registry.a;` and selects on aliases such as
+//! `users = registry.users;` are rewritten relative to that prefix. When the
+//! prefix itself moved, its own change rewrites the header or binding.
+//! String-list lookups and `registry.a ? b` checks follow the same rule for
+//! their target expression.
 
-use crate::scanner::RegistryRef;
+use crate::registry;
+use crate::scanner::{self, RefKind, RegistryRef};
 use anyhow::Result;
+use rnix::SyntaxKind;
+use rowan::ast::AstNode;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 
+/// How broken path imports such as `../registry/home/alice` are rewritten.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathStyle {
+    /// Keep a relative path literal, pointing at the new location.
+    #[default]
+    Relative,
+    /// Replace the literal with the equivalent `registry.X.Y` select.
+    Registry,
+}
+
 /// A single text replacement with position information.
 #[derive(Debug, Clone)]
 struct Replacement {
@@ -21,37 +45,172 @@ struct Replacement {
 /// For each `(old_ref, new_path)` pair, replaces the exact span of the original
 /// select expression with the corrected path. Changes are applied in reverse
 /// offset order to maintain position validity.
+///
+/// Returns the indices into `changes` that could not be rewritten; their
+/// sites are left as they were.
 pub fn apply_changes(
     file: &Path,
-    registry_name: &str,
     changes: &[(RegistryRef, String)],
-) -> Result<()> {
+    path_style: PathStyle,
+) -> Result<Vec<usize>> {
     let content = std::fs::read_to_string(file)?;
-    let new_content = apply_replacements(&content, registry_name, changes);
+    let (new_content, unrewritten) = rewrite(&content, changes, path_style);
     std::fs::write(file, new_content)?;
-    Ok(())
+    Ok(unrewritten)
 }
 
 /// Applies replacements to source content, returning the modified string.
 ///
 /// Sorts replacements by start offset (descending) and applies each in turn.
-/// This ensures earlier replacements don't invalidate later offsets.
+/// This ensures earlier replacements don't invalidate later offsets. Each
+/// reference is rewritten against the registry identifier it was written with.
 pub fn apply_replacements(
     content: &str,
-    registry_name: &str,
     changes: &[(RegistryRef, String)],
+    path_style: PathStyle,
 ) -> String {
-    let mut replacements: Vec<Replacement> = changes
+    rewrite(content, changes, path_style).0
+}
+
+/// [`apply_replacements`], also returning the indices into `changes` whose
+/// sites could not be rewritten and were left alone.
+pub fn rewrite(
+    content: &str,
+    changes: &[(RegistryRef, String)],
+    path_style: PathStyle,
+) -> (String, Vec<usize>) {
+    let mut replacements = Vec::new();
+    let mut unrewritten = Vec::new();
+    let mut inherits: BTreeMap<usize, Vec<(usize, &RegistryRef, &str)>> = BTreeMap::new();
+    // Targets of lookups and `?` checks rebuilt from a new parent, which take
+    // the place of the target's own change
+    let mut rebuilt_targets: Vec<Replacement> = Vec::new();
+
+    // A `with registry.a;` header or alias binding is itself a reference, so
+    // a change starting at its offset means the prefix moved
+    let moved: HashMap<usize, &str> = changes
         .iter()
-        .map(|(reference, new_path)| Replacement {
-            start: reference.start_offset,
-            end: reference.end_offset,
-            new_text: format!("{}.{}", registry_name, new_path),
-        })
+        .map(|(r, new_path)| (r.start_offset, new_path.as_str()))
         .collect();
 
+    for (index, (reference, new_path)) in changes.iter().enumerate() {
+        match reference.kind {
+            RefKind::Select | RefKind::Mention => replacements.push(Replacement {
+                start: reference.start_offset,
+                end: reference.end_offset,
+                new_text: format!("{}.{}", reference.base, new_path),
+            }),
+            RefKind::Inherit {
+                statement_start, ..
+            } => inherits
+                .entry(statement_start)
+                .or_default()
+                .push((index, reference, new_path)),
+            RefKind::ViaWith {
+                header_start,
+                ref prefix,
+                ..
+            } => {
+                let prefix = moved.get(&header_start).copied().unwrap_or(prefix);
+                replacements.push(Replacement {
+                    start: reference.start_offset,
+                    end: reference.end_offset,
+                    new_text: relative_use_site(&reference.base, None, prefix, new_path),
+                });
+            }
+            RefKind::Alias {
+                binding_start,
+                ref name,
+                ref prefix,
+                ..
+            } => {
+                let prefix = moved.get(&binding_start).copied().unwrap_or(prefix);
+                replacements.push(Replacement {
+                    start: reference.start_offset,
+                    end: reference.end_offset,
+                    new_text: relative_use_site(&reference.base, Some(name), prefix, new_path),
+                });
+            }
+            RefKind::Lookup {
+                ref elements,
+                list,
+                target_start,
+                target_end,
+                ref prefix,
+            } => {
+                let lookup = Lookup {
+                    elements,
+                    list,
+                    target: (target_start, target_end),
+                    prefix: moved.get(&target_start).copied().unwrap_or(prefix),
+                };
+                match rewrite_lookup(&reference.base, &lookup, new_path) {
+                    Some((elements, target)) => {
+                        replacements.extend(elements);
+                        rebuilt_targets.extend(target);
+                    }
+                    None => unrewritten.push(index),
+                }
+            }
+            RefKind::HasAttr {
+                target_start,
+                target_end,
+                ref prefix,
+                attrpath_start,
+                attrpath_end,
+            } => {
+                // The check starts where its target does, so the target is
+                // matched by its full span rather than through `moved`
+                let target_moved = changes
+                    .iter()
+                    .find(|(r, _)| is_target(r, target_start, target_end))
+                    .map(|(_, new_path)| new_path.as_str());
+                match rewrite_has_attr(
+                    &reference.base,
+                    (target_start, target_end),
+                    (attrpath_start, attrpath_end),
+                    target_moved.unwrap_or(prefix),
+                    new_path,
+                ) {
+                    Some((attrpath, target)) => {
+                        replacements.push(attrpath);
+                        rebuilt_targets.extend(target);
+                    }
+                    None => unrewritten.push(index),
+                }
+            }
+            RefKind::Path {
+                ref prefix,
+                ref suffix,
+                ..
+            } => replacements.push(Replacement {
+                start: reference.start_offset,
+                end: reference.end_offset,
+                new_text: match path_style {
+                    PathStyle::Relative => path_import(&reference.file, prefix, suffix, new_path),
+                    PathStyle::Registry => format!("{}.{}", reference.base, new_path),
+                },
+            }),
+        }
+    }
+
+    for group in inherits.values() {
+        let refs: Vec<(&RegistryRef, &str)> = group.iter().map(|&(_, r, p)| (r, p)).collect();
+        match rewrite_inherit(content, &refs) {
+            Some(replacement) => replacements.push(replacement),
+            None => unrewritten.extend(group.iter().map(|&(index, ..)| index)),
+        }
+    }
+
+    replacements.retain(|r| {
+        !rebuilt_targets
+            .iter()
+            .any(|t| (t.start, t.end) == (r.start, r.end))
+    });
+    replacements.extend(rebuilt_targets);
+
     // Sort by start offset descending so we can apply from end to start
-    replacements.sort_by(|a, b| b.start.cmp(&a.start));
+    replacements.sort_by_key(|r| std::cmp::Reverse(r.start));
 
     let mut result = content.to_string();
     for rep in replacements {
@@ -60,22 +219,314 @@ pub fn apply_replacements(
         }
     }
 
-    result
+    (result, unrewritten)
+}
+
+/// Rewrites one `inherit (registry.a) b c;` statement.
+///
+/// Names without a change keep their current parent. If all names end up
+/// under one parent, only the source expression is replaced. Otherwise the
+/// statement is split into one `inherit` per parent, in order of first
+/// appearance, and names whose leaf changed become `name = registry.x.y;`.
+fn rewrite_inherit(content: &str, changes: &[(&RegistryRef, &str)]) -> Option<Replacement> {
+    let (reference, _) = changes.first()?;
+    let registry_name = reference.base.as_str();
+    let RefKind::Inherit {
+        source_start,
+        source_end,
+        statement_start,
+        statement_end,
+    } = reference.kind
+    else {
+        return None;
+    };
+
+    let names = inherit_names(content.get(statement_start..statement_end)?)?;
+    let mut old_parent = registry::split_attrs(&reference.path);
+    old_parent.pop();
+
+    let new_paths: HashMap<String, Vec<String>> = changes
+        .iter()
+        .filter_map(|(r, new_path)| {
+            let name = registry::split_attrs(&r.path).pop()?;
+            Some((name, registry::split_attrs(new_path)))
+        })
+        .collect();
+
+    let mut groups: Vec<(Vec<String>, Vec<&str>)> = Vec::new();
+    let mut bindings = Vec::new();
+    for (written, name) in &names {
+        let target = new_paths.get(name).cloned().unwrap_or_else(|| {
+            let mut path = old_parent.clone();
+            path.push(name.clone());
+            path
+        });
+        let (leaf, parent) = target.split_last()?;
+
+        if leaf != name {
+            bindings.push(format!(
+                "{} = {}.{};",
+                written,
+                registry_name,
+                registry::join_attrs(&target)
+            ));
+        } else if let Some((_, group)) = groups.iter_mut().find(|(p, _)| p == parent) {
+            group.push(written);
+        } else {
+            groups.push((parent.to_vec(), vec![written]));
+        }
+    }
+
+    if bindings.is_empty() && groups.len() == 1 {
+        return Some(Replacement {
+            start: source_start,
+            end: source_end,
+            new_text: registry_select(registry_name, &groups[0].0),
+        });
+    }
+
+    let statements: Vec<String> = groups
+        .iter()
+        .map(|(parent, names)| {
+            format!(
+                "inherit ({}) {};",
+                registry_select(registry_name, parent),
+                names.join(" ")
+            )
+        })
+        .chain(bindings)
+        .collect();
+
+    Some(Replacement {
+        start: statement_start,
+        end: statement_end,
+        new_text: statements.join(&statement_separator(content, statement_start)),
+    })
+}
+
+/// Text for a reference written relative to a registry prefix: inside
+/// `with registry.<prefix>;` (`base` is `None`) or as a select on an alias
+/// `base` bound to `registry.<prefix>`.
+///
+/// Paths still under the (possibly moved) prefix stay relative, leaving the
+/// `with` header or alias binding alone. Anything else is written in full.
+fn relative_use_site(
+    registry_name: &str,
+    base: Option<&str>,
+    prefix: &str,
+    new_path: &str,
+) -> String {
+    let prefix = registry::split_attrs(prefix);
+    let target = registry::split_attrs(new_path);
+    let full = || format!("{}.{}", registry_name, new_path);
+
+    match (target.strip_prefix(prefix.as_slice()), base) {
+        (Some([]), Some(base)) => base.to_string(),
+        (Some(rest), Some(base)) => format!("{}.{}", base, registry::join_attrs(rest)),
+        // Under `with`, the path must start with a bare identifier
+        (Some(rest), None) if rest.first().is_some_and(|n| registry::format_attr(n) == *n) => {
+            registry::join_attrs(rest)
+        }
+        _ => full(),
+    }
+}
+
+/// Spans of an `attrByPath` / `getAttrFromPath` / `getAttr` call.
+struct Lookup<'a> {
+    elements: &'a [(usize, usize)],
+    list: Option<(usize, usize)>,
+    target: (usize, usize),
+    /// Registry path the target selects, after any move of the target itself.
+    prefix: &'a str,
+}
+
+/// Rewrites a string-list lookup so it resolves to `new_path`, returning the
+/// replacements for its string elements and, when it changes, its target.
+///
+/// When the new path is still under the (possibly moved) target, only the
+/// string elements change: in place if their count is unchanged, otherwise
+/// the list is rebuilt. Otherwise the lookup starts from the registry root
+/// (or, for `getAttr`, the new parent), which takes the place of any rewrite
+/// of the target as a reference of its own. `None` if neither is possible.
+fn rewrite_lookup(
+    registry_name: &str,
+    lookup: &Lookup,
+    new_path: &str,
+) -> Option<(Vec<Replacement>, Option<Replacement>)> {
+    let prefix = registry::split_attrs(lookup.prefix);
+    let target = registry::split_attrs(new_path);
+
+    if let Some(rest) = target.strip_prefix(prefix.as_slice())
+        && let Some(replacements) = lookup_elements(lookup, rest)
+    {
+        return Some((replacements, None));
+    }
+
+    let (parent, names) = match lookup.list {
+        Some(_) => (&[][..], target.as_slice()),
+        None => {
+            let (leaf, parent) = target.split_last()?;
+            (parent, std::slice::from_ref(leaf))
+        }
+    };
+    let replacements = lookup_elements(lookup, names)?;
+    let target = Replacement {
+        start: lookup.target.0,
+        end: lookup.target.1,
+        new_text: registry_select(registry_name, parent),
+    };
+    Some((replacements, Some(target)))
+}
+
+/// True when `reference` is exactly the expression spanning `start..end`.
+fn is_target(reference: &RegistryRef, start: usize, end: usize) -> bool {
+    reference.start_offset == start && reference.end_offset == end
+}
+
+/// Rewrites a `target ? a.b` check so it tests `new_path`, returning the
+/// replacement for its attrpath and, when it changes, its target.
+///
+/// Only the attrpath changes while the new path is still under the (possibly
+/// moved) target. Otherwise the whole check is rebased onto the registry
+/// root, which takes the place of any rewrite of the target as a reference of
+/// its own. `None` if the new path is the registry itself.
+fn rewrite_has_attr(
+    registry_name: &str,
+    target: (usize, usize),
+    attrpath: (usize, usize),
+    prefix: &str,
+    new_path: &str,
+) -> Option<(Replacement, Option<Replacement>)> {
+    let prefix = registry::split_attrs(prefix);
+    let names = registry::split_attrs(new_path);
+
+    let attrpath = |names: &[String]| Replacement {
+        start: attrpath.0,
+        end: attrpath.1,
+        new_text: registry::join_attrs(names),
+    };
+
+    if let Some(rest) = names.strip_prefix(prefix.as_slice())
+        && !rest.is_empty()
+    {
+        return Some((attrpath(rest), None));
+    }
+    if names.is_empty() {
+        return None;
+    }
+    let target = Replacement {
+        start: target.0,
+        end: target.1,
+        new_text: registry_name.to_string(),
+    };
+    Some((attrpath(&names), Some(target)))
+}
+
+/// Replacements that make the lookup's string elements spell `names`.
+fn lookup_elements(lookup: &Lookup, names: &[String]) -> Option<Vec<Replacement>> {
+    if names.len() == lookup.elements.len() {
+        return Some(
+            lookup
+                .elements
+                .iter()
+                .zip(names)
+                .map(|(&(start, end), name)| Replacement {
+                    start,
+                    end,
+                    new_text: registry::nix_string(name),
+                })
+                .collect(),
+        );
+    }
+
+    let (start, end) = lookup.list.filter(|_| !names.is_empty())?;
+    let items: Vec<String> = names.iter().map(|n| registry::nix_string(n)).collect();
+    Some(vec![Replacement {
+        start,
+        end,
+        new_text: format!("[ {} ]", items.join(" ")),
+    }])
+}
+
+/// New literal for a path import into the registry directory, keeping the
+/// original `prefix`.
+///
+/// The original suffix (`.nix`, `/default.nix` or none) is kept unless only
+/// another form of the new path exists on disk, relative to `file`.
+fn path_import(file: &Path, prefix: &str, suffix: &str, new_path: &str) -> String {
+    let dir = registry::split_attrs(new_path).join("/");
+    let literal = |suffix: &str| format!("{}/{}{}", prefix, dir, suffix);
+    let base = file.parent().unwrap_or(Path::new(""));
+
+    [suffix, "", ".nix", "/default.nix"]
+        .into_iter()
+        .map(literal)
+        .find(|l| base.join(l).exists())
+        .unwrap_or_else(|| literal(suffix))
+}
+
+/// Returns `(as written, decoded)` for each name of an `inherit` statement,
+/// or `None` if any name is interpolated.
+fn inherit_names(statement: &str) -> Option<Vec<(String, String)>> {
+    let root = rnix::Root::parse(&format!("{{ {} }}", statement)).tree();
+    let inherit = root
+        .syntax()
+        .descendants()
+        .find(|n| n.kind() == SyntaxKind::NODE_INHERIT)?;
+
+    inherit
+        .children()
+        .filter(|c| c.kind() != SyntaxKind::NODE_INHERIT_FROM)
+        .map(|attr| Some((attr.text().to_string(), scanner::static_attr_name(&attr)?)))
+        .collect()
+}
+
+/// `registry` or `registry.a.b` for the attribute path `parent`.
+fn registry_select(registry_name: &str, parent: &[String]) -> String {
+    if parent.is_empty() {
+        registry_name.to_string()
+    } else {
+        format!("{}.{}", registry_name, registry::join_attrs(parent))
+    }
+}
+
+/// Separator between split statements: a newline at the statement's
+/// indentation when it starts its own line, otherwise a space.
+fn statement_separator(content: &str, statement_start: usize) -> String {
+    let line_start = content[..statement_start].rfind('\n').map_or(0, |i| i + 1);
+    let indent = &content[line_start..statement_start];
+    if indent.chars().all(char::is_whitespace) {
+        format!("\n{}", indent)
+    } else {
+        " ".to_string()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::scanner::{PathSegment, RefKind};
+    use crate::span::Span;
     use std::path::PathBuf;
 
     fn make_ref(path: &str, start: usize, end: usize) -> RegistryRef {
         RegistryRef {
+            base: "registry".to_string(),
             path: path.to_string(),
+            segments: path
+                .split('.')
+                .map(|s| PathSegment::Static(s.to_string()))
+                .collect(),
             file: PathBuf::from("test.nix"),
             line: 1,
             column: 1,
+            end_line: 1,
+            end_column: 1,
+            span: Span::default(),
             start_offset: start,
             end_offset: end,
+            guarded: false,
+            kind: RefKind::Select,
         }
     }
 
@@ -84,7 +535,7 @@ mod tests {
         let content = "{ imports = [ registry.home.alice ]; }";
         //                          ^14           ^32
         let changes = vec![(make_ref("home.alice", 14, 33), "users.alice".to_string())];
-        let result = apply_replacements(content, "registry", &changes);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
         assert_eq!(result, "{ imports = [ registry.users.alice ]; }");
     }
 
@@ -96,7 +547,7 @@ mod tests {
             (make_ref("foo.x", 6, 20), "baz.x".to_string()),
             (make_ref("bar.y", 26, 40), "qux.y".to_string()),
         ];
-        let result = apply_replacements(content, "registry", &changes);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
         assert_eq!(result, "{ a = registry.baz.x; b = registry.qux.y; }");
     }
 
@@ -105,7 +556,7 @@ mod tests {
         let content = "# comment\n{ x = registry.old.path; }\n# end";
         //                              ^16             ^32
         let changes = vec![(make_ref("old.path", 16, 33), "new.path".to_string())];
-        let result = apply_replacements(content, "registry", &changes);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
         assert_eq!(result, "# comment\n{ x = registry.new.path; }\n# end");
     }
 
@@ -117,13 +568,30 @@ mod tests {
         //             Comment starts at 0, actual ref at 40
         //                                                    ^40             ^56
         let changes = vec![(make_ref("old.path", 40, 57), "new.path".to_string())];
-        let result = apply_replacements(content, "registry", &changes);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
         assert_eq!(
             result,
             "# registry.old.path is deprecated\n{ x = registry.new.path; }"
         );
     }
 
+    #[test]
+    fn comment_mentions_rewritten_when_included() {
+        let content = "# registry.old.path is deprecated\n{ x = registry.old.path; }";
+        let scan = scanner::scan_source(content, Path::new("test.nix"), &["registry"]);
+        let changes: Vec<_> = scan
+            .refs
+            .into_iter()
+            .chain(scan.mentions)
+            .map(|r| (r, "new.path".to_string()))
+            .collect();
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "# registry.new.path is deprecated\n{ x = registry.new.path; }"
+        );
+    }
+
     #[test]
     fn handles_different_length_replacements() {
         let content = "{ x = registry.a; y = registry.b.c.d; }";
@@ -132,7 +600,7 @@ mod tests {
             (make_ref("a", 6, 16), "very.long.path".to_string()),
             (make_ref("b.c.d", 22, 36), "x".to_string()),
         ];
-        let result = apply_replacements(content, "registry", &changes);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
         assert_eq!(result, "{ x = registry.very.long.path; y = registry.x; }");
     }
 
@@ -140,16 +608,413 @@ mod tests {
     fn empty_changes_returns_original() {
         let content = "{ x = registry.foo; }";
         let changes: Vec<(RegistryRef, String)> = vec![];
-        let result = apply_replacements(content, "registry", &changes);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
         assert_eq!(result, content);
     }
 
+    #[test]
+    fn preserves_or_default() {
+        let content = "{ x = registry.old.path or null; }";
+        let refs = crate::scanner::scan_source(content, Path::new("test.nix"), &["registry"]).refs;
+        let changes = vec![(refs[0].clone(), "new.path".to_string())];
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "{ x = registry.new.path or null; }");
+    }
+
+    fn path_import_changes(
+        root: &Path,
+        content: &str,
+        renames: &[(&str, &str)],
+    ) -> Vec<(RegistryRef, String)> {
+        std::fs::create_dir_all(root.join("hosts")).unwrap();
+        let file = root.join("hosts/server.nix");
+        let registry_dir = root.join("registry");
+        scanner::scan_source_with_dir(content, &file, &["registry"], Some(&registry_dir))
+            .refs
+            .into_iter()
+            .filter_map(|r| {
+                let (_, new) = renames.iter().find(|(old, _)| *old == r.path)?;
+                Some((r, new.to_string()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn path_import_rewritten_relative() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("registry/users")).unwrap();
+        std::fs::write(tmp.path().join("registry/users/alice.nix"), "{}").unwrap();
+
+        let content = "{ imports = [ ../registry/home/alice.nix ]; }";
+        let changes = path_import_changes(tmp.path(), content, &[("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "{ imports = [ ../registry/users/alice.nix ]; }");
+    }
+
+    #[test]
+    fn path_import_follows_form_on_disk() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("registry/users/alice")).unwrap();
+        std::fs::write(tmp.path().join("registry/users/alice/default.nix"), "{}").unwrap();
+
+        // `alice.nix` became `alice/default.nix`; the directory form is used
+        let content = "{ imports = [ ../registry/home/alice.nix ]; }";
+        let changes = path_import_changes(tmp.path(), content, &[("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "{ imports = [ ../registry/users/alice ]; }");
+    }
+
+    #[test]
+    fn path_import_rewritten_as_registry_select() {
+        let tmp = tempfile::tempdir().unwrap();
+        let content = "{ imports = [ ../registry/home/alice ]; }";
+        let changes = path_import_changes(tmp.path(), content, &[("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Registry);
+        assert_eq!(result, "{ imports = [ registry.users.alice ]; }");
+    }
+
+    #[test]
+    fn writes_quoted_segments() {
+        let content = "{ x = registry.old.path; }";
+        let changes = vec![(make_ref("old.path", 6, 23), r#"web."my.app""#.to_string())];
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, r#"{ x = registry.web."my.app"; }"#);
+    }
+
+    #[test]
+    fn preserves_registry_name_of_each_reference() {
+        let content = "{ registry, reg, ... }: [ registry.home.alice reg.home.bob ]";
+        let changes: Vec<_> =
+            scanner::scan_source(content, Path::new("test.nix"), &["registry", "reg"])
+                .refs
+                .into_iter()
+                .map(|r| {
+                    let new_path = r.path.replace("home", "users");
+                    (r, new_path)
+                })
+                .collect();
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "{ registry, reg, ... }: [ registry.users.alice reg.users.bob ]"
+        );
+    }
+
+    fn scanned_changes(content: &str, renames: &[(&str, &str)]) -> Vec<(RegistryRef, String)> {
+        scanner::scan_source(content, Path::new("test.nix"), &["registry"])
+            .refs
+            .into_iter()
+            .filter_map(|r| {
+                let (_, new) = renames.iter().find(|(old, _)| *old == r.path)?;
+                Some((r, new.to_string()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lookup_elements_rewritten_in_place() {
+        let content = r#"{ x = lib.attrByPath [ "home" "alice" ] null registry; }"#;
+        let changes = scanned_changes(content, &[("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            r#"{ x = lib.attrByPath [ "users" "alice" ] null registry; }"#
+        );
+    }
+
+    #[test]
+    fn lookup_list_rebuilt_when_depth_changes() {
+        let content = r#"{ x = lib.getAttrFromPath [ "home" "alice" ] registry; }"#;
+        let changes = scanned_changes(content, &[("home.alice", "users.admins.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            r#"{ x = lib.getAttrFromPath [ "users" "admins" "alice" ] registry; }"#
+        );
+    }
+
+    #[test]
+    fn get_attr_leaf_rename() {
+        let content = r#"{ x = builtins.getAttr "alice" registry.home; }"#;
+        let changes = scanned_changes(content, &[("home.alice", "home.alicia")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            r#"{ x = builtins.getAttr "alicia" registry.home; }"#
+        );
+    }
+
+    #[test]
+    fn get_attr_target_rewritten_when_parent_changes() {
+        let content = r#"{ x = builtins.getAttr "alice" registry.home; }"#;
+        let changes = scanned_changes(content, &[("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            r#"{ x = builtins.getAttr "alice" registry.users; }"#
+        );
+    }
+
+    #[test]
+    fn lookup_follows_moved_target() {
+        let content = r#"{ x = lib.getAttrFromPath [ "alice" ] registry.home; }"#;
+        let changes = scanned_changes(content, &[("home", "users"), ("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            r#"{ x = lib.getAttrFromPath [ "alice" ] registry.users; }"#
+        );
+    }
+
+    #[test]
+    fn lookup_rebuilt_when_moved_target_loses_the_path() {
+        let content = r#"{ x = builtins.getAttr "carol" registry.home; y = lib.getAttrFromPath [ "carol" ] registry.home; }"#;
+        let changes = scanned_changes(
+            content,
+            &[("home", "users"), ("home.carol", "admins.carol")],
+        );
+        let (result, unrewritten) = rewrite(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            r#"{ x = builtins.getAttr "carol" registry.admins; y = lib.getAttrFromPath [ "admins" "carol" ] registry; }"#
+        );
+        assert!(unrewritten.is_empty());
+    }
+
+    #[test]
+    fn dotted_base_kept_when_rewriting() {
+        let content =
+            "{ self, ... }: { a = self.registry.home.alice; inherit (self.registry.home) bob; }";
+        let changes: Vec<_> =
+            scanner::scan_source(content, Path::new("flake.nix"), &["self.registry"])
+                .refs
+                .into_iter()
+                .map(|r| {
+                    let new = r.path.replace("home", "users");
+                    (r, new)
+                })
+                .collect();
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "{ self, ... }: { a = self.registry.users.alice; inherit (self.registry.users) bob; }"
+        );
+    }
+
+    #[test]
+    fn has_attr_attrpath_rewritten() {
+        let content = "{ x = registry.home ? alice; y = registry ? home.bob; }";
+        let changes = scanned_changes(
+            content,
+            &[("home.alice", "home.alicia"), ("home.bob", "users.bob")],
+        );
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "{ x = registry.home ? alicia; y = registry ? users.bob; }"
+        );
+    }
+
+    #[test]
+    fn has_attr_rebased_when_target_changes() {
+        let content = "{ x = registry.home ? alice; }";
+        let changes = scanned_changes(content, &[("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "{ x = registry ? users.alice; }");
+    }
+
+    #[test]
+    fn has_attr_follows_moved_target() {
+        let content = "{ x = registry.home ? alice; }";
+        let changes = scanned_changes(content, &[("home", "users"), ("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "{ x = registry.users ? alice; }");
+    }
+
+    #[test]
+    fn has_attr_rebased_when_moved_target_loses_the_path() {
+        let content = "{ x = registry.home ? carol; y = registry.home.alice; }";
+        let changes = scanned_changes(
+            content,
+            &[
+                ("home", "users"),
+                ("home.carol", "admins.carol"),
+                ("home.alice", "users.alice"),
+            ],
+        );
+        let (result, unrewritten) = rewrite(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "{ x = registry ? admins.carol; y = registry.users.alice; }"
+        );
+        assert!(unrewritten.is_empty());
+    }
+
+    #[test]
+    fn or_default_kept_when_select_rewritten() {
+        let content = "{ x = registry.home.alice or null; }";
+        let changes = scanned_changes(content, &[("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "{ x = registry.users.alice or null; }");
+    }
+
+    #[test]
+    fn inherit_source_updated_when_all_names_move() {
+        let content = "{ inherit (registry.home) alice bob; }";
+        let changes = scanned_changes(
+            content,
+            &[("home.alice", "users.alice"), ("home.bob", "users.bob")],
+        );
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "{ inherit (registry.users) alice bob; }");
+    }
+
+    #[test]
+    fn inherit_split_when_parents_diverge() {
+        let content = "{\n  inherit (registry.home) alice bob;\n}";
+        let changes = scanned_changes(content, &[("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "{\n  inherit (registry.users) alice;\n  inherit (registry.home) bob;\n}"
+        );
+    }
+
+    #[test]
+    fn inherit_split_on_one_line() {
+        let content = "{ inherit (registry.home) alice bob; }";
+        let changes = scanned_changes(content, &[("home.bob", "users.bob")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "{ inherit (registry.home) alice; inherit (registry.users) bob; }"
+        );
+    }
+
+    #[test]
+    fn inherit_leaf_rename_becomes_binding() {
+        let content = "{ inherit (registry.home) alice; }";
+        let changes = scanned_changes(content, &[("home.alice", "users.alicia")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "{ alice = registry.users.alicia; }");
+    }
+
+    #[test]
+    fn inherit_from_bare_registry_gains_parent() {
+        let content = "{ inherit (registry) alice; }";
+        let changes = scanned_changes(content, &[("alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "{ inherit (registry.users) alice; }");
+    }
+
+    #[test]
+    fn with_reference_rewritten_relative_to_header() {
+        let content = "{ imports = with registry; [ home.alice profiles.desktop ]; }";
+        let changes = scanned_changes(content, &[("home.alice", "users.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "{ imports = with registry; [ users.alice profiles.desktop ]; }"
+        );
+    }
+
+    #[test]
+    fn with_bare_ident_rewritten_within_prefix() {
+        let content = "with registry.profiles; [ desktop ]";
+        let changes = scanned_changes(content, &[("profiles.desktop", "profiles.laptop")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "with registry.profiles; [ laptop ]");
+    }
+
+    #[test]
+    fn with_header_rewritten_when_prefix_moved() {
+        let content = "with registry.profiles; [ desktop ]";
+        let changes = scanned_changes(
+            content,
+            &[("profiles", "roles"), ("profiles.desktop", "roles.desktop")],
+        );
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "with registry.roles; [ desktop ]");
+    }
+
+    #[test]
+    fn with_reference_outside_prefix_written_in_full() {
+        let content = "with registry.profiles; [ desktop server ]";
+        let changes = scanned_changes(content, &[("profiles.desktop", "roles.desktop")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "with registry.profiles; [ registry.roles.desktop server ]"
+        );
+    }
+
+    #[test]
+    fn nested_with_on_other_values_left_alone() {
+        for content in [
+            "pkgs: with pkgs; with registry; [ hello git ]",
+            "pkgs: with registry; with pkgs; [ hello git ]",
+        ] {
+            let changes = scanned_changes(content, &[("hello", "tools.hello")]);
+            assert!(changes.is_empty());
+            let result = apply_replacements(content, &changes, PathStyle::Relative);
+            assert_eq!(result, content);
+        }
+    }
+
+    #[test]
+    fn alias_use_site_rewritten_when_suffix_changed() {
+        let content = "let u = registry.users; in u.alice";
+        let changes = scanned_changes(content, &[("users.alice", "users.alicia")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "let u = registry.users; in u.alicia");
+    }
+
+    #[test]
+    fn alias_binding_rewritten_when_prefix_changed() {
+        let content = "let u = registry.home; in [ u.alice u.bob ]";
+        let changes = scanned_changes(
+            content,
+            &[
+                ("home", "users"),
+                ("home.alice", "users.alice"),
+                ("home.bob", "users.bob"),
+            ],
+        );
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "let u = registry.users; in [ u.alice u.bob ]");
+    }
+
+    #[test]
+    fn alias_use_site_outside_prefix_written_in_full() {
+        let content = "let u = registry.users; in [ u.alice u.bob ]";
+        let changes = scanned_changes(content, &[("users.alice", "admins.alice")]);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(
+            result,
+            "let u = registry.users; in [ registry.admins.alice u.bob ]"
+        );
+    }
+
+    #[test]
+    fn inherit_alias_follows_moved_inherit() {
+        let content = "let inherit (registry.home) alice; in alice.shell";
+        let changes = scanned_changes(
+            content,
+            &[
+                ("home.alice", "users.alice"),
+                ("home.alice.shell", "users.alice.shell"),
+            ],
+        );
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
+        assert_eq!(result, "let inherit (registry.users) alice; in alice.shell");
+    }
+
     #[test]
     fn handles_multiline_content() {
         let content = "{\n  imports = [\n    registry.home.alice\n  ];\n}";
         //                                  ^20               ^39
         let changes = vec![(make_ref("home.alice", 20, 39), "users.alice".to_string())];
-        let result = apply_replacements(content, "registry", &changes);
+        let result = apply_replacements(content, &changes, PathStyle::Relative);
         assert_eq!(
             result,
             "{\n  imports = [\n    registry.users.alice\n  ];\n}"
@@ -1,74 +1,371 @@
 //! Nix file scanner.
 //!
-//! Recursively walks directories to collect `.nix` files, skipping entries
-//! whose names start with `.` or `_`, plus any paths matching user-provided
-//! glob patterns. Uses rnix to parse each file and extract `registry.X.Y.Z`
-//! attribute selection expressions from the AST.
-
+//! Recursively walks directories to collect `.nix` files (or other configured
+//! extensions and include globs), skipping entries whose names start with `.`
+//! or `_`, plus any paths matching user-provided glob patterns. Uses rnix to
+//! parse each file and extract `registry.X.Y.Z` attribute selection
+//! expressions from the AST.
+//!
+//! Quoted attrpath segments (`registry."web-app"`) are decoded to their names.
+//! References with a `${...}` segment are dynamic: only their static prefix is
+//! checked and they are never rewritten.
+//!
+//! `inherit (registry.a) b c;` yields one reference per inherited name, and
+//! free identifiers inside `with registry.a;` resolve to registry paths.
+//! Simple aliases (`users = registry.users;`, `inherit (registry) users;`) are
+//! followed so that `users.alice` is reported as `users.alice`. Lookups
+//! spelled with string literals, such as `lib.attrByPath [ "home" "alice" ]
+//! null registry` or `builtins.getAttr "alice" registry.home`, are reported
+//! by the path they resolve to. When the registry directory is known,
+//! relative path literals into it such as `../../registry/home/alice` are
+//! reported as `home.alice`.
+//!
+//! Has-attr checks (`registry ? home.alice`), selects with an `or` default
+//! and `attrByPath` lookups are marked as guarded: a broken path there never
+//! fails evaluation, it just takes the fallback.
+//!
+//! Registry names may be dotted: with `self.registry`, only selects starting
+//! with `self.registry` match, and the remaining segments form the path.
+//!
+//! Extraction is scope-aware: a `registry` bound by an inner lambda, `let` or
+//! `rec` attrset shadows the module argument, so selects on it are reported
+//! separately as skipped rather than checked against the registry.
+
+use crate::cache::{self, CacheEntry, ScanCache};
+use crate::infer;
+use crate::mentions;
+use crate::registry;
+use crate::span::{LineIndex, Span};
 use anyhow::{Context, Result};
 use glob::Pattern;
+use ignore::{DirEntry, WalkBuilder};
+use rayon::prelude::*;
 use rnix::SyntaxKind;
-use rowan::{WalkEvent, ast::AstNode};
-use serde::Serialize;
-use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use rnix::ast::InterpolPart;
+use rowan::ast::AstNode;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
 
 /// A reference to a registry path found in source.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RegistryRef {
-    /// Dotted path after `registry.`, e.g. `"home.alice"`.
+    /// Registry identifier the reference was written against, e.g. `registry`.
+    pub base: String,
+    /// Dotted path after `registry.`, e.g. `"home.alice"`. For dynamic
+    /// references this is only the static prefix before the first `${...}`.
     pub path: String,
+    /// Every attrpath component in source order, including dynamic ones.
+    pub segments: Vec<PathSegment>,
     /// Source file containing the reference.
     pub file: PathBuf,
     /// Line number, 1-indexed.
     pub line: usize,
-    /// Column number, 1-indexed.
+    /// Column number in Unicode scalar values, 1-indexed.
     pub column: usize,
+    /// Line number of the end of the reference, 1-indexed.
+    pub end_line: usize,
+    /// Column just past the end of the reference, 1-indexed.
+    pub end_column: usize,
+    /// Start and end with byte and UTF-16 columns as well, for editors and
+    /// SARIF consumers.
+    pub span: Span,
     /// Byte offset of the start of the entire select expression.
     pub start_offset: usize,
     /// Byte offset of the end of the entire select expression.
     pub end_offset: usize,
+    /// True when the expression tolerates a missing path, as with an `or`
+    /// default, a `?` check or `attrByPath`, so breakage does not fail
+    /// evaluation.
+    pub guarded: bool,
+    /// Syntactic form of the reference, which decides how it is rewritten.
+    #[serde(flatten)]
+    pub kind: RefKind,
+}
+
+/// The syntactic form a registry reference takes in source.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RefKind {
+    /// `registry.a.b`. The reference span covers the base and attrpath.
+    Select,
+    /// One name of `inherit (registry.a) b c;`. The reference span covers the
+    /// inherited name.
+    Inherit {
+        /// Byte offset of the start of the parenthesised source expression.
+        source_start: usize,
+        /// Byte offset of the end of the parenthesised source expression.
+        source_end: usize,
+        /// Byte offset of the start of the whole `inherit` statement.
+        statement_start: usize,
+        /// Byte offset of the end of the whole `inherit` statement.
+        statement_end: usize,
+    },
+    /// A free identifier or select inside `with registry.a;`, such as `b.c`.
+    /// The reference span covers the identifier or select as written.
+    ViaWith {
+        /// Byte offset of the start of the `with` header expression.
+        header_start: usize,
+        /// Byte offset of the end of the `with` header expression.
+        header_end: usize,
+        /// Dotted registry path the header selects, empty for `with registry;`.
+        prefix: String,
+    },
+    /// A select on a local alias, such as `users.alice` after
+    /// `users = registry.users;`. The reference span covers the select.
+    Alias {
+        /// Byte offset of the start of the reference the alias was bound from.
+        binding_start: usize,
+        /// Byte offset of the end of the reference the alias was bound from.
+        binding_end: usize,
+        /// The alias identifier.
+        name: String,
+        /// Dotted registry path the alias stands for.
+        prefix: String,
+    },
+    /// A lookup through string literals, such as
+    /// `lib.attrByPath [ "home" "alice" ] null registry` or
+    /// `builtins.getAttr "alice" registry.home`. The reference span covers the
+    /// whole call.
+    Lookup {
+        /// Spans of the string literal path elements, in order.
+        elements: Vec<(usize, usize)>,
+        /// Span of the `[ ... ]` list, absent for `getAttr`.
+        list: Option<(usize, usize)>,
+        /// Byte offset of the start of the registry expression looked into.
+        target_start: usize,
+        /// Byte offset of the end of the registry expression looked into.
+        target_end: usize,
+        /// Dotted registry path the target selects, empty for `registry`.
+        prefix: String,
+    },
+    /// A has-attr check such as `registry ? users.alice` or
+    /// `registry.users ? alice`. The reference span covers the whole check.
+    HasAttr {
+        /// Byte offset of the start of the registry expression checked.
+        target_start: usize,
+        /// Byte offset of the end of the registry expression checked.
+        target_end: usize,
+        /// Dotted registry path the target selects, empty for `registry`.
+        prefix: String,
+        /// Byte offset of the start of the attrpath after `?`.
+        attrpath_start: usize,
+        /// Byte offset of the end of the attrpath after `?`.
+        attrpath_end: usize,
+    },
+    /// A path written in a comment or a Markdown `nix` block rather than in
+    /// code. See [`crate::mentions`].
+    Mention,
+    /// A relative path literal into the registry directory, such as
+    /// `../../registry/home/alice`. It is valid while `target` exists on disk.
+    Path {
+        /// The literal resolved against the containing file's directory.
+        target: PathBuf,
+        /// Literal text up to the registry directory, e.g. `../../registry`.
+        prefix: String,
+        /// Literal text after the last registry path component: empty,
+        /// `.nix` or `/default.nix`.
+        suffix: String,
+    },
+}
+
+/// Project-level ignore file with gitignore semantics, read from the flake
+/// root and any directory below it.
+pub const IGNORE_FILE: &str = ".imp-refactor-ignore";
+
+/// Which files [`collect_nix_files`] picks up and how it walks to them.
+#[derive(Debug, Clone)]
+pub struct CollectOptions {
+    /// Glob patterns for entries to skip, matched against both file names and
+    /// full paths.
+    pub exclude: Vec<String>,
+    /// Glob patterns for extra files to scan regardless of extension, matched
+    /// against file names, full paths and paths relative to the scanned root.
+    pub include: Vec<String>,
+    /// File name suffixes to scan, without the leading dot, e.g. `nix.in`.
+    pub extensions: Vec<String>,
+    /// Skip entries starting with `.` or `_`.
+    pub use_default_excludes: bool,
+    /// Honor `.gitignore`, `.git/info/exclude` and [`IGNORE_FILE`].
+    pub use_ignore_files: bool,
+    /// Descend into symlinked directories and scan symlinked files.
+    pub follow_symlinks: bool,
+}
+
+impl Default for CollectOptions {
+    fn default() -> Self {
+        Self {
+            exclude: Vec::new(),
+            include: Vec::new(),
+            extensions: vec!["nix".to_string()],
+            use_default_excludes: true,
+            use_ignore_files: true,
+            follow_symlinks: false,
+        }
+    }
+}
+
+/// Why a file was selected for scanning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileRule {
+    /// The file name ends with `.<extension>`.
+    Extension(String),
+    /// The file matched an `include` pattern.
+    Include(String),
+}
+
+impl std::fmt::Display for FileRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileRule::Extension(ext) => write!(f, "extension .{}", ext),
+            FileRule::Include(pattern) => write!(f, "include {}", pattern),
+        }
+    }
+}
+
+/// Files found by [`collect_nix_files`].
+#[derive(Debug, Clone, Default)]
+pub struct NixFiles {
+    /// Selected files in walk order, each listed once, with the rule that
+    /// selected them.
+    pub files: Vec<(PathBuf, FileRule)>,
+    /// Directories that could not be read and symlink loops. The walk goes
+    /// on without them.
+    pub errors: Vec<ReadError>,
+}
+
+impl NixFiles {
+    /// The selected file paths, in walk order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.files.iter().map(|(path, _)| path.clone()).collect()
+    }
 }
 
-/// Collects all `.nix` files under `paths`, applying exclusion rules.
+/// Collects the files under `paths` selected by `options`.
 ///
-/// When `use_default_excludes` is true, entries starting with `.` or `_` are skipped.
-/// Additional patterns from `exclude_patterns` are matched against both filenames
-/// and full paths.
-pub fn collect_nix_files(
-    paths: &[PathBuf],
-    exclude_patterns: &[String],
-    use_default_excludes: bool,
-) -> Result<Vec<PathBuf>> {
-    let patterns: Vec<Pattern> = exclude_patterns
+/// A file is selected when its name ends with one of the configured
+/// extensions or it matches an include pattern, unless an exclude rule
+/// applies: with `use_default_excludes`, entries starting with `.` or `_` are
+/// skipped, and with `use_ignore_files`, `.gitignore`, `.git/info/exclude`
+/// and [`IGNORE_FILE`] rules are honored. A pattern that does not parse is an
+/// error.
+///
+/// Symlinks are skipped unless `follow_symlinks` is set. Files reached more
+/// than once, through overlapping `paths` or symlinks, are listed under the
+/// first path they were found at. A missing entry in `paths` is an error;
+/// anything unreadable below it is reported in [`NixFiles::errors`].
+pub fn collect_nix_files(paths: &[PathBuf], options: &CollectOptions) -> Result<NixFiles> {
+    let compile = |kind: &str, patterns: &[String]| {
+        patterns
+            .iter()
+            .map(|p| Pattern::new(p).with_context(|| format!("Invalid {} pattern '{}'", kind, p)))
+            .collect::<Result<Vec<_>>>()
+    };
+    let excludes = compile("exclude", &options.exclude)?;
+    let includes = compile("include", &options.include)?;
+    let extensions: Vec<&str> = options
+        .extensions
         .iter()
-        .filter_map(|p| Pattern::new(p).ok())
+        .map(|ext| ext.trim_start_matches('.'))
         .collect();
 
-    let mut files = Vec::new();
+    let mut found = NixFiles::default();
+    let mut seen = HashSet::new();
 
     for path in paths {
-        for entry in WalkDir::new(path)
-            .into_iter()
-            .filter_entry(|e| !should_exclude(e, &patterns, use_default_excludes))
-        {
-            let entry = entry?;
-            if entry.file_type().is_file()
-                && entry.path().extension().is_some_and(|ext| ext == "nix")
-            {
-                files.push(entry.into_path());
+        if !path.exists() {
+            anyhow::bail!("Path not found: {}", path.display());
+        }
+
+        let excludes = excludes.clone();
+        let use_default_excludes = options.use_default_excludes;
+        let mut walker = WalkBuilder::new(path);
+        walker
+            .standard_filters(false)
+            .git_ignore(options.use_ignore_files)
+            .git_exclude(options.use_ignore_files)
+            .parents(options.use_ignore_files)
+            .require_git(false)
+            .follow_links(options.follow_symlinks)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .filter_entry(move |e| !should_exclude(e, &excludes, use_default_excludes));
+        if options.use_ignore_files {
+            walker.add_custom_ignore_filename(IGNORE_FILE);
+        }
+
+        for entry in walker.build() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    found.errors.push(walk_error(path, err));
+                    continue;
+                }
+            };
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                continue;
+            }
+            let Some(rule) = select_file(entry.path(), path, &extensions, &includes) else {
+                continue;
+            };
+            let canonical = entry
+                .path()
+                .canonicalize()
+                .unwrap_or_else(|_| entry.path().to_path_buf());
+            if seen.insert(canonical) {
+                found.files.push((entry.into_path(), rule));
             }
         }
     }
 
-    Ok(files)
+    Ok(found)
+}
+
+/// Returns the first rule selecting `file`, found while walking `root`.
+/// Extensions are checked before include patterns.
+fn select_file(
+    file: &Path,
+    root: &Path,
+    extensions: &[&str],
+    includes: &[Pattern],
+) -> Option<FileRule> {
+    let name = file.file_name()?.to_string_lossy();
+    if let Some(ext) = extensions.iter().find(|ext| {
+        name.strip_suffix(**ext)
+            .and_then(|stem| stem.strip_suffix('.'))
+            .is_some_and(|stem| !stem.is_empty())
+    }) {
+        return Some(FileRule::Extension(ext.to_string()));
+    }
+
+    let full = file.to_string_lossy();
+    let relative = file.strip_prefix(root).unwrap_or(file).to_string_lossy();
+    includes
+        .iter()
+        .find(|p| p.matches(&name) || p.matches(&full) || p.matches(&relative))
+        .map(|p| FileRule::Include(p.as_str().to_string()))
+}
+
+/// Flattens a walk error into the path it concerns and a short reason.
+fn walk_error(path: &Path, err: ignore::Error) -> ReadError {
+    match err {
+        ignore::Error::WithDepth { err, .. } => walk_error(path, *err),
+        ignore::Error::WithPath { path, err } => walk_error(&path, *err),
+        ignore::Error::Loop { ancestor, child } => ReadError {
+            file: child,
+            message: format!("symlink loop back to {}", ancestor.display()),
+        },
+        ignore::Error::Io(err) => ReadError {
+            file: path.to_path_buf(),
+            message: err.kind().to_string(),
+        },
+        other => ReadError {
+            file: path.to_path_buf(),
+            message: other.to_string(),
+        },
+    }
 }
 
-fn should_exclude(
-    entry: &walkdir::DirEntry,
-    patterns: &[Pattern],
-    use_default_excludes: bool,
-) -> bool {
+fn should_exclude(entry: &DirEntry, patterns: &[Pattern], use_default_excludes: bool) -> bool {
     let name = entry.file_name().to_str().unwrap_or("");
 
     // Never exclude the root entry (e.g., "." when scanning current directory)
@@ -88,224 +385,1654 @@ fn should_exclude(
         .any(|p| p.matches(&path_str) || p.matches(name))
 }
 
-/// Parses a Nix file and extracts all `registry_name.X.Y...` attribute selections.
-///
-/// Walks the rnix AST looking for `NODE_SELECT` nodes. For each, reconstructs
-/// the full dotted path by traversing nested selects, then checks whether the
-/// root identifier matches `registry_name`.
-pub fn extract_registry_refs(file: &Path, registry_name: &str) -> Result<Vec<RegistryRef>> {
-    let source = std::fs::read_to_string(file)
-        .with_context(|| format!("Failed to read {}", file.display()))?;
+/// One attrpath component of a registry reference.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathSegment {
+    /// An identifier or string literal, decoded to the attribute name.
+    Static(String),
+    /// A `${...}` interpolation or interpolated string, as written.
+    Dynamic(String),
+}
 
-    let parse = rnix::Root::parse(&source);
-    if !parse.errors().is_empty() {
-        eprintln!(
-            "warn: Parse errors in {}: {:?}",
-            file.display(),
-            parse.errors()
-        );
+impl RegistryRef {
+    /// Returns true if any segment is only known at evaluation time.
+    ///
+    /// Dynamic references are checked by their static prefix and are never
+    /// rewritten automatically.
+    pub fn is_dynamic(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|s| matches!(s, PathSegment::Dynamic(_)))
     }
 
-    let root = parse.tree();
-    let mut refs = Vec::new();
-
-    for event in root.syntax().preorder() {
-        if let WalkEvent::Enter(node) = event {
-            if node.kind() == SyntaxKind::NODE_SELECT {
-                if let Some(path) = extract_dotted_path(&node, registry_name) {
-                    let range = node.text_range();
-                    let start: usize = range.start().into();
-                    let end: usize = range.end().into();
-                    let (line, column) = offset_to_line_col(&source, start);
-                    refs.push(RegistryRef {
-                        path,
-                        file: file.to_path_buf(),
-                        line,
-                        column,
-                        start_offset: start,
-                        end_offset: end,
-                    });
-                }
-            }
-        }
+    /// The full path as written, e.g. `users.${name}.shell`.
+    pub fn display_path(&self) -> String {
+        self.segments
+            .iter()
+            .map(|s| match s {
+                PathSegment::Static(name) => registry::format_attr(name),
+                PathSegment::Dynamic(text) => text.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(".")
     }
+}
 
-    Ok(refs)
+/// A `registry_name.X.Y` select whose base identifier is bound locally.
+///
+/// These look like registry references but resolve to a lambda parameter,
+/// `let` binding or `rec` attribute that shadows the module argument. They are
+/// kept so verbose and JSON output can show what was deliberately ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SkippedRef {
+    #[serde(flatten)]
+    pub reference: RegistryRef,
+    /// Why the reference was not checked, e.g. `"local binding"`.
+    pub reason: String,
 }
 
-/// Reconstructs a dotted attribute path from a `NODE_SELECT` node.
+/// A syntax error in a scanned file.
 ///
-/// rnix parses `registry.home.alice` as:
-/// ```text
-/// NODE_SELECT
-///   NODE_IDENT (registry)
-///   NODE_ATTRPATH
-///     NODE_IDENT (home)
-///     NODE_IDENT (alice)
-/// ```
+/// rnix recovers from errors, so references in the rest of the file are still
+/// extracted; the error is reported so it can be fixed or fail a `--strict` run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParseError {
+    pub file: PathBuf,
+    /// Line number, 1-indexed.
+    pub line: usize,
+    /// Column number, 1-indexed.
+    pub column: usize,
+    pub message: String,
+}
+
+/// A file or directory that could not be read.
 ///
-/// Returns `Some(path)` if the base identifier equals `registry_name`,
-/// where `path` is the attrpath joined by `.`.
-fn extract_dotted_path(
-    node: &rowan::SyntaxNode<rnix::NixLanguage>,
-    registry_name: &str,
-) -> Option<String> {
-    let mut children = node.children();
-
-    let base = children.next()?;
-    if base.kind() != SyntaxKind::NODE_IDENT {
-        return None;
+/// Like a [`ParseError`], it is reported without stopping the scan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadError {
+    pub file: PathBuf,
+    /// Why reading failed, e.g. `"permission denied"`.
+    pub message: String,
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.file.display(), self.message)
     }
-    if base.text().to_string() != registry_name {
-        return None;
+}
+
+impl std::error::Error for ReadError {}
+
+/// Everything extracted from a single file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileScan {
+    /// References that resolve to the registry module argument.
+    pub refs: Vec<RegistryRef>,
+    /// Selects on a shadowing binding of the registry name.
+    pub skipped: Vec<SkippedRef>,
+    /// Syntax errors rnix recovered from while parsing the file.
+    pub parse_errors: Vec<ParseError>,
+    /// Set when the file could not be read or is not UTF-8; nothing else is
+    /// extracted from it then.
+    pub read_errors: Vec<ReadError>,
+    /// Formal arguments of the file's top-level function; see
+    /// [`infer::module_args`].
+    pub module_args: Vec<String>,
+    /// Registry paths mentioned in comments, or in `nix` code blocks for a
+    /// Markdown file. Only checked on request.
+    ///
+    /// Comment mentions are extracted whether or not they are checked, so a
+    /// cached scan serves runs with and without `--include-comments` alike.
+    /// Matching comment text is cheap next to parsing the file, which has
+    /// happened by then anyway.
+    pub mentions: Vec<RegistryRef>,
+}
+
+/// Registry identifiers to look for, optionally scoped to files by glob.
+///
+/// Every file is scanned for the global names plus any scoped name whose glob
+/// matches the file path, so `registry` can be used everywhere while
+/// home-manager modules under `home/**` receive the same registry as `reg`.
+/// With a registry directory set, relative path imports into it are reported
+/// too.
+#[derive(Debug, Clone, Default)]
+pub struct RegistryNames {
+    global: Vec<String>,
+    scoped: Vec<(Pattern, String)>,
+    dir: Option<PathBuf>,
+    roots: Vec<PathBuf>,
+    infer_args: bool,
+}
+
+impl RegistryNames {
+    /// Creates a set with a single name used in every file.
+    pub fn new(name: &str) -> Self {
+        Self {
+            global: vec![name.to_string()],
+            scoped: Vec::new(),
+            dir: None,
+            roots: Vec::new(),
+            infer_args: false,
+        }
     }
 
-    let attrpath = children.next()?;
-    if attrpath.kind() != SyntaxKind::NODE_ATTRPATH {
-        return None;
+    /// Also checks, in each file, the top-level module arguments whose name
+    /// looks like a registry (see [`infer::looks_like_registry`]). They are
+    /// only checked in the file that declares them.
+    pub fn set_infer_args(&mut self, infer_args: bool) {
+        self.infer_args = infer_args;
     }
 
-    let parts: Vec<String> = attrpath
-        .children()
-        .filter(|c| c.kind() == SyntaxKind::NODE_IDENT)
-        .map(|c| c.text().to_string())
-        .collect();
+    /// Whether registry-like module arguments are checked.
+    pub fn infers_args(&self) -> bool {
+        self.infer_args
+    }
 
-    if parts.is_empty() {
-        None
-    } else {
-        Some(parts.join("."))
+    /// Sets the paths files were collected from, which scoped globs are
+    /// matched relative to.
+    pub fn set_roots(&mut self, roots: &[PathBuf]) {
+        self.roots = roots.to_vec();
+    }
+
+    /// Sets the directory the registry is built from, enabling detection of
+    /// path imports such as `../registry/home/alice`.
+    pub fn set_dir(&mut self, dir: &Path) {
+        self.dir = Some(dir.to_path_buf());
+    }
+
+    /// The registry directory, if set.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// Adds a name used in every file.
+    pub fn add(&mut self, name: &str) {
+        if !self.global.iter().any(|n| n == name) {
+            self.global.push(name.to_string());
+        }
+    }
+
+    /// Returns true if `name` is used in any file, globally or scoped.
+    pub fn contains(&self, name: &str) -> bool {
+        self.global.iter().any(|n| n == name) || self.scoped.iter().any(|(_, n)| n == name)
+    }
+
+    /// Adds a name used only in files whose path matches `glob`.
+    ///
+    /// Globs are matched against the collected path with any leading `./`
+    /// removed and against the path relative to the root it was collected
+    /// from (see [`set_roots`](Self::set_roots)), e.g. `home/**` or
+    /// `**/home-manager/*.nix`.
+    pub fn add_scoped(&mut self, glob: &str, name: &str) -> Result<()> {
+        let pattern =
+            Pattern::new(glob).with_context(|| format!("Invalid registry name glob '{}'", glob))?;
+        self.scoped.push((pattern, name.to_string()));
+        Ok(())
+    }
+
+    /// Returns the names that apply to `file`.
+    pub fn for_file(&self, file: &Path) -> Vec<&str> {
+        let mut relative = vec![file.strip_prefix(".").unwrap_or(file)];
+        relative.extend(
+            self.roots
+                .iter()
+                .filter_map(|root| file.strip_prefix(root).ok()),
+        );
+        let mut names: Vec<&str> = self.global.iter().map(String::as_str).collect();
+        for (pattern, name) in &self.scoped {
+            if relative.iter().any(|path| pattern.matches_path(path))
+                && !names.contains(&name.as_str())
+            {
+                names.push(name);
+            }
+        }
+        names
     }
 }
 
-fn offset_to_line_col(source: &str, offset: usize) -> (usize, usize) {
-    let mut line = 1;
-    let mut col = 1;
-    for (i, c) in source.char_indices() {
-        if i >= offset {
-            break;
+/// Parses a Nix file and extracts all `registry.X.Y...` attribute selections,
+/// where `registry` is any of `registry_names`. Names may be dotted, such as
+/// `self.registry`.
+///
+/// Only references whose base identifier resolves to the registry are returned;
+/// see [`scan_file`] for the shadowed selects as well.
+pub fn extract_registry_refs(file: &Path, registry_names: &[&str]) -> Result<Vec<RegistryRef>> {
+    Ok(scan_file(file, registry_names)?.refs)
+}
+
+/// Bump when extraction rules change so cached scans are discarded.
+pub const EXTRACTION_VERSION: u32 = 14;
+
+/// Scans `files` on a pool of `jobs` worker threads, or one per core when
+/// `jobs` is `None`.
+///
+/// Results are returned in the order of `files` regardless of which worker
+/// finishes first, so output stays deterministic. With a `cache`, files whose
+/// contents and registry identifiers are unchanged are not parsed again, and
+/// fresh scans are added to it. A file that cannot be read yields a scan with
+/// only [`FileScan::read_errors`] set, and a Markdown file one with only
+/// [`FileScan::mentions`].
+pub fn scan_files(
+    files: &[PathBuf],
+    registry_names: &RegistryNames,
+    jobs: Option<usize>,
+    mut cache: Option<&mut ScanCache>,
+) -> Result<Vec<FileScan>> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs.unwrap_or(0))
+        .build()
+        .context("Failed to start scan worker pool")?;
+
+    let cached = cache.as_deref();
+    let results: Vec<(FileScan, Option<CacheEntry>)> = pool.install(|| {
+        files
+            .par_iter()
+            .map(|file| {
+                let names = registry_names.for_file(file);
+                let dir = registry_names.dir();
+                let infer_args = registry_names.infers_args();
+                let source = match read_source(file) {
+                    Ok(source) => source,
+                    Err(err) => {
+                        let scan = FileScan {
+                            read_errors: vec![err],
+                            ..FileScan::default()
+                        };
+                        return (scan, None);
+                    }
+                };
+                let Some(cache) = cached else {
+                    return (scan_contents(&source, file, &names, dir, infer_args), None);
+                };
+
+                let hash = cache::content_hash(source.as_bytes());
+                if let Some(scan) = cache.get(file, &hash, &names, dir, infer_args) {
+                    return (scan, None);
+                }
+                let scan = scan_contents(&source, file, &names, dir, infer_args);
+                let entry = CacheEntry {
+                    hash,
+                    names: names.iter().map(|n| n.to_string()).collect(),
+                    registry_dir: dir.map(Path::to_path_buf),
+                    infer_args,
+                    scan: scan.clone(),
+                };
+                (scan, Some(entry))
+            })
+            .collect()
+    });
+
+    let mut scans = Vec::with_capacity(results.len());
+    for (file, (scan, entry)) in files.iter().zip(results) {
+        if let (Some(cache), Some(entry)) = (cache.as_deref_mut(), entry) {
+            cache.insert(file.clone(), entry);
         }
-        if c == '\n' {
-            line += 1;
-            col = 1;
-        } else {
-            col += 1;
+        scans.push(scan);
+    }
+    Ok(scans)
+}
+
+/// Scans a Markdown file for mentions only, and anything else as Nix.
+fn scan_contents(
+    source: &str,
+    file: &Path,
+    names: &[&str],
+    dir: Option<&Path>,
+    infer_args: bool,
+) -> FileScan {
+    if mentions::is_markdown(file) {
+        FileScan {
+            mentions: mentions::markdown_mentions(source, file, names),
+            ..FileScan::default()
         }
+    } else {
+        scan_nix(source, file, names, dir, infer_args)
     }
-    (line, col)
 }
 
-/// Extracts registry paths from Nix source code.
+/// Reads and scans a Nix file, returning both resolved and skipped references.
+pub fn scan_file(file: &Path, registry_names: &[&str]) -> Result<FileScan> {
+    let source = read_source(file)?;
+    Ok(scan_source(&source, file, registry_names))
+}
+
+/// Reads `file` as UTF-8 text.
+fn read_source(file: &Path) -> Result<String, ReadError> {
+    let error = |message: String| ReadError {
+        file: file.to_path_buf(),
+        message,
+    };
+    let bytes = std::fs::read(file).map_err(|err| error(err.kind().to_string()))?;
+    String::from_utf8(bytes).map_err(|err| {
+        error(format!(
+            "not valid UTF-8 (invalid byte at offset {})",
+            err.utf8_error().valid_up_to()
+        ))
+    })
+}
+
+/// Scans Nix source text attributed to `file`.
 ///
-/// Parses the source string and returns all dotted paths following `registry_name.`.
-/// Unlike `extract_registry_refs`, this operates on strings directly for testing.
-#[cfg(test)]
-pub fn extract_paths_from_source(source: &str, registry_name: &str) -> Vec<String> {
+/// Walks the rnix AST with a lexical scope model. A `NODE_SELECT` whose base
+/// identifier is one of `registry_names` is reported when that identifier is
+/// bound by the file's top-level lambda or is free; selects on a lambda
+/// parameter, `let` binding or `rec` attribute of the same name are reported
+/// as skipped. For a dotted name such as `self.registry`, any lambda may bind
+/// `self`; only a `let` or `rec` binding shadows it. Syntax errors are
+/// collected in [`FileScan::parse_errors`].
+pub fn scan_source(source: &str, file: &Path, registry_names: &[&str]) -> FileScan {
+    scan_source_with_dir(source, file, registry_names, None)
+}
+
+/// [`scan_source`], also reporting relative path literals that resolve into
+/// `registry_dir` as [`RefKind::Path`] references.
+pub fn scan_source_with_dir(
+    source: &str,
+    file: &Path,
+    registry_names: &[&str],
+    registry_dir: Option<&Path>,
+) -> FileScan {
+    scan_nix(source, file, registry_names, registry_dir, false)
+}
+
+/// [`scan_source_with_dir`], with `infer_args` also checking the file's
+/// registry-like module arguments.
+fn scan_nix(
+    source: &str,
+    file: &Path,
+    registry_names: &[&str],
+    registry_dir: Option<&Path>,
+    infer_args: bool,
+) -> FileScan {
+    let lines = LineIndex::new(source);
     let parse = rnix::Root::parse(source);
+    let parse_errors = parse
+        .errors()
+        .iter()
+        .map(|err| describe_parse_error(source, &lines, file, err))
+        .collect();
     let root = parse.tree();
-    let mut paths = Vec::new();
+    let module_args = infer::module_args(root.syntax());
+    let mut names = registry_names.to_vec();
+    if infer_args {
+        for arg in &module_args {
+            if infer::looks_like_registry(arg) && !names.contains(&arg.as_str()) {
+                names.push(arg);
+            }
+        }
+    }
+    let registry_names = names.as_slice();
+    let mentions = mentions::comment_mentions(root.syntax(), &lines, file, registry_names);
+    let mut walker = ScopeWalker {
+        lines,
+        file,
+        registry_names,
+        registry_dir: registry_dir.map(normalize_path),
+        scopes: Vec::new(),
+        withs: Vec::new(),
+        scan: FileScan {
+            parse_errors,
+            module_args: module_args.clone(),
+            mentions,
+            ..FileScan::default()
+        },
+    };
+    walker.walk(root.syntax(), true);
+    walker.scan
+}
+
+type SyntaxNode = rowan::SyntaxNode<rnix::NixLanguage>;
+
+/// What a name refers to at a given point in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Binding {
+    /// The registry passed in as a top-level module argument.
+    Registry,
+    /// A `let` or `rec` name bound to a registry path, e.g.
+    /// `users = registry.users;` or `inherit (registry) users;`.
+    Alias {
+        /// Registry identifier at the root of the alias chain.
+        base: String,
+        prefix: Vec<PathSegment>,
+        /// Span of the reference the alias was bound from.
+        anchor_start: usize,
+        anchor_end: usize,
+    },
+    /// Any other lexical binding: lambda parameters, `let`, `rec` attributes.
+    Local,
+}
+
+/// A `let` or `rec` binding that may turn out to alias a registry path.
+enum AliasCandidate {
+    /// `name = <expr>;`
+    Value(String, SyntaxNode),
+    /// `inherit (<source>) name;`, with the inherited attribute node.
+    InheritFrom(String, SyntaxNode, SyntaxNode),
+}
 
-    for event in root.syntax().preorder() {
-        if let WalkEvent::Enter(node) = event {
-            if node.kind() == SyntaxKind::NODE_SELECT {
-                if let Some(path) = extract_dotted_path(&node, registry_name) {
-                    paths.push(path);
+/// Recursive AST walker tracking lexical scopes.
+///
+/// Each lambda, `let ... in` and `rec { }` pushes a scope with the names it
+/// binds. Free identifiers are assumed to come from the module system (e.g.
+/// `specialArgs`), so a free `registry` counts as the registry.
+struct ScopeWalker<'a> {
+    lines: LineIndex<'a>,
+    file: &'a Path,
+    registry_names: &'a [&'a str],
+    /// Absolute, normalized registry directory for path imports.
+    registry_dir: Option<PathBuf>,
+    scopes: Vec<HashMap<String, Binding>>,
+    /// Enclosing `with` expressions, innermost last. `None` for a `with` on
+    /// something other than the registry.
+    withs: Vec<Option<WithScope>>,
+    scan: FileScan,
+}
+
+/// A `with registry.a;` whose attributes are in scope.
+struct WithScope {
+    base: String,
+    prefix: Vec<PathSegment>,
+    header_start: usize,
+    header_end: usize,
+}
+
+/// Identifiers Nix provides globally, which a `with` never supplies in practice.
+const NIX_GLOBALS: &[&str] = &[
+    "abort",
+    "baseNameOf",
+    "builtins",
+    "derivation",
+    "dirOf",
+    "false",
+    "fetchGit",
+    "fetchTarball",
+    "fetchTree",
+    "fetchurl",
+    "import",
+    "isNull",
+    "map",
+    "null",
+    "placeholder",
+    "removeAttrs",
+    "scopedImport",
+    "throw",
+    "toString",
+    "true",
+];
+
+impl ScopeWalker<'_> {
+    /// Walks `node`. `module_level` is true while still in the chain of
+    /// parentheses and lambdas at the root of the file.
+    fn walk(&mut self, node: &SyntaxNode, module_level: bool) {
+        match node.kind() {
+            SyntaxKind::NODE_LAMBDA => self.walk_lambda(node, module_level),
+            SyntaxKind::NODE_LET_IN | SyntaxKind::NODE_LEGACY_LET => {
+                self.walk_recursive_bindings(node)
+            }
+            SyntaxKind::NODE_ATTR_SET if is_rec(node) => self.walk_recursive_bindings(node),
+            SyntaxKind::NODE_SELECT => {
+                self.visit_select(node);
+                self.walk_children(node, false);
+            }
+            SyntaxKind::NODE_INHERIT => {
+                if !self.visit_inherit(node) {
+                    self.walk_children(node, false);
                 }
             }
+            SyntaxKind::NODE_WITH => self.walk_with(node),
+            SyntaxKind::NODE_PATH => self.visit_path(node),
+            SyntaxKind::NODE_APPLY => {
+                self.visit_lookup(node);
+                self.walk_children(node, false);
+            }
+            SyntaxKind::NODE_HAS_ATTR => {
+                self.visit_has_attr(node);
+                self.walk_children(node, false);
+            }
+            SyntaxKind::NODE_IDENT if is_expression_ident(node) => self.visit_ident(node),
+            SyntaxKind::NODE_ROOT | SyntaxKind::NODE_PAREN => {
+                self.walk_children(node, module_level)
+            }
+            _ => self.walk_children(node, false),
         }
     }
 
-    paths
-}
+    fn walk_children(&mut self, node: &SyntaxNode, module_level: bool) {
+        for child in node.children() {
+            self.walk(&child, module_level);
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn walk_lambda(&mut self, node: &SyntaxNode, module_level: bool) {
+        let mut children = node.children();
+        let Some(param) = children.next() else {
+            return;
+        };
 
-    #[test]
-    fn extracts_single_registry_reference() {
-        let source = r#"
-            { registry, ... }:
-            { imports = [ registry.home.alice ]; }
-        "#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["home.alice"]);
+        let binding = if module_level {
+            Binding::Registry
+        } else {
+            Binding::Local
+        };
+        let scope = param_names(&param)
+            .into_iter()
+            .map(|name| {
+                // `self` in `outputs = { self, ... }:` is where `self.registry`
+                // comes from, whichever lambda binds it
+                let b = if self.is_registry_name(&name) {
+                    binding.clone()
+                } else if self.is_registry_head(&name) {
+                    Binding::Registry
+                } else {
+                    Binding::Local
+                };
+                (name, b)
+            })
+            .collect();
+
+        self.scopes.push(scope);
+        // `{ users ? registry.users }:` aliases its default, which can refer
+        // to the other formals
+        let defaults = pattern_defaults(&param);
+        for _ in 0..defaults.len() {
+            let mut changed = false;
+            for (name, value) in &defaults {
+                let Some(binding) = self.alias_target(value) else {
+                    continue;
+                };
+                let scope = self.scopes.last_mut().expect("scope was just pushed");
+                if scope.get(name) != Some(&binding) {
+                    scope.insert(name.clone(), binding);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        self.walk(&param, false);
+        for body in children {
+            self.walk(&body, module_level);
+        }
+        self.scopes.pop();
     }
 
-    #[test]
-    fn extracts_multiple_registry_references() {
-        let source = r#"
-            { registry, ... }:
-            {
-                imports = [
-                    registry.home.alice
-                    registry.modules.nixos
-                    registry.hosts.server
-                ];
+    /// Walks a `let`, legacy `let { }` or `rec { }` whose bindings are visible
+    /// to their own values.
+    ///
+    /// Bindings to registry paths become aliases. Since bindings can refer to
+    /// each other in any order, aliases are resolved repeatedly until nothing
+    /// changes.
+    fn walk_recursive_bindings(&mut self, node: &SyntaxNode) {
+        let mut scope = HashMap::new();
+        let mut candidates = Vec::new();
+        for child in node.children() {
+            match child.kind() {
+                SyntaxKind::NODE_ATTRPATH_VALUE => {
+                    let mut parts = child.children();
+                    let (Some(attrpath), Some(value)) = (parts.next(), parts.next()) else {
+                        continue;
+                    };
+                    let Some(name) = attrpath.first_child().and_then(|a| static_attr_name(&a))
+                    else {
+                        continue;
+                    };
+                    scope.insert(name.clone(), Binding::Local);
+                    if attrpath.children().count() == 1 {
+                        candidates.push(AliasCandidate::Value(name, value));
+                    }
+                }
+                SyntaxKind::NODE_INHERIT => {
+                    let from = child
+                        .children()
+                        .find(|c| c.kind() == SyntaxKind::NODE_INHERIT_FROM)
+                        .and_then(|f| f.first_child());
+                    for attr in child
+                        .children()
+                        .filter(|c| c.kind() != SyntaxKind::NODE_INHERIT_FROM)
+                    {
+                        let Some(name) = static_attr_name(&attr) else {
+                            continue;
+                        };
+                        if let Some(source) = &from {
+                            scope.insert(name.clone(), Binding::Local);
+                            candidates.push(AliasCandidate::InheritFrom(
+                                name,
+                                source.clone(),
+                                attr,
+                            ));
+                        } else if let Some(binding) = self.resolve(&name) {
+                            // `inherit x;` re-binds whatever `x` means outside
+                            scope.insert(name, binding);
+                        } else if self.is_registry_head(&name) {
+                            scope.insert(name, Binding::Registry);
+                        }
+                    }
+                }
+                _ => {}
             }
-        "#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["home.alice", "modules.nixos", "hosts.server"]);
+        }
+
+        self.scopes.push(scope);
+        for _ in 0..=candidates.len() {
+            let mut changed = false;
+            for candidate in &candidates {
+                let (name, binding) = match candidate {
+                    AliasCandidate::Value(name, value) => (name, self.alias_target(value)),
+                    AliasCandidate::InheritFrom(name, source, attr) => (
+                        name,
+                        self.alias_target(source)
+                            .and_then(|b| inherited_alias(b, name, attr)),
+                    ),
+                };
+                let binding = binding.unwrap_or(Binding::Local);
+                let scope = self.scopes.last_mut().expect("scope was just pushed");
+                if scope.get(name) != Some(&binding) {
+                    scope.insert(name.clone(), binding);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        self.walk_children(node, false);
+        self.scopes.pop();
     }
 
-    #[test]
-    fn extracts_references_from_same_line() {
-        let source = "{ foo = registry.a.b; bar = registry.c.d; }";
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["a.b", "c.d"]);
+    /// Returns the alias binding for an expression that evaluates to a static
+    /// registry path: the registry itself, a select on it, or on an alias.
+    fn alias_target(&self, value: &SyntaxNode) -> Option<Binding> {
+        let range = value.text_range();
+        let (base, segments) = match value.kind() {
+            SyntaxKind::NODE_PAREN => return self.alias_target(&value.first_child()?),
+            SyntaxKind::NODE_IDENT => (value.clone(), Vec::new()),
+            SyntaxKind::NODE_SELECT => {
+                let mut children = value.children();
+                let base = children.next()?;
+                let attrpath = children.next()?;
+                if base.kind() != SyntaxKind::NODE_IDENT
+                    || attrpath.kind() != SyntaxKind::NODE_ATTRPATH
+                {
+                    return None;
+                }
+                let segments: Vec<PathSegment> = attrpath
+                    .children()
+                    .filter_map(|c| attr_segment(&c))
+                    .collect();
+                (base, segments)
+            }
+            _ => return None,
+        };
+        if segments
+            .iter()
+            .any(|s| matches!(s, PathSegment::Dynamic(_)))
+        {
+            return None;
+        }
+
+        let (base, prefix) = match self.resolve(&base.text().to_string()) {
+            Some(Binding::Alias {
+                base, mut prefix, ..
+            }) => {
+                prefix.extend(segments);
+                (base, prefix)
+            }
+            None | Some(Binding::Registry) => registry_expr(value, self.registry_names)?,
+            _ => return None,
+        };
+
+        // Anchor on the reference the scanner reports for this expression
+        let anchor_end = match value.children().nth(1) {
+            Some(attrpath) => attrpath.text_range().end(),
+            None => range.end(),
+        };
+        Some(Binding::Alias {
+            base,
+            prefix,
+            anchor_start: range.start().into(),
+            anchor_end: anchor_end.into(),
+        })
     }
 
-    #[test]
-    fn ignores_non_registry_patterns() {
-        let source = r#"
-            { pkgs, lib, ... }:
-            {
-                foo = "registry";
-                bar = pkgs.hello;
+    fn walk_with(&mut self, node: &SyntaxNode) {
+        let mut children = node.children();
+        let Some(header) = children.next() else {
+            return;
+        };
+        self.walk(&header, false);
+
+        let prefix = registry_expr(&header, self.registry_names)
+            .filter(|(_, segments)| segments.iter().all(|s| matches!(s, PathSegment::Static(_))));
+        let scope = prefix
+            .filter(|(base, _)| !self.is_shadowed(base))
+            .map(|(base, prefix)| WithScope {
+                base,
+                prefix,
+                header_start: header.text_range().start().into(),
+                header_end: header.text_range().end().into(),
+            });
+
+        self.withs.push(scope);
+        for body in children {
+            self.walk(&body, false);
+        }
+        self.withs.pop();
+    }
+
+    /// Resolves a free identifier through the innermost `with`, returning the
+    /// registry path prefix it selects from and the matching reference kind.
+    ///
+    /// Only the innermost `with` is considered: an inner `with lib;` could
+    /// supply the name just as well, so nothing is reported beneath it. See
+    /// [`Self::with_is_ambiguous`] for the reverse order.
+    fn resolve_via_with(&self, name: &str) -> Option<(&WithScope, RefKind)> {
+        if self.is_registry_head(name)
+            || NIX_GLOBALS.contains(&name)
+            || name.starts_with("__")
+            || self.resolve(name).is_some()
+        {
+            return None;
+        }
+
+        let scope = self.withs.last()?.as_ref()?;
+        let kind = RefKind::ViaWith {
+            header_start: scope.header_start,
+            header_end: scope.header_end,
+            prefix: static_path(&scope.prefix),
+        };
+        Some((scope, kind))
+    }
+
+    /// True if a `with` on something other than the registry encloses the
+    /// innermost one. Nix falls back to the outer `with` for names the
+    /// registry lacks, as in `with pkgs; with registry; [ hello ]`, so which
+    /// one supplies a name cannot be decided without evaluating both.
+    fn with_is_ambiguous(&self) -> bool {
+        self.withs.iter().rev().skip(1).any(Option::is_none)
+    }
+
+    /// Handles `attrByPath`, `getAttrFromPath` and `getAttr` calls whose path
+    /// elements are all string literals and whose target is the registry or a
+    /// static select on it.
+    ///
+    /// The functions may be called bare or through `lib.` / `builtins.`, e.g.
+    /// `lib.attrsets.attrByPath`.
+    fn visit_lookup(&mut self, node: &SyntaxNode) {
+        let (function, args) = call_parts(node);
+        let Some(name) = lookup_function(&function) else {
+            return;
+        };
+
+        let (path_arg, target) = match (name.as_str(), args.as_slice()) {
+            ("attrByPath", [path, _default, target]) => (path, target),
+            ("getAttrFromPath" | "getAttr", [path, target]) => (path, target),
+            _ => return,
+        };
+
+        let (names, elements, list) = if name == "getAttr" {
+            let Some(name) = string_literal(path_arg) else {
+                return;
+            };
+            let range = path_arg.text_range();
+            (
+                vec![name],
+                vec![(range.start().into(), range.end().into())],
+                None,
+            )
+        } else {
+            if path_arg.kind() != SyntaxKind::NODE_LIST {
+                return;
             }
-        "#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(refs.is_empty());
+            let items: Vec<SyntaxNode> = path_arg.children().collect();
+            let Some(names) = items.iter().map(string_literal).collect::<Option<Vec<_>>>() else {
+                return;
+            };
+            let elements = items
+                .iter()
+                .map(|item| {
+                    (
+                        item.text_range().start().into(),
+                        item.text_range().end().into(),
+                    )
+                })
+                .collect();
+            let range = path_arg.text_range();
+            (
+                names,
+                elements,
+                Some((range.start().into(), range.end().into())),
+            )
+        };
+
+        let Some((base, mut segments, (target_start, target_end))) = self.registry_target(target)
+        else {
+            return;
+        };
+
+        let kind = RefKind::Lookup {
+            elements,
+            list,
+            target_start,
+            target_end,
+            prefix: static_path(&segments),
+        };
+        segments.extend(names.into_iter().map(PathSegment::Static));
+        let shadowed = self.is_shadowed(&base);
+        let range = node.text_range();
+        self.record(
+            base,
+            segments,
+            range.start().into(),
+            range.end().into(),
+            kind,
+            shadowed,
+        )
+        .guarded = name == "attrByPath";
     }
 
-    #[test]
-    fn handles_deep_nesting() {
-        let source = "registry.a.b.c.d.e";
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["a.b.c.d.e"]);
+    /// Handles `registry ? users.alice` and `registry.users ? alice`.
+    fn visit_has_attr(&mut self, node: &SyntaxNode) {
+        let mut children = node.children();
+        let (Some(target), Some(attrpath)) = (children.next(), children.next()) else {
+            return;
+        };
+        if attrpath.kind() != SyntaxKind::NODE_ATTRPATH {
+            return;
+        }
+        let Some((base, mut segments, (target_start, target_end))) = self.registry_target(&target)
+        else {
+            return;
+        };
+
+        let range = attrpath.text_range();
+        let kind = RefKind::HasAttr {
+            target_start,
+            target_end,
+            prefix: static_path(&segments),
+            attrpath_start: range.start().into(),
+            attrpath_end: range.end().into(),
+        };
+        segments.extend(attrpath.children().filter_map(|c| attr_segment(&c)));
+        let shadowed = self.is_shadowed(&base);
+        let range = node.text_range();
+        self.record(
+            base,
+            segments,
+            range.start().into(),
+            range.end().into(),
+            kind,
+            shadowed,
+        )
+        .guarded = true;
     }
 
-    #[test]
-    fn extracts_with_custom_registry_name() {
-        let source = r#"
-            { impRegistry, ... }:
-            { imports = [ impRegistry.home.alice ]; }
-        "#;
-        let refs = extract_paths_from_source(source, "impRegistry");
-        assert_eq!(refs, vec!["home.alice"]);
+    /// Resolves the registry expression a lookup or `?` check applies to: the
+    /// registry itself or a static select on it. Returns the base identifier,
+    /// the selected segments and the expression's span.
+    fn registry_target(
+        &self,
+        node: &SyntaxNode,
+    ) -> Option<(String, Vec<PathSegment>, (usize, usize))> {
+        let node = unparen(node);
+        let (base, segments) = registry_expr(&node, self.registry_names)?;
+        let span = match node.kind() {
+            SyntaxKind::NODE_SELECT => select_span(&node),
+            _ => (
+                node.text_range().start().into(),
+                node.text_range().end().into(),
+            ),
+        };
+        let is_static = segments.iter().all(|s| matches!(s, PathSegment::Static(_)));
+        is_static.then_some((base, segments, span))
     }
 
-    #[test]
-    fn ignores_registry_without_path() {
-        let source = "x = registry;";
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(refs.is_empty());
+    /// Handles a relative path literal such as `../../registry/home/alice`,
+    /// mapping it to `home.alice` when it resolves into the registry directory.
+    ///
+    /// `alice.nix` and `alice/default.nix` both map to `alice`. Interpolated,
+    /// absolute, home-relative and `<search>` paths are ignored.
+    fn visit_path(&mut self, node: &SyntaxNode) {
+        let (Some(registry_dir), Some(dir), Some(&base)) = (
+            self.registry_dir.as_ref(),
+            self.file.parent(),
+            self.registry_names.first(),
+        ) else {
+            return;
+        };
+        let mut parts = node.children_with_tokens();
+        let (Some(rowan::NodeOrToken::Token(token)), None) = (parts.next(), parts.next()) else {
+            return;
+        };
+        let literal = token.text();
+        if !literal.starts_with("./") && !literal.starts_with("../") {
+            return;
+        }
+
+        let target = normalize_path(&dir.join(literal));
+        let Ok(relative) = target.strip_prefix(registry_dir) else {
+            return;
+        };
+        let mut names: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let suffix = match names.last().map(String::as_str) {
+            Some("default.nix") => {
+                names.pop();
+                "/default.nix"
+            }
+            Some(last) if last.len() > 4 && last.ends_with(".nix") => {
+                let stem = last.trim_end_matches(".nix").to_string();
+                names.pop();
+                names.push(stem);
+                ".nix"
+            }
+            _ => "",
+        };
+        if names.is_empty() {
+            return;
+        }
+
+        // Only literals written as `<prefix>/<a>/<b><suffix>` can be rewritten
+        let written = format!("{}{}", names.join("/"), suffix);
+        let Some(prefix) = literal
+            .strip_suffix(written.as_str())
+            .and_then(|p| p.strip_suffix('/'))
+        else {
+            return;
+        };
+
+        let range = node.text_range();
+        let kind = RefKind::Path {
+            target,
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+        };
+        self.record(
+            base.to_string(),
+            names.into_iter().map(PathSegment::Static).collect(),
+            range.start().into(),
+            range.end().into(),
+            kind,
+            false,
+        );
     }
 
-    #[test]
-    fn extracts_from_fixture_file() {
-        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests/fixtures/migrate-test/outputs/config-a.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
-        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
-        assert_eq!(paths, vec!["home.alice", "modules.nixos"]);
+    /// Handles a bare identifier such as `desktop` in `with registry.profiles;`.
+    fn visit_ident(&mut self, node: &SyntaxNode) {
+        let name = node.text().to_string();
+        if let Some((scope, kind)) = self.resolve_via_with(&name) {
+            let base = scope.base.clone();
+            let mut segments = scope.prefix.clone();
+            segments.push(PathSegment::Static(name));
+            let range = node.text_range();
+            let ambiguous = self.with_is_ambiguous();
+            let reference = self.reference(
+                base,
+                segments,
+                range.start().into(),
+                range.end().into(),
+                kind,
+            );
+            self.classify(reference, ambiguous.then_some(AMBIGUOUS_WITH));
+        }
     }
 
-    #[test]
-    fn extracts_from_multi_rename_fixture() {
-        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    fn is_registry_name(&self, name: &str) -> bool {
+        self.registry_names.contains(&name)
+    }
+
+    /// True if `name` is a registry name or the identifier a dotted one such
+    /// as `self.registry` starts with.
+    fn is_registry_head(&self, name: &str) -> bool {
+        self.registry_names
+            .iter()
+            .any(|n| n.split('.').next() == Some(name))
+    }
+
+    /// True if the identifier `base` starts with is locally rebound.
+    fn is_shadowed(&self, base: &str) -> bool {
+        let head = base.split('.').next().unwrap_or(base);
+        self.resolve(head) == Some(Binding::Local)
+    }
+
+    /// Returns the innermost binding of `name`, or `None` if it is free.
+    fn resolve(&self, name: &str) -> Option<Binding> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+    }
+
+    fn visit_select(&mut self, node: &SyntaxNode) {
+        let (start, end) = select_span(node);
+        let guarded = node
+            .children_with_tokens()
+            .any(|c| c.kind() == SyntaxKind::TOKEN_OR);
+
+        if let Some((base, segments)) = extract_segments(node, self.registry_names) {
+            let shadowed = self.is_shadowed(&base);
+            self.record(base, segments, start, end, RefKind::Select, shadowed)
+                .guarded = guarded;
+            return;
+        }
+
+        let mut children = node.children();
+        let (Some(base), Some(attrpath)) = (children.next(), children.next()) else {
+            return;
+        };
+        if base.kind() != SyntaxKind::NODE_IDENT || attrpath.kind() != SyntaxKind::NODE_ATTRPATH {
+            return;
+        }
+
+        // `users.alice` where `users = registry.users;`
+        let name = base.text().to_string();
+        if let Some(Binding::Alias {
+            base,
+            mut prefix,
+            anchor_start,
+            anchor_end,
+        }) = self.resolve(&name)
+        {
+            let kind = RefKind::Alias {
+                binding_start: anchor_start,
+                binding_end: anchor_end,
+                name,
+                prefix: static_path(&prefix),
+            };
+            prefix.extend(attrpath.children().filter_map(|c| attr_segment(&c)));
+            self.record(base, prefix, start, end, kind, false).guarded = guarded;
+            return;
+        }
+
+        // `users.alice` inside `with registry;`
+        if let Some((scope, kind)) = self.resolve_via_with(&name) {
+            let base = scope.base.clone();
+            let mut segments = scope.prefix.clone();
+            segments.push(PathSegment::Static(name));
+            segments.extend(attrpath.children().filter_map(|c| attr_segment(&c)));
+            let ambiguous = self.with_is_ambiguous();
+            let reference = self.reference(base, segments, start, end, kind);
+            self.classify(reference, ambiguous.then_some(AMBIGUOUS_WITH))
+                .guarded = guarded;
+        }
+    }
+
+    /// Emits one reference per name of `inherit (registry.a) b c;`.
+    ///
+    /// Returns false if the inherit source is not the registry, in which case
+    /// the caller keeps walking into it.
+    fn visit_inherit(&mut self, node: &SyntaxNode) -> bool {
+        let Some(from) = node
+            .children()
+            .find(|c| c.kind() == SyntaxKind::NODE_INHERIT_FROM)
+        else {
+            return false;
+        };
+        let Some(source) = from.first_child() else {
+            return false;
+        };
+
+        let Some((base, prefix)) = registry_expr(&source, self.registry_names) else {
+            return false;
+        };
+        let shadowed = self.is_shadowed(&base);
+
+        let kind = RefKind::Inherit {
+            source_start: source.text_range().start().into(),
+            source_end: source.text_range().end().into(),
+            statement_start: node.text_range().start().into(),
+            statement_end: node.text_range().end().into(),
+        };
+        for attr in node
+            .children()
+            .filter(|c| c.kind() != SyntaxKind::NODE_INHERIT_FROM)
+        {
+            let Some(segment) = attr_segment(&attr) else {
+                continue;
+            };
+            let mut segments = prefix.clone();
+            segments.push(segment);
+            let range = attr.text_range();
+            self.record(
+                base.clone(),
+                segments,
+                range.start().into(),
+                range.end().into(),
+                kind.clone(),
+                shadowed,
+            );
+        }
+        true
+    }
+
+    /// Classifies a reference as checked or skipped and stores it.
+    ///
+    /// `shadowed` is true when the registry name at the reference resolves to
+    /// a local binding rather than the module argument.
+    fn record(
+        &mut self,
+        base: String,
+        segments: Vec<PathSegment>,
+        start: usize,
+        end: usize,
+        kind: RefKind,
+        shadowed: bool,
+    ) -> &mut RegistryRef {
+        let reference = self.reference(base, segments, start, end, kind);
+        self.classify(reference, shadowed.then_some("local binding"))
+    }
+
+    /// Builds the reference spanning `start..end`.
+    fn reference(
+        &self,
+        base: String,
+        segments: Vec<PathSegment>,
+        start: usize,
+        end: usize,
+        kind: RefKind,
+    ) -> RegistryRef {
+        let span = self.lines.span(start, end);
+        RegistryRef {
+            base,
+            path: static_path(&segments),
+            segments,
+            file: self.file.to_path_buf(),
+            line: span.start.line,
+            column: span.start.column,
+            end_line: span.end.line,
+            end_column: span.end.column,
+            span,
+            start_offset: start,
+            end_offset: end,
+            guarded: false,
+            kind,
+        }
+    }
+
+    /// Stores `reference` as checked, or as skipped for `skip` or for lacking
+    /// a static prefix.
+    fn classify(&mut self, reference: RegistryRef, skip: Option<&str>) -> &mut RegistryRef {
+        let reason = match skip {
+            Some(reason) => reason,
+            None if reference.path.is_empty() => "dynamic reference without static prefix",
+            None => {
+                self.scan.refs.push(reference);
+                return self.scan.refs.last_mut().unwrap();
+            }
+        };
+        self.scan.skipped.push(SkippedRef {
+            reference,
+            reason: reason.to_string(),
+        });
+        &mut self.scan.skipped.last_mut().unwrap().reference
+    }
+}
+
+/// Skip reason for a name a non-registry `with` may supply instead.
+const AMBIGUOUS_WITH: &str = "ambiguous with scope";
+
+/// Joins the static segments before the first dynamic one into a dotted path.
+fn static_path(segments: &[PathSegment]) -> String {
+    let names: Vec<&str> = segments
+        .iter()
+        .map_while(|s| match s {
+            PathSegment::Static(name) => Some(name.as_str()),
+            PathSegment::Dynamic(_) => None,
+        })
+        .collect();
+    registry::join_attrs(&names)
+}
+
+/// Binding for `name` in `inherit (<source>) name;` where `source` aliases
+/// a registry path. The anchor is the inherited name, as for inherit refs.
+fn inherited_alias(source: Binding, name: &str, attr: &SyntaxNode) -> Option<Binding> {
+    let Binding::Alias {
+        base, mut prefix, ..
+    } = source
+    else {
+        return None;
+    };
+    prefix.push(PathSegment::Static(name.to_string()));
+    Some(Binding::Alias {
+        base,
+        prefix,
+        anchor_start: attr.text_range().start().into(),
+        anchor_end: attr.text_range().end().into(),
+    })
+}
+
+/// Returns true if a `NODE_IDENT` is used as a value rather than as an
+/// attribute name, parameter name, inherited name or select base.
+fn is_expression_ident(node: &SyntaxNode) -> bool {
+    let Some(parent) = node.parent() else {
+        return true;
+    };
+    let is_first = parent.first_child().as_ref() == Some(node);
+    match parent.kind() {
+        SyntaxKind::NODE_ATTRPATH
+        | SyntaxKind::NODE_INHERIT
+        | SyntaxKind::NODE_IDENT_PARAM
+        | SyntaxKind::NODE_PAT_BIND => false,
+        SyntaxKind::NODE_SELECT | SyntaxKind::NODE_PAT_ENTRY => !is_first,
+        _ => true,
+    }
+}
+
+fn is_rec(node: &SyntaxNode) -> bool {
+    node.children_with_tokens()
+        .any(|c| c.kind() == SyntaxKind::TOKEN_REC)
+}
+
+/// Names bound by a lambda parameter: `x:`, `{ a, b ? 1, ... }:` or `args@{ ... }:`.
+fn param_names(param: &SyntaxNode) -> Vec<String> {
+    match param.kind() {
+        SyntaxKind::NODE_IDENT_PARAM => param
+            .first_child()
+            .map(|ident| vec![ident.text().to_string()])
+            .unwrap_or_default(),
+        SyntaxKind::NODE_PATTERN => param
+            .children()
+            .filter(|c| {
+                matches!(
+                    c.kind(),
+                    SyntaxKind::NODE_PAT_ENTRY | SyntaxKind::NODE_PAT_BIND
+                )
+            })
+            .filter_map(|c| {
+                c.children()
+                    .find(|i| i.kind() == SyntaxKind::NODE_IDENT)
+                    .map(|i| i.text().to_string())
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Formals of a `{ ... }:` pattern that have a default, with the default.
+fn pattern_defaults(param: &SyntaxNode) -> Vec<(String, SyntaxNode)> {
+    param
+        .children()
+        .filter(|c| c.kind() == SyntaxKind::NODE_PAT_ENTRY)
+        .filter_map(|entry| {
+            let mut children = entry.children();
+            let name = children.next()?;
+            let default = children.next()?;
+            (name.kind() == SyntaxKind::NODE_IDENT).then(|| (name.text().to_string(), default))
+        })
+        .collect()
+}
+
+/// Returns the name of an identifier or string-literal attribute, ignoring
+/// interpolations.
+pub(crate) fn static_attr_name(attr: &SyntaxNode) -> Option<String> {
+    match attr_segment(attr)? {
+        PathSegment::Static(name) => Some(name),
+        PathSegment::Dynamic(_) => None,
+    }
+}
+
+/// Classifies a single attrpath component.
+///
+/// Identifiers and plain string literals are static. `${"name"}` wrapping a
+/// plain string is static too; any other interpolation is dynamic.
+fn attr_segment(attr: &SyntaxNode) -> Option<PathSegment> {
+    match attr.kind() {
+        SyntaxKind::NODE_IDENT => Some(PathSegment::Static(attr.text().to_string())),
+        SyntaxKind::NODE_STRING => Some(
+            string_literal(attr)
+                .map(PathSegment::Static)
+                .unwrap_or_else(|| PathSegment::Dynamic(attr.text().to_string())),
+        ),
+        SyntaxKind::NODE_DYNAMIC => Some(
+            attr.first_child()
+                .filter(|inner| inner.kind() == SyntaxKind::NODE_STRING)
+                .and_then(|inner| string_literal(&inner))
+                .map(PathSegment::Static)
+                .unwrap_or_else(|| PathSegment::Dynamic(attr.text().to_string())),
+        ),
+        _ => None,
+    }
+}
+
+/// Decodes a string node without interpolations, resolving escapes.
+fn string_literal(node: &SyntaxNode) -> Option<String> {
+    let string = rnix::ast::Str::cast(node.clone())?;
+    let mut out = String::new();
+    for part in string.normalized_parts() {
+        match part {
+            InterpolPart::Literal(text) => out.push_str(&text),
+            InterpolPart::Interpolation(_) => return None,
+        }
+    }
+    Some(out)
+}
+
+/// Span of a select up to the end of its attrpath, leaving out any `or`
+/// default.
+fn select_span(node: &SyntaxNode) -> (usize, usize) {
+    let start = node.text_range().start().into();
+    let end = node
+        .children()
+        .nth(1)
+        .map_or(node.text_range().end(), |attrpath| {
+            attrpath.text_range().end()
+        })
+        .into();
+    (start, end)
+}
+
+/// Splits a curried call `f a b c` into `f` and `[a, b, c]`.
+fn call_parts(node: &SyntaxNode) -> (SyntaxNode, Vec<SyntaxNode>) {
+    let mut args = Vec::new();
+    let mut function = node.clone();
+    while function.kind() == SyntaxKind::NODE_APPLY {
+        let mut children = function.children();
+        let (Some(lambda), Some(arg)) = (children.next(), children.next()) else {
+            break;
+        };
+        args.push(arg);
+        function = lambda;
+    }
+    args.reverse();
+    (function, args)
+}
+
+/// Name of a path lookup function called as `name`, `lib.name`,
+/// `lib.attrsets.name` or `builtins.name`.
+fn lookup_function(node: &SyntaxNode) -> Option<String> {
+    const LOOKUPS: &[&str] = &["attrByPath", "getAttrFromPath", "getAttr"];
+
+    let name = match node.kind() {
+        SyntaxKind::NODE_IDENT => node.text().to_string(),
+        SyntaxKind::NODE_SELECT => {
+            let mut children = node.children();
+            let base = children.next()?;
+            if base.kind() != SyntaxKind::NODE_IDENT {
+                return None;
+            }
+            let attrs = children
+                .next()?
+                .children()
+                .map(|attr| static_attr_name(&attr))
+                .collect::<Option<Vec<_>>>()?;
+            match (base.text().to_string().as_str(), attrs.as_slice()) {
+                ("builtins" | "lib", [name]) => name.clone(),
+                ("lib", [attrsets, name]) if attrsets == "attrsets" => name.clone(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    LOOKUPS.contains(&name.as_str()).then_some(name)
+}
+
+/// Strips any parentheses around `node`.
+pub(crate) fn unparen(node: &SyntaxNode) -> SyntaxNode {
+    let mut node = node.clone();
+    while node.kind() == SyntaxKind::NODE_PAREN {
+        match node.children().next() {
+            Some(inner) => node = inner,
+            None => break,
+        }
+    }
+    node
+}
+
+/// Reconstructs the attrpath segments of a `NODE_SELECT` node.
+///
+/// rnix parses `registry.home."web-app".${name}` as:
+/// ```text
+/// NODE_SELECT
+///   NODE_IDENT (registry)
+///   NODE_ATTRPATH
+///     NODE_IDENT (home)
+///     NODE_STRING ("web-app")
+///     NODE_DYNAMIC (${name})
+/// ```
+///
+/// Returns the registry name and the segments below it if the select starts
+/// with one of `registry_names`, which may be dotted (see [`registry_expr`]).
+fn extract_segments(
+    node: &SyntaxNode,
+    registry_names: &[&str],
+) -> Option<(String, Vec<PathSegment>)> {
+    if node.kind() != SyntaxKind::NODE_SELECT {
+        return None;
+    }
+    registry_expr(node, registry_names).filter(|(_, segments)| !segments.is_empty())
+}
+
+/// Matches the registry itself or a select on it, returning the registry
+/// name and the segments selected below it (empty for the registry itself).
+///
+/// A dotted name such as `self.registry` matches a select whose base
+/// identifier and leading static segments spell it out, so `self.registry`
+/// and `self.registry.users` match but `self.packages` does not. When several
+/// names match, the longest wins.
+fn registry_expr(node: &SyntaxNode, registry_names: &[&str]) -> Option<(String, Vec<PathSegment>)> {
+    let (head, segments) = match node.kind() {
+        SyntaxKind::NODE_IDENT => (node.text().to_string(), Vec::new()),
+        SyntaxKind::NODE_SELECT => {
+            let mut children = node.children();
+            let (Some(base), Some(attrpath)) = (children.next(), children.next()) else {
+                return None;
+            };
+            if base.kind() != SyntaxKind::NODE_IDENT || attrpath.kind() != SyntaxKind::NODE_ATTRPATH
+            {
+                return None;
+            }
+            let segments: Vec<PathSegment> = attrpath
+                .children()
+                .filter_map(|c| attr_segment(&c))
+                .collect();
+            (base.text().to_string(), segments)
+        }
+        _ => return None,
+    };
+
+    let (name, depth) = registry_names
+        .iter()
+        .filter_map(|name| {
+            let mut parts = name.split('.');
+            if parts.next()? != head {
+                return None;
+            }
+            let rest: Vec<&str> = parts.collect();
+            let matches = rest.len() <= segments.len()
+                && rest
+                    .iter()
+                    .zip(&segments)
+                    .all(|(part, s)| matches!(s, PathSegment::Static(n) if n == part));
+            matches.then_some((*name, rest.len()))
+        })
+        .max_by_key(|(_, depth)| *depth)?;
+    Some((name.to_string(), segments[depth..].to_vec()))
+}
+
+/// Makes `path` absolute and resolves `.` and `..` components lexically, so
+/// path literals can be compared without touching the filesystem.
+fn normalize_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Locates an rnix parse error and renders it without byte offsets.
+fn describe_parse_error(
+    source: &str,
+    lines: &LineIndex,
+    file: &Path,
+    err: &rnix::parser::ParseError,
+) -> ParseError {
+    use rnix::parser::ParseError as E;
+
+    let kinds = |kinds: &[SyntaxKind]| {
+        kinds
+            .iter()
+            .map(|k| syntax_kind_name(*k))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let (offset, message) = match err {
+        E::Unexpected(range) => (range.start().into(), "unexpected syntax".to_string()),
+        E::UnexpectedExtra(range) => (
+            range.start().into(),
+            "unexpected token after the end of the expression".to_string(),
+        ),
+        E::UnexpectedWanted(got, range, wanted) => (
+            range.start().into(),
+            format!(
+                "unexpected {}, expected one of: {}",
+                syntax_kind_name(*got),
+                kinds(wanted)
+            ),
+        ),
+        E::UnexpectedDoubleBind(range) => {
+            (range.start().into(), "pattern is bound twice".to_string())
+        }
+        E::UnexpectedEOF => (source.len(), "unexpected end of file".to_string()),
+        E::UnexpectedEOFWanted(wanted) => (
+            source.len(),
+            format!("unexpected end of file, expected one of: {}", kinds(wanted)),
+        ),
+        E::DuplicatedArgs(range, name) => (
+            range.start().into(),
+            format!("duplicated argument '{}'", name),
+        ),
+        E::RecursionLimitExceeded => (0, "expression is nested too deeply".to_string()),
+        other => (0, other.to_string()),
+    };
+
+    let position = lines.position(offset);
+    ParseError {
+        file: file.to_path_buf(),
+        line: position.line,
+        column: position.column,
+        message,
+    }
+}
+
+/// `TOKEN_SEMICOLON` -> `semicolon`, `NODE_ATTRPATH` -> `attrpath`.
+fn syntax_kind_name(kind: SyntaxKind) -> String {
+    let name = format!("{:?}", kind);
+    name.trim_start_matches("TOKEN_")
+        .trim_start_matches("NODE_")
+        .to_lowercase()
+}
+
+/// Extracts registry paths from Nix source code.
+///
+/// Parses the source string and returns all dotted paths following `registry_name.`.
+/// Unlike `extract_registry_refs`, this operates on strings directly for testing.
+#[cfg(test)]
+pub fn extract_paths_from_source(source: &str, registry_name: &str) -> Vec<String> {
+    scan_source(source, Path::new("test.nix"), &[registry_name])
+        .refs
+        .into_iter()
+        .map(|r| r.path)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_single_registry_reference() {
+        let source = r#"
+            { registry, ... }:
+            { imports = [ registry.home.alice ]; }
+        "#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["home.alice"]);
+    }
+
+    #[test]
+    fn extracts_multiple_registry_references() {
+        let source = r#"
+            { registry, ... }:
+            {
+                imports = [
+                    registry.home.alice
+                    registry.modules.nixos
+                    registry.hosts.server
+                ];
+            }
+        "#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["home.alice", "modules.nixos", "hosts.server"]);
+    }
+
+    #[test]
+    fn extracts_references_from_same_line() {
+        let source = "{ foo = registry.a.b; bar = registry.c.d; }";
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["a.b", "c.d"]);
+    }
+
+    #[test]
+    fn ignores_non_registry_patterns() {
+        let source = r#"
+            { pkgs, lib, ... }:
+            {
+                foo = "registry";
+                bar = pkgs.hello;
+            }
+        "#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn handles_deep_nesting() {
+        let source = "registry.a.b.c.d.e";
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["a.b.c.d.e"]);
+    }
+
+    #[test]
+    fn extracts_with_custom_registry_name() {
+        let source = r#"
+            { impRegistry, ... }:
+            { imports = [ impRegistry.home.alice ]; }
+        "#;
+        let refs = extract_paths_from_source(source, "impRegistry");
+        assert_eq!(refs, vec!["home.alice"]);
+    }
+
+    #[test]
+    fn ignores_registry_without_path() {
+        let source = "x = registry;";
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn extracts_from_fixture_file() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/migrate-test/outputs/config-a.nix");
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["home.alice", "modules.nixos"]);
+    }
+
+    #[test]
+    fn extracts_from_multi_rename_fixture() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests/fixtures/complex-renames/files/multi-rename.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
         let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
         assert_eq!(
             paths,
@@ -320,311 +2047,1438 @@ mod tests {
     }
 
     #[test]
-    fn collects_nix_files_from_fixture() {
-        let fixture_dir =
-            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/migrate-test/outputs");
-        let files = collect_nix_files(&[fixture_dir], &[], true).unwrap();
-        assert_eq!(files.len(), 3);
+    fn collects_nix_files_from_fixture() {
+        let fixture_dir =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/migrate-test/outputs");
+        let files = collect_nix_files(&[fixture_dir], &CollectOptions::default())
+            .unwrap()
+            .paths();
+        assert_eq!(files.len(), 3);
+    }
+
+    #[test]
+    fn extracts_from_config_b_fixture() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/migrate-test/outputs/config-b.nix");
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["users.alice", "mods.nixos"]);
+    }
+
+    #[test]
+    fn extracts_from_mixed_fixture() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/migrate-test/outputs/mixed.nix");
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["hosts.server", "users.bob"]);
+    }
+
+    #[test]
+    fn extracts_from_deep_nesting_fixture() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/complex-renames/files/deep-nesting.nix");
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "svc.db.postgresql",
+                "svc.db.redis",
+                "svc.http.nginx",
+                "svc.http.caddy",
+                "utils.helpers.strings",
+            ]
+        );
+    }
+
+    #[test]
+    fn extracts_from_ambiguous_fixture() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/complex-renames/files/ambiguous.nix");
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "old.programs.editor",
+                "old.desktop.gnome",
+                "config.server.minimal",
+                "configs.base",
+            ]
+        );
+    }
+
+    #[test]
+    fn extracts_from_partial_valid_fixture() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/complex-renames/files/partial-valid.nix");
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "users.alice.programs.editor",
+                "services.database.postgresql",
+                "profiles.desktop.gnome",
+                "home.bob.shell",
+                "svc.web.caddy",
+            ]
+        );
+    }
+
+    #[test]
+    fn extracts_from_all_valid_fixture() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/complex-renames/files/all-valid.nix");
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "users.alice.programs.editor",
+                "users.alice.programs.zsh",
+                "users.bob.shell",
+                "services.database.postgresql",
+                "services.web.nginx",
+                "profiles.desktop.gnome",
+                "lib.helpers.strings",
+            ]
+        );
+    }
+
+    #[test]
+    fn collects_nix_files_from_complex_renames() {
+        let fixture_dir =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/complex-renames/files");
+        let files = collect_nix_files(&[fixture_dir], &CollectOptions::default())
+            .unwrap()
+            .paths();
+        assert_eq!(files.len(), 6);
+    }
+
+    #[test]
+    fn exclude_pattern_filters_by_name() {
+        let fixture_dir =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/complex-renames/files");
+        let files = collect_nix_files(
+            &[fixture_dir],
+            &CollectOptions {
+                exclude: vec!["ambiguous.nix".to_string()],
+                ..CollectOptions::default()
+            },
+        )
+        .unwrap()
+        .paths();
+        assert_eq!(files.len(), 5);
+    }
+
+    #[test]
+    fn exclude_pattern_with_glob() {
+        let fixture_dir =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/complex-renames/files");
+        let files = collect_nix_files(
+            &[fixture_dir],
+            &CollectOptions {
+                exclude: vec!["*valid*".to_string()],
+                ..CollectOptions::default()
+            },
+        )
+        .unwrap()
+        .paths();
+        // Excludes all-valid.nix and partial-valid.nix
+        assert_eq!(files.len(), 4);
+    }
+
+    #[test]
+    fn no_default_excludes_includes_dotfiles() {
+        let tmp = tempfile::tempdir().unwrap();
+        let testdir = tmp.path().join("testroot");
+        std::fs::create_dir(&testdir).unwrap();
+        let dotdir = testdir.join(".hidden");
+        std::fs::create_dir(&dotdir).unwrap();
+        std::fs::write(dotdir.join("test.nix"), "{}").unwrap();
+        std::fs::write(testdir.join("visible.nix"), "{}").unwrap();
+
+        // With defaults, hidden dir is excluded
+        let files = collect_nix_files(std::slice::from_ref(&testdir), &CollectOptions::default())
+            .unwrap()
+            .paths();
+        assert_eq!(files.len(), 1);
+
+        // Without defaults, hidden dir is included
+        let files = collect_nix_files(
+            &[testdir],
+            &CollectOptions {
+                use_default_excludes: false,
+                ..CollectOptions::default()
+            },
+        )
+        .unwrap()
+        .paths();
+        assert_eq!(files.len(), 2);
+    }
+
+    #[test]
+    fn no_default_excludes_includes_underscored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let testdir = tmp.path().join("testroot");
+        std::fs::create_dir(&testdir).unwrap();
+        std::fs::write(testdir.join("_internal.nix"), "{}").unwrap();
+        std::fs::write(testdir.join("public.nix"), "{}").unwrap();
+
+        // With defaults, underscore-prefixed is excluded
+        let files = collect_nix_files(std::slice::from_ref(&testdir), &CollectOptions::default())
+            .unwrap()
+            .paths();
+        assert_eq!(files.len(), 1);
+
+        // Without defaults, underscore-prefixed is included
+        let files = collect_nix_files(
+            &[testdir],
+            &CollectOptions {
+                use_default_excludes: false,
+                ..CollectOptions::default()
+            },
+        )
+        .unwrap()
+        .paths();
+        assert_eq!(files.len(), 2);
+    }
+
+    #[test]
+    fn scan_files_preserves_input_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..16)
+            .map(|i| {
+                let file = tmp.path().join(format!("f{}.nix", i));
+                std::fs::write(&file, format!("{{ x = registry.n{}; }}", i)).unwrap();
+                file
+            })
+            .collect();
+
+        let scans = scan_files(&files, &RegistryNames::new("registry"), Some(4), None).unwrap();
+        let paths: Vec<_> = scans.iter().map(|s| s.refs[0].path.clone()).collect();
+        let expected: Vec<_> = (0..16).map(|i| format!("n{}", i)).collect();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn invalid_exclude_pattern_is_an_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        let err = collect_nix_files(
+            &[tmp.path().to_path_buf()],
+            &CollectOptions {
+                exclude: vec!["[".to_string()],
+                ..CollectOptions::default()
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Invalid exclude pattern '['"));
+    }
+
+    #[test]
+    fn respects_gitignore_and_project_ignore_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let testdir = tmp.path().join("testroot");
+        std::fs::create_dir_all(testdir.join("result")).unwrap();
+        std::fs::create_dir_all(testdir.join("vendor")).unwrap();
+        std::fs::write(testdir.join(".gitignore"), "result/\n").unwrap();
+        std::fs::write(testdir.join(IGNORE_FILE), "vendor/\n*.gen.nix\n").unwrap();
+        std::fs::write(testdir.join("result/out.nix"), "{}").unwrap();
+        std::fs::write(testdir.join("vendor/lib.nix"), "{}").unwrap();
+        std::fs::write(testdir.join("types.gen.nix"), "{}").unwrap();
+        std::fs::write(testdir.join("default.nix"), "{}").unwrap();
+
+        let files = collect_nix_files(std::slice::from_ref(&testdir), &CollectOptions::default())
+            .unwrap()
+            .paths();
+        assert_eq!(files, vec![testdir.join("default.nix")]);
+
+        // Without ignore files, everything is collected
+        let files = collect_nix_files(
+            &[testdir],
+            &CollectOptions {
+                use_ignore_files: false,
+                ..CollectOptions::default()
+            },
+        )
+        .unwrap()
+        .paths();
+        assert_eq!(files.len(), 4);
+    }
+
+    #[test]
+    fn respects_git_info_exclude() {
+        let tmp = tempfile::tempdir().unwrap();
+        let testdir = tmp.path().join("testroot");
+        std::fs::create_dir_all(testdir.join(".git/info")).unwrap();
+        std::fs::write(testdir.join(".git/info/exclude"), "scratch.nix\n").unwrap();
+        std::fs::write(testdir.join("scratch.nix"), "{}").unwrap();
+        std::fs::write(testdir.join("default.nix"), "{}").unwrap();
+
+        let files = collect_nix_files(std::slice::from_ref(&testdir), &CollectOptions::default())
+            .unwrap()
+            .paths();
+        assert_eq!(files, vec![testdir.join("default.nix")]);
+    }
+
+    #[test]
+    fn unreadable_files_are_reported_not_fatal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let binary = tmp.path().join("binary.nix");
+        let good = tmp.path().join("good.nix");
+        std::fs::write(&binary, b"{ x = \xff\xfe; }").unwrap();
+        std::fs::write(&good, "{ x = registry.users.alice; }").unwrap();
+        let missing = tmp.path().join("missing.nix");
+
+        let files = vec![binary.clone(), missing.clone(), good];
+        let scans = scan_files(&files, &RegistryNames::new("registry"), None, None).unwrap();
+
+        assert_eq!(scans[0].read_errors[0].file, binary);
+        assert!(scans[0].read_errors[0].message.contains("not valid UTF-8"));
+        assert_eq!(scans[1].read_errors[0].file, missing);
+        assert_eq!(scans[1].read_errors[0].message, "entity not found");
+        assert!(scans[2].read_errors.is_empty());
+        assert_eq!(scans[2].refs[0].path, "users.alice");
+    }
+
+    #[test]
+    fn extensions_and_include_patterns_select_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("testroot");
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::write(root.join("default.nix"), "{}").unwrap();
+        std::fs::write(root.join("module.nix.in"), "{}").unwrap();
+        std::fs::write(root.join("lib/generated"), "{}").unwrap();
+        std::fs::write(root.join("lib/README"), "").unwrap();
+        std::fs::write(root.join("nix"), "").unwrap();
+
+        let options = CollectOptions {
+            include: vec!["lib/gen*".to_string()],
+            extensions: vec!["nix".to_string(), ".nix.in".to_string()],
+            ..CollectOptions::default()
+        };
+        let found = collect_nix_files(std::slice::from_ref(&root), &options).unwrap();
+        assert_eq!(
+            found.paths(),
+            vec![
+                root.join("default.nix"),
+                root.join("lib/generated"),
+                root.join("module.nix.in")
+            ]
+        );
+        let rules: Vec<_> = found.files.iter().map(|(_, rule)| rule).collect();
+        assert_eq!(
+            rules,
+            vec![
+                &FileRule::Extension("nix".to_string()),
+                &FileRule::Include("lib/gen*".to_string()),
+                &FileRule::Extension("nix.in".to_string()),
+            ]
+        );
+        assert_eq!(rules[1].to_string(), "include lib/gen*");
+    }
+
+    #[test]
+    fn invalid_include_pattern_is_an_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        let options = CollectOptions {
+            include: vec!["[".to_string()],
+            ..CollectOptions::default()
+        };
+        let err = collect_nix_files(&[tmp.path().to_path_buf()], &options).unwrap_err();
+        assert!(err.to_string().contains("Invalid include pattern '['"));
+    }
+
+    #[test]
+    fn markdown_files_yield_only_mentions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let readme = tmp.path().join("README.md");
+        let module = tmp.path().join("a.nix");
+        std::fs::write(&readme, "```nix\nregistry.home.alice\n```\n").unwrap();
+        std::fs::write(&module, "# registry.home.bob\nregistry.users.carol").unwrap();
+
+        let files = vec![readme, module];
+        let scans = scan_files(&files, &RegistryNames::new("registry"), None, None).unwrap();
+        assert!(scans[0].refs.is_empty() && scans[0].parse_errors.is_empty());
+        assert_eq!(scans[0].mentions[0].path, "home.alice");
+        assert_eq!(scans[1].refs[0].path, "users.carol");
+        assert_eq!(scans[1].mentions[0].path, "home.bob");
+    }
+
+    #[test]
+    fn missing_scan_path_is_an_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        let err =
+            collect_nix_files(&[tmp.path().join("nope")], &CollectOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("Path not found"));
+    }
+
+    #[test]
+    fn overlapping_paths_are_deduplicated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("testroot");
+        std::fs::create_dir_all(root.join("nix")).unwrap();
+        std::fs::write(root.join("nix/a.nix"), "{}").unwrap();
+        std::fs::write(root.join("b.nix"), "{}").unwrap();
+
+        let paths = vec![root.join("nix"), root.clone()];
+        let files = collect_nix_files(&paths, &CollectOptions::default())
+            .unwrap()
+            .paths();
+        assert_eq!(files, vec![root.join("nix/a.nix"), root.join("b.nix")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_skipped_unless_followed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("testroot");
+        let outside = tmp.path().join("outside");
+        std::fs::create_dir_all(root.join("real")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(root.join("real/a.nix"), "{}").unwrap();
+        std::fs::write(outside.join("b.nix"), "{}").unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("same")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("linked")).unwrap();
+
+        let files = collect_nix_files(std::slice::from_ref(&root), &CollectOptions::default())
+            .unwrap()
+            .paths();
+        assert_eq!(files, vec![root.join("real/a.nix")]);
+
+        // `same/a.nix` is the file already found through `real/`
+        let files = collect_nix_files(
+            std::slice::from_ref(&root),
+            &CollectOptions {
+                follow_symlinks: true,
+                ..CollectOptions::default()
+            },
+        )
+        .unwrap()
+        .paths();
+        assert_eq!(
+            files,
+            vec![root.join("linked/b.nix"), root.join("real/a.nix")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loops_are_reported() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("testroot");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/a.nix"), "{}").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+
+        let found = collect_nix_files(
+            std::slice::from_ref(&root),
+            &CollectOptions {
+                follow_symlinks: true,
+                ..CollectOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(found.paths(), vec![root.join("sub/a.nix")]);
+        assert_eq!(found.errors.len(), 1);
+        assert_eq!(found.errors[0].file, root.join("sub/loop"));
+        assert!(found.errors[0].message.contains("symlink loop"));
+    }
+
+    #[test]
+    fn scan_files_reuses_cached_scans() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.nix");
+        std::fs::write(&file, "{ x = registry.users.alice; }").unwrap();
+        let files = vec![file.clone()];
+        let names = RegistryNames::new("registry");
+        let mut cache = ScanCache::open(tmp.path().join("scan.json"));
+
+        scan_files(&files, &names, None, Some(&mut cache)).unwrap();
+        let hash = cache::content_hash(b"{ x = registry.users.alice; }");
+        assert!(
+            cache
+                .get(&file, &hash, &["registry"], None, false)
+                .is_some()
+        );
+
+        // A changed file is parsed again rather than served from the cache
+        std::fs::write(&file, "{ x = registry.users.bob; }").unwrap();
+        let scans = scan_files(&files, &names, None, Some(&mut cache)).unwrap();
+        assert_eq!(scans[0].refs[0].path, "users.bob");
+    }
+
+    #[test]
+    fn parse_errors_are_collected_with_location() {
+        let source = "{\n  a = registry.users.alice;\n  b = ;\n}";
+        let scan = scan_source(source, Path::new("broken.nix"), &["registry"]);
+        assert_eq!(scan.refs.len(), 1, "refs before the error are still found");
+        assert!(!scan.parse_errors.is_empty());
+
+        let err = &scan.parse_errors[0];
+        assert_eq!(err.file, Path::new("broken.nix"));
+        assert_eq!((err.line, err.column), (3, 7));
+        assert!(!err.message.contains(".."), "message has no byte offsets");
+    }
+
+    #[test]
+    fn valid_source_has_no_parse_errors() {
+        let scan = scan_source("{ a = registry.x; }", Path::new("ok.nix"), &["registry"]);
+        assert!(scan.parse_errors.is_empty());
+    }
+
+    // =========================================================================
+    // False positive tests - patterns that should NOT be detected
+    // =========================================================================
+
+    #[test]
+    fn ignores_string_containing_registry() {
+        let source = r#"npmRegistry = "https://registry.npmjs.org";"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(refs.is_empty(), "String literal should not match");
+    }
+
+    #[test]
+    fn ignores_registry_as_attrset_key() {
+        let source = r#"dockerConfig = { registry = "ghcr.io"; };"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(refs.is_empty(), "Attrset key definition should not match");
+    }
+
+    #[test]
+    fn ignores_select_on_other_ident_with_registry_attr() {
+        let source = r#"x = someModule.registry.path;"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(
+            refs.is_empty(),
+            "Select on non-registry ident should not match"
+        );
+    }
+
+    #[test]
+    fn ignores_config_nix_registry() {
+        let source = r#"nix.registry.nixpkgs.flake = inputs.nixpkgs;"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(refs.is_empty(), "nix.registry path should not match");
+    }
+
+    #[test]
+    fn ignores_registry_as_function_call() {
+        let source = r#"result = registry { arg = 1; };"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(refs.is_empty(), "Function call should not match");
+    }
+
+    #[test]
+    fn ignores_similar_ident_names() {
+        let source = r#"x = registryBackup.old.path;"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(
+            refs.is_empty(),
+            "Similar but different ident should not match"
+        );
+    }
+
+    #[test]
+    fn decodes_quoted_attr_access() {
+        // A quoted name containing a dot is a single attribute, so it stays quoted
+        let source = r#"{ foo = registry."home.alice"; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec![r#""home.alice""#]);
+    }
+
+    #[test]
+    fn ignores_inherit_pattern() {
+        let source = r#"{ inherit (inputs) registry; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(refs.is_empty(), "Inherit should not match");
+    }
+
+    #[test]
+    fn detects_inherit_from_registry() {
+        let source = r#"{ registry, ... }: { inherit (registry.users) alice bob; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["users.alice", "users.bob"]);
+    }
+
+    #[test]
+    fn detects_inherit_from_bare_registry() {
+        let source = r#"let inherit (registry) users "web-app"; in users"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["users", "web-app"]);
+    }
+
+    #[test]
+    fn inherit_from_registry_does_not_report_source_separately() {
+        let source = r#"{ inherit (registry.users.admins) alice; x = registry.users.bob; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["users.admins.alice", "users.bob"]);
+    }
+
+    #[test]
+    fn inherit_refs_point_at_source_and_attr() {
+        let source = "{ inherit (registry.users) alice; }";
+        let refs = scan_source(source, Path::new("test.nix"), &["registry"]).refs;
+        assert_eq!(refs.len(), 1);
+        assert_eq!(&source[refs[0].start_offset..refs[0].end_offset], "alice");
+        assert_eq!(refs[0].column, 28);
+        let RefKind::Inherit {
+            source_start,
+            source_end,
+            statement_start,
+            statement_end,
+        } = refs[0].kind
+        else {
+            panic!("expected inherit kind, got {:?}", refs[0].kind);
+        };
+        assert_eq!(&source[source_start..source_end], "registry.users");
+        assert_eq!(
+            &source[statement_start..statement_end],
+            "inherit (registry.users) alice;"
+        );
+    }
+
+    #[test]
+    fn inherit_from_shadowed_registry_is_skipped() {
+        let source = r#"{ f = registry: { inherit (registry.users) alice; }; }"#;
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+    }
+
+    #[test]
+    fn ignores_nested_attrpath_definition() {
+        // This is a definition, not a reference
+        let source = r#"{ container.registry.url = "docker.io"; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert!(
+            refs.is_empty(),
+            "Nested attrpath definition should not match"
+        );
+    }
+
+    // =========================================================================
+    // True positive tests - patterns that SHOULD be detected
+    // =========================================================================
+
+    #[test]
+    fn detects_registry_in_parentheses() {
+        let source = r#"{ a = (registry.path.one); }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["path.one"]);
+    }
+
+    #[test]
+    fn detects_registry_as_function_arg() {
+        let source = r#"{ b = lib.mkDefault registry.path.two; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["path.two"]);
+    }
+
+    #[test]
+    fn detects_multiple_registry_in_list() {
+        let source = r#"{ c = [ registry.path.three registry.path.four ]; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["path.three", "path.four"]);
+    }
+
+    #[test]
+    fn detects_registry_in_attrset_value() {
+        let source = r#"{ d = { key = registry.path.five; }; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["path.five"]);
+    }
+
+    #[test]
+    fn detects_registry_with_or_default() {
+        let source = r#"{ e = registry.path.six or null; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["path.six"]);
+    }
+
+    #[test]
+    fn detects_registry_with_merge_operator() {
+        let source = r#"{ f = { } // registry.path.seven; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["path.seven"]);
+    }
+
+    #[test]
+    fn detects_registry_in_rec_attrset() {
+        let source = r#"{ h = rec { val = registry.path.nine; }; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["path.nine"]);
+    }
+
+    #[test]
+    fn detects_registry_in_let_binding() {
+        let source = r#"let x = registry.users.alice; in x"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["users.alice"]);
+    }
+
+    #[test]
+    fn detects_registry_in_import_list() {
+        let source = r#"{ imports = [ registry.hosts.desktop registry.modules.base ]; }"#;
+        let refs = extract_paths_from_source(source, "registry");
+        assert_eq!(refs, vec!["hosts.desktop", "modules.base"]);
+    }
+
+    // =========================================================================
+    // Quoted and interpolated segment tests
+    // =========================================================================
+
+    fn refs_from_source(source: &str) -> Vec<RegistryRef> {
+        scan_source(source, Path::new("test.nix"), &["registry"]).refs
+    }
+
+    #[test]
+    fn references_carry_end_position_and_column_units() {
+        let source = "{\n  \"é\" = registry.users\n    .alice;\n}";
+        let refs = refs_from_source(source);
+        let r = &refs[0];
+        assert_eq!((r.line, r.column), (2, 9));
+        assert_eq!((r.end_line, r.end_column), (3, 11));
+        assert_eq!(r.span.start.byte_column, 10);
+        assert_eq!(r.span.start.utf16_column, 9);
+        assert_eq!(r.span.end.byte_column, 11);
+    }
+
+    #[test]
+    fn decodes_string_segments() {
+        let source = r#"registry."web-app".nginx"#;
+        assert_eq!(
+            extract_paths_from_source(source, "registry"),
+            vec!["web-app.nginx"]
+        );
+    }
+
+    #[test]
+    fn decodes_escapes_in_string_segments() {
+        let source = r#"registry.services."my\"app".x"#;
+        assert_eq!(
+            extract_paths_from_source(source, "registry"),
+            vec![r#"services."my\"app".x"#]
+        );
+    }
+
+    #[test]
+    fn decodes_constant_interpolation_as_static() {
+        let source = r#"registry.${"home"}.alice"#;
+        let refs = refs_from_source(source);
+        assert_eq!(refs[0].path, "home.alice");
+        assert!(!refs[0].is_dynamic());
+    }
+
+    #[test]
+    fn dynamic_segment_keeps_static_prefix() {
+        let source = r#"registry.users.${name}.shell"#;
+        let refs = refs_from_source(source);
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].path, "users");
+        assert!(refs[0].is_dynamic());
+        assert_eq!(refs[0].display_path(), "users.${name}.shell");
+        assert_eq!(
+            refs[0].segments,
+            vec![
+                PathSegment::Static("users".to_string()),
+                PathSegment::Dynamic("${name}".to_string()),
+                PathSegment::Static("shell".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn interpolated_string_segment_is_dynamic() {
+        let source = r#"registry.users."${name}-home""#;
+        let refs = refs_from_source(source);
+        assert_eq!(refs[0].path, "users");
+        assert_eq!(refs[0].display_path(), r#"users."${name}-home""#);
+    }
+
+    #[test]
+    fn leading_dynamic_segment_is_skipped() {
+        let source = r#"registry.${name}.alice"#;
+        let scan = scan_source(source, Path::new("test.nix"), &["registry"]);
+        assert!(scan.refs.is_empty());
+        assert_eq!(scan.skipped.len(), 1);
+        assert_eq!(
+            scan.skipped[0].reason,
+            "dynamic reference without static prefix"
+        );
+    }
+
+    #[test]
+    fn span_excludes_or_default() {
+        let source = "registry.a.b or null";
+        let refs = refs_from_source(source);
+        assert_eq!(refs[0].start_offset, 0);
+        assert_eq!(refs[0].end_offset, "registry.a.b".len());
+    }
+
+    // =========================================================================
+    // `with registry;` scope tests
+    // =========================================================================
+
+    #[test]
+    fn detects_selects_inside_with_registry() {
+        let source = r#"
+            { registry, ... }:
+            { imports = with registry; [ users.alice profiles.desktop ]; }
+        "#;
+        let refs = refs_from_source(source);
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["users.alice", "profiles.desktop"]);
+        assert!(
+            refs.iter()
+                .all(|r| matches!(r.kind, RefKind::ViaWith { .. }))
+        );
+    }
+
+    #[test]
+    fn detects_bare_idents_inside_with_registry_prefix() {
+        let source = "with registry.profiles; [ desktop server ]";
+        let refs = refs_from_source(source);
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        // The header is itself a reference
+        assert_eq!(
+            paths,
+            vec!["profiles", "profiles.desktop", "profiles.server"]
+        );
+        assert_eq!(refs[0].kind, RefKind::Select);
+        assert_eq!(
+            refs[1].kind,
+            RefKind::ViaWith {
+                header_start: 5,
+                header_end: 22,
+                prefix: "profiles".to_string(),
+            }
+        );
+        assert_eq!(&source[refs[1].start_offset..refs[1].end_offset], "desktop");
+    }
+
+    #[test]
+    fn with_ignores_lexically_bound_names() {
+        let source = "{ registry, lib, ... }: with registry; [ lib.foo (x: x.y) ]";
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+    }
+
+    #[test]
+    fn with_ignores_nix_globals() {
+        let source = "with registry; map toString [ true null builtins.x ]";
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+    }
+
+    #[test]
+    fn with_ignores_attr_names_and_params() {
+        let source = "x: with registry; { a = 1; b = { c, ... }: c; inherit (x) d; }";
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+    }
+
+    #[test]
+    fn inner_non_registry_with_hides_registry_with() {
+        let source = "lib: with registry; with lib; [ foo ]";
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+    }
+
+    #[test]
+    fn registry_with_inside_other_with_is_ambiguous() {
+        let source = "pkgs: with pkgs; with registry; [ hello tools.git ]";
+        let scan = scan_source(source, Path::new("test.nix"), &["registry"]);
+        assert!(scan.refs.is_empty());
+        let skipped: Vec<_> = scan
+            .skipped
+            .iter()
+            .map(|s| (s.reference.path.as_str(), s.reason.as_str()))
+            .collect();
+        assert_eq!(
+            skipped,
+            vec![
+                ("hello", "ambiguous with scope"),
+                ("tools.git", "ambiguous with scope")
+            ]
+        );
+
+        // Either order: the inner `with pkgs;` may supply the name too
+        let source = "pkgs: with registry; with pkgs; [ hello tools.git ]";
+        let scan = scan_source(source, Path::new("test.nix"), &["registry"]);
+        assert!(scan.refs.is_empty() && scan.skipped.is_empty());
+    }
+
+    #[test]
+    fn with_on_shadowed_registry_is_ignored() {
+        let source = "{ f = registry: with registry; [ users.alice ]; }";
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+    }
+
+    #[test]
+    fn plain_with_registry_select_still_detected() {
+        let source = "with lib; mkIf true registry.hosts.server";
+        assert_eq!(
+            extract_paths_from_source(source, "registry"),
+            vec!["hosts.server"]
+        );
+    }
+
+    // =========================================================================
+    // Alias tracking tests
+    // =========================================================================
+
+    #[test]
+    fn follows_let_alias() {
+        let source = "let users = registry.users; in [ users.alice users.bob ]";
+        let refs = refs_from_source(source);
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["users", "users.alice", "users.bob"]);
+        assert_eq!(
+            refs[1].kind,
+            RefKind::Alias {
+                binding_start: 12,
+                binding_end: 26,
+                name: "users".to_string(),
+                prefix: "users".to_string(),
+            }
+        );
+        assert_eq!(
+            &source[refs[1].start_offset..refs[1].end_offset],
+            "users.alice"
+        );
+    }
+
+    #[test]
+    fn follows_chained_aliases_in_any_order() {
+        let source = "let a = u.alice; u = registry.users; in a.programs.editor";
+        assert_eq!(
+            extract_paths_from_source(source, "registry"),
+            vec!["users.alice", "users", "users.alice.programs.editor"]
+        );
+    }
+
+    #[test]
+    fn follows_alias_of_whole_registry() {
+        let source = "let r = registry; in r.users.alice";
+        assert_eq!(
+            extract_paths_from_source(source, "registry"),
+            vec!["users.alice"]
+        );
+    }
+
+    #[test]
+    fn follows_rec_attrset_alias() {
+        let source = "rec { users = registry.users; alice = users.alice; }";
+        assert_eq!(
+            extract_paths_from_source(source, "registry"),
+            vec!["users", "users.alice"]
+        );
+    }
+
+    #[test]
+    fn follows_inherit_alias() {
+        let source = "let inherit (registry) users; in users.alice";
+        let refs = refs_from_source(source);
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["users", "users.alice"]);
+        let RefKind::Alias { binding_start, .. } = refs[1].kind else {
+            panic!("expected alias kind, got {:?}", refs[1].kind);
+        };
+        assert_eq!(binding_start, refs[0].start_offset);
+    }
+
+    #[test]
+    fn follows_pattern_default_alias() {
+        let source =
+            "{ registry, ... }: let f = { u ? registry.users, a ? u.alice }: a.shell; in f";
+        let refs = refs_from_source(source);
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["users", "users.alice", "users.alice.shell"]);
+        assert!(matches!(&refs[2].kind, RefKind::Alias { name, .. } if name == "a"));
+    }
+
+    #[test]
+    fn alias_shadowed_by_lambda_is_ignored() {
+        let source = "let u = registry.users; in (u: u.x) 1";
+        assert_eq!(extract_paths_from_source(source, "registry"), vec!["users"]);
+    }
+
+    #[test]
+    fn alias_of_shadowed_registry_is_ignored() {
+        let source = "{ f = registry: let u = registry.users; in u.x; }";
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+    }
+
+    #[test]
+    fn non_registry_let_values_are_not_aliases() {
+        let source = "let u = lib.users; d = registry.${x}; in [ u.alice d.y ]";
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+    }
+
+    // =========================================================================
+    // String-list lookup tests
+    // =========================================================================
+
+    #[test]
+    fn detects_attr_by_path_lookup() {
+        let source = r#"{ x = lib.attrByPath [ "home" "alice" ] null registry; }"#;
+        let refs = refs_from_source(source);
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].path, "home.alice");
+        assert!(matches!(
+            &refs[0].kind,
+            RefKind::Lookup { elements, list: Some(_), prefix, .. }
+                if elements.len() == 2 && prefix.is_empty()
+        ));
+    }
+
+    #[test]
+    fn detects_get_attr_from_path_and_get_attr() {
+        let source = r#"{
+          a = lib.attrsets.getAttrFromPath [ "users" "bob" ] registry;
+          b = builtins.getAttr "alice" registry.home;
+          c = getAttr "carol" (registry.home);
+        }"#;
+        let paths: Vec<_> = refs_from_source(source)
+            .into_iter()
+            .filter(|r| matches!(r.kind, RefKind::Lookup { .. }))
+            .map(|r| r.path)
+            .collect();
+        assert_eq!(paths, vec!["users.bob", "home.alice", "home.carol"]);
+    }
+
+    #[test]
+    fn lookup_target_select_is_also_reported() {
+        let source = r#"{ b = builtins.getAttr "alice" registry.home; }"#;
+        let paths: Vec<_> = refs_from_source(source)
+            .into_iter()
+            .map(|r| r.path)
+            .collect();
+        assert_eq!(paths, vec!["home.alice", "home"]);
+    }
+
+    #[test]
+    fn ignores_lookups_with_non_literal_elements() {
+        let source = r#"{
+          a = lib.attrByPath [ "home" name ] null registry;
+          b = lib.getAttrFromPath path registry;
+          c = builtins.getAttr "${name}" registry;
+          d = lib.getAttrFromPath [ "home" ] pkgs;
+          e = other.getAttr "alice" registry;
+          f = lib.foo.getAttr "alice" registry;
+          g = builtins.attrsets.getAttr "alice" registry;
+        }"#;
+        let lookups: Vec<_> = refs_from_source(source)
+            .into_iter()
+            .filter(|r| matches!(r.kind, RefKind::Lookup { .. }))
+            .collect();
+        assert!(lookups.is_empty());
     }
 
     #[test]
-    fn extracts_from_config_b_fixture() {
-        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests/fixtures/migrate-test/outputs/config-b.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
-        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
-        assert_eq!(paths, vec!["users.alice", "mods.nixos"]);
+    fn lookup_on_shadowed_registry_is_skipped() {
+        let source = r#"let registry = { }; in lib.attrByPath [ "a" ] null registry"#;
+        let skipped = skipped_paths_from_source(source);
+        assert_eq!(skipped, vec!["a"]);
     }
 
+    // =========================================================================
+    // Guarded reference tests
+    // =========================================================================
+
     #[test]
-    fn extracts_from_mixed_fixture() {
-        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests/fixtures/migrate-test/outputs/mixed.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
-        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
-        assert_eq!(paths, vec!["hosts.server", "users.bob"]);
+    fn detects_has_attr_checks() {
+        let source = r#"{
+          a = registry ? home.alice;
+          b = if registry.users ? "bob" then 1 else 2;
+        }"#;
+        let checks: Vec<_> = refs_from_source(source)
+            .into_iter()
+            .filter(|r| matches!(r.kind, RefKind::HasAttr { .. }))
+            .collect();
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0].path, "home.alice");
+        assert_eq!(checks[1].path, "users.bob");
+        assert!(checks.iter().all(|r| r.guarded));
+        assert!(matches!(
+            &checks[1].kind,
+            RefKind::HasAttr { prefix, .. } if prefix == "users"
+        ));
     }
 
     #[test]
-    fn extracts_from_deep_nesting_fixture() {
-        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests/fixtures/complex-renames/files/deep-nesting.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
-        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+    fn or_defaults_and_attr_by_path_are_guarded() {
+        let source = r#"{
+          a = registry.home.alice or null;
+          b = registry.home.bob;
+          c = lib.attrByPath [ "home" "carol" ] null registry;
+          d = lib.getAttrFromPath [ "home" "dave" ] registry;
+        }"#;
+        let guarded: Vec<_> = refs_from_source(source)
+            .into_iter()
+            .map(|r| (r.path, r.guarded))
+            .collect();
         assert_eq!(
-            paths,
+            guarded,
             vec![
-                "svc.db.postgresql",
-                "svc.db.redis",
-                "svc.http.nginx",
-                "svc.http.caddy",
-                "utils.helpers.strings",
+                ("home.alice".to_string(), true),
+                ("home.bob".to_string(), false),
+                ("home.carol".to_string(), true),
+                ("home.dave".to_string(), false),
             ]
         );
     }
 
     #[test]
-    fn extracts_from_ambiguous_fixture() {
-        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests/fixtures/complex-renames/files/ambiguous.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
-        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+    fn or_default_is_not_part_of_the_span() {
+        let source = "{ a = registry.home.alice or null; }";
+        let refs = refs_from_source(source);
         assert_eq!(
-            paths,
-            vec![
-                "old.programs.editor",
-                "old.desktop.gnome",
-                "config.server.minimal",
-                "configs.base",
-            ]
+            &source[refs[0].start_offset..refs[0].end_offset],
+            "registry.home.alice"
         );
     }
 
     #[test]
-    fn extracts_from_partial_valid_fixture() {
-        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests/fixtures/complex-renames/files/partial-valid.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
-        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
-        assert_eq!(
-            paths,
-            vec![
-                "users.alice.programs.editor",
-                "services.database.postgresql",
-                "profiles.desktop.gnome",
-                "home.bob.shell",
-                "svc.web.caddy",
-            ]
-        );
+    fn ignores_has_attr_on_other_values() {
+        let source = "{ a = pkgs ? hello; b = registry.${x} ? y; }";
+        let checks: Vec<_> = refs_from_source(source)
+            .into_iter()
+            .filter(|r| matches!(r.kind, RefKind::HasAttr { .. }))
+            .collect();
+        assert!(checks.is_empty());
     }
 
-    #[test]
-    fn extracts_from_all_valid_fixture() {
-        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests/fixtures/complex-renames/files/all-valid.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
-        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
-        assert_eq!(
-            paths,
-            vec![
-                "users.alice.programs.editor",
-                "users.alice.programs.zsh",
-                "users.bob.shell",
-                "services.database.postgresql",
-                "services.web.nginx",
-                "profiles.desktop.gnome",
-                "lib.helpers.strings",
-            ]
-        );
+    // =========================================================================
+    // Path import tests
+    // =========================================================================
+
+    fn path_refs(source: &str) -> Vec<RegistryRef> {
+        let file = Path::new("hosts/server/default.nix");
+        scan_source_with_dir(source, file, &["registry"], Some(Path::new("registry"))).refs
     }
 
     #[test]
-    fn collects_nix_files_from_complex_renames() {
-        let fixture_dir =
-            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/complex-renames/files");
-        let files = collect_nix_files(&[fixture_dir], &[], true).unwrap();
-        assert_eq!(files.len(), 6);
+    fn detects_relative_path_imports_into_registry() {
+        let refs = path_refs("{ imports = [ ../../registry/home/alice ]; }");
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].path, "home.alice");
+        assert_eq!(refs[0].base, "registry");
+        let RefKind::Path {
+            target,
+            prefix,
+            suffix,
+        } = &refs[0].kind
+        else {
+            panic!("expected a path reference");
+        };
+        assert!(target.ends_with("registry/home/alice"));
+        assert_eq!(prefix, "../../registry");
+        assert_eq!(suffix, "");
     }
 
     #[test]
-    fn exclude_pattern_filters_by_name() {
-        let fixture_dir =
-            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/complex-renames/files");
-        let files =
-            collect_nix_files(&[fixture_dir], &["ambiguous.nix".to_string()], true).unwrap();
-        assert_eq!(files.len(), 5);
+    fn path_imports_map_nix_files_to_attributes() {
+        let refs = path_refs(
+            "{ imports = [ ../../registry/home/alice.nix ../../registry/svc/db/default.nix ]; }",
+        );
+        let found: Vec<_> = refs
+            .iter()
+            .map(|r| match &r.kind {
+                RefKind::Path { suffix, .. } => (r.path.as_str(), suffix.as_str()),
+                _ => panic!("expected a path reference"),
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![("home.alice", ".nix"), ("svc.db", "/default.nix")]
+        );
     }
 
     #[test]
-    fn exclude_pattern_with_glob() {
-        let fixture_dir =
-            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/complex-renames/files");
-        let files = collect_nix_files(&[fixture_dir], &["*valid*".to_string()], true).unwrap();
-        // Excludes all-valid.nix and partial-valid.nix
-        assert_eq!(files.len(), 4);
+    fn ignores_paths_outside_registry() {
+        let refs = path_refs(
+            "{ imports = [ ./hardware.nix ../../registry ../../registry/${x} <nixpkgs> /etc/nixos ]; }",
+        );
+        assert!(refs.is_empty());
     }
 
     #[test]
-    fn no_default_excludes_includes_dotfiles() {
-        let tmp = tempfile::tempdir().unwrap();
-        let testdir = tmp.path().join("testroot");
-        std::fs::create_dir(&testdir).unwrap();
-        let dotdir = testdir.join(".hidden");
-        std::fs::create_dir(&dotdir).unwrap();
-        std::fs::write(dotdir.join("test.nix"), "{}").unwrap();
-        std::fs::write(testdir.join("visible.nix"), "{}").unwrap();
+    fn path_imports_need_registry_dir() {
+        let source = "{ imports = [ ../../registry/home/alice ]; }";
+        let refs = scan_source(source, Path::new("hosts/server/default.nix"), &["registry"]).refs;
+        assert!(refs.is_empty());
+    }
 
-        // With defaults, hidden dir is excluded
-        let files = collect_nix_files(&[testdir.clone()], &[], true).unwrap();
-        assert_eq!(files.len(), 1);
+    // =========================================================================
+    // Multiple registry name tests
+    // =========================================================================
 
-        // Without defaults, hidden dir is included
-        let files = collect_nix_files(&[testdir], &[], false).unwrap();
-        assert_eq!(files.len(), 2);
+    #[test]
+    fn detects_any_of_several_registry_names() {
+        let source = "{ registry, reg, ... }: [ registry.users.alice reg.users.bob ]";
+        let refs = scan_source(source, Path::new("test.nix"), &["registry", "reg"]).refs;
+        let found: Vec<_> = refs
+            .iter()
+            .map(|r| (r.base.as_str(), r.path.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![("registry", "users.alice"), ("reg", "users.bob")]
+        );
     }
 
     #[test]
-    fn no_default_excludes_includes_underscored() {
-        let tmp = tempfile::tempdir().unwrap();
-        let testdir = tmp.path().join("testroot");
-        std::fs::create_dir(&testdir).unwrap();
-        std::fs::write(testdir.join("_internal.nix"), "{}").unwrap();
-        std::fs::write(testdir.join("public.nix"), "{}").unwrap();
-
-        // With defaults, underscore-prefixed is excluded
-        let files = collect_nix_files(&[testdir.clone()], &[], true).unwrap();
-        assert_eq!(files.len(), 1);
-
-        // Without defaults, underscore-prefixed is included
-        let files = collect_nix_files(&[testdir], &[], false).unwrap();
-        assert_eq!(files.len(), 2);
+    fn alias_and_with_refs_keep_their_registry_name() {
+        let source = "let u = reg.users; in [ u.alice (with reg; hosts.a) ]";
+        let refs = scan_source(source, Path::new("test.nix"), &["registry", "reg"]).refs;
+        assert!(refs.iter().all(|r| r.base == "reg"));
+        assert_eq!(refs.len(), 3);
     }
 
     // =========================================================================
-    // False positive tests - patterns that should NOT be detected
+    // Dotted registry base tests
     // =========================================================================
 
-    #[test]
-    fn ignores_string_containing_registry() {
-        let source = r#"npmRegistry = "https://registry.npmjs.org";"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(refs.is_empty(), "String literal should not match");
+    fn dotted_refs(source: &str) -> Vec<(String, String)> {
+        scan_source(source, Path::new("flake.nix"), &["self.registry"])
+            .refs
+            .into_iter()
+            .map(|r| (r.base, r.path))
+            .collect()
     }
 
     #[test]
-    fn ignores_registry_as_attrset_key() {
-        let source = r#"dockerConfig = { registry = "ghcr.io"; };"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(refs.is_empty(), "Attrset key definition should not match");
+    fn detects_dotted_registry_base() {
+        let source = r#"{
+          outputs = { self, ... }: {
+            a = self.registry.users.alice;
+            b = self.packages.x86_64-linux.default;
+          };
+        }"#;
+        assert_eq!(
+            dotted_refs(source),
+            vec![("self.registry".to_string(), "users.alice".to_string())]
+        );
     }
 
     #[test]
-    fn ignores_select_on_other_ident_with_registry_attr() {
-        let source = r#"x = someModule.registry.path;"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(
-            refs.is_empty(),
-            "Select on non-registry ident should not match"
+    fn dotted_base_in_inherit_with_and_alias() {
+        let source = r#"{ self, ... }: let u = self.registry.users; in {
+          inherit (self.registry.hosts) server;
+          a = with self.registry; profiles.desktop;
+          b = u.alice;
+          c = self.registry ? home.bob;
+        }"#;
+        let paths: Vec<_> = dotted_refs(source).into_iter().map(|(_, p)| p).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "users",
+                "hosts.server",
+                "profiles.desktop",
+                "users.alice",
+                "home.bob"
+            ]
         );
     }
 
     #[test]
-    fn ignores_config_nix_registry() {
-        let source = r#"nix.registry.nixpkgs.flake = inputs.nixpkgs;"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(refs.is_empty(), "nix.registry path should not match");
+    fn dotted_base_requires_every_component() {
+        let source =
+            "{ a = inputs.self.registry.users.alice; b = self.registryX.a; c = registry.a; }";
+        let refs = scan_source(
+            source,
+            Path::new("flake.nix"),
+            &["self.registry", "inputs.self.registry"],
+        )
+        .refs;
+        let found: Vec<_> = refs
+            .iter()
+            .map(|r| (r.base.as_str(), r.path.as_str()))
+            .collect();
+        assert_eq!(found, vec![("inputs.self.registry", "users.alice")]);
     }
 
     #[test]
-    fn ignores_registry_as_function_call() {
-        let source = r#"result = registry { arg = 1; };"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(refs.is_empty(), "Function call should not match");
+    fn dotted_base_keeps_nix_registry_protection() {
+        let source = r#"{ config, ... }: {
+          nix.registry.nixpkgs.flake = inputs.nixpkgs;
+          x = config.nix.registry.nixpkgs;
+          y = config.imp.registry.users.alice;
+        }"#;
+        let refs = scan_source(source, Path::new("a.nix"), &["config.imp.registry"]).refs;
+        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["users.alice"]);
     }
 
     #[test]
-    fn ignores_similar_ident_names() {
-        let source = r#"x = registryBackup.old.path;"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(
-            refs.is_empty(),
-            "Similar but different ident should not match"
+    fn dotted_base_shadowed_by_let() {
+        let source = "let self = { registry.a = 1; }; in self.registry.a";
+        let skipped: Vec<_> = scan_source(source, Path::new("flake.nix"), &["self.registry"])
+            .skipped
+            .into_iter()
+            .map(|s| s.reference.path)
+            .collect();
+        assert_eq!(skipped, vec!["a"]);
+    }
+
+    #[test]
+    fn registry_names_scoped_by_glob() {
+        let mut names = RegistryNames::new("registry");
+        names.add_scoped("home/**", "reg").unwrap();
+        assert_eq!(
+            names.for_file(Path::new("./home/alice/default.nix")),
+            vec!["registry", "reg"]
+        );
+        assert_eq!(
+            names.for_file(Path::new("hosts/server.nix")),
+            vec!["registry"]
         );
     }
 
     #[test]
-    fn ignores_quoted_attr_access() {
-        // Dynamic attribute access with quotes
-        let source = r#"foo = registry."home.alice";"#;
-        let refs = extract_paths_from_source(source, "registry");
-        // This may or may not match depending on rnix parsing - document behavior
-        // The key point is it shouldn't crash and behavior should be consistent
-        assert!(refs.is_empty() || refs.len() == 1);
+    fn registry_name_globs_match_relative_to_scan_root() {
+        let mut names = RegistryNames::new("registry");
+        names.add_scoped("home/**", "reg").unwrap();
+        names.set_roots(&[PathBuf::from("/abs/repo"), PathBuf::from("../other")]);
+        assert_eq!(
+            names.for_file(Path::new("/abs/repo/home/alice.nix")),
+            vec!["registry", "reg"]
+        );
+        assert_eq!(
+            names.for_file(Path::new("../other/home/bob.nix")),
+            vec!["registry", "reg"]
+        );
+        assert_eq!(
+            names.for_file(Path::new("/elsewhere/home/carol.nix")),
+            vec!["registry"]
+        );
     }
 
     #[test]
-    fn ignores_inherit_pattern() {
-        let source = r#"{ inherit (inputs) registry; }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(refs.is_empty(), "Inherit should not match");
+    fn registry_like_arguments_are_checked_in_their_module() {
+        let tmp = tempfile::tempdir().unwrap();
+        let module = tmp.path().join("module.nix");
+        let other = tmp.path().join("other.nix");
+        std::fs::write(&module, "{ reg, flakeRegistry, ... }: [ reg.home.alice ]").unwrap();
+        std::fs::write(&other, "{ ... }: [ reg.home.bob flakeRegistry.x ]").unwrap();
+        let paren = tmp.path().join("paren.nix");
+        std::fs::write(&paren, "({ reg, ... }: [ reg.home.carol ])").unwrap();
+        let files = vec![module, other, paren];
+
+        let mut names = RegistryNames::new("registry");
+        let scans = scan_files(&files, &names, None, None).unwrap();
+        assert!(scans.iter().all(|scan| scan.refs.is_empty()));
+        assert_eq!(scans[0].module_args, vec!["reg", "flakeRegistry"]);
+
+        names.set_infer_args(true);
+        let scans = scan_files(&files, &names, None, None).unwrap();
+        let paths: Vec<_> = scans[0].refs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["home.alice"]);
+        assert!(scans[1].refs.is_empty());
+        assert_eq!(scans[2].refs[0].path, "home.carol");
     }
 
     #[test]
-    fn ignores_nested_attrpath_definition() {
-        // This is a definition, not a reference
-        let source = r#"{ container.registry.url = "docker.io"; }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert!(
-            refs.is_empty(),
-            "Nested attrpath definition should not match"
-        );
+    fn registry_names_reject_invalid_glob() {
+        let mut names = RegistryNames::new("registry");
+        let err = names.add_scoped("home/[", "reg").unwrap_err();
+        assert!(err.to_string().contains("home/["));
     }
 
     // =========================================================================
-    // True positive tests - patterns that SHOULD be detected
+    // Scope resolution tests
     // =========================================================================
 
+    fn skipped_paths_from_source(source: &str) -> Vec<String> {
+        scan_source(source, Path::new("test.nix"), &["registry"])
+            .skipped
+            .into_iter()
+            .map(|s| s.reference.path)
+            .collect()
+    }
+
     #[test]
-    fn detects_registry_in_parentheses() {
-        let source = r#"{ a = (registry.path.one); }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["path.one"]);
+    fn detects_top_level_module_argument() {
+        let source = r#"{ registry, ... }: { x = (y: registry.users.alice) 1; }"#;
+        assert_eq!(
+            extract_paths_from_source(source, "registry"),
+            vec!["users.alice"]
+        );
     }
 
     #[test]
-    fn detects_registry_as_function_arg() {
-        let source = r#"{ b = lib.mkDefault registry.path.two; }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["path.two"]);
+    fn detects_through_curried_top_level_lambdas() {
+        let source = r#"{ inputs }: { registry, ... }: registry.users.alice"#;
+        assert_eq!(
+            extract_paths_from_source(source, "registry"),
+            vec!["users.alice"]
+        );
     }
 
     #[test]
-    fn detects_multiple_registry_in_list() {
-        let source = r#"{ c = [ registry.path.three registry.path.four ]; }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["path.three", "path.four"]);
+    fn skips_inner_lambda_parameter() {
+        let source = r#"{ registry, ... }: { f = registry: registry.data.items; }"#;
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+        assert_eq!(skipped_paths_from_source(source), vec!["data.items"]);
     }
 
     #[test]
-    fn detects_registry_in_attrset_value() {
-        let source = r#"{ d = { key = registry.path.five; }; }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["path.five"]);
+    fn skips_inner_pattern_parameter() {
+        let source = r#"{ f = { registry, port }: registry.endpoint; }"#;
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+        assert_eq!(skipped_paths_from_source(source), vec!["endpoint"]);
     }
 
     #[test]
-    fn detects_registry_with_or_default() {
-        let source = r#"{ e = registry.path.six or null; }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["path.six"]);
+    fn skips_pattern_bind() {
+        let source = r#"{ f = registry@{ ... }: registry.endpoint; }"#;
+        assert_eq!(skipped_paths_from_source(source), vec!["endpoint"]);
     }
 
     #[test]
-    fn detects_registry_with_merge_operator() {
-        let source = r#"{ f = { } // registry.path.seven; }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["path.seven"]);
+    fn skips_let_shadowing() {
+        let source = r#"let registry = { x = 1; }; in registry.x"#;
+        assert!(extract_paths_from_source(source, "registry").is_empty());
+        assert_eq!(skipped_paths_from_source(source), vec!["x"]);
     }
 
     #[test]
-    fn detects_registry_in_rec_attrset() {
-        let source = r#"{ h = rec { val = registry.path.nine; }; }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["path.nine"]);
+    fn let_shadowing_applies_to_its_own_bindings() {
+        let source = r#"let registry = { }; y = registry.a.b; in y"#;
+        assert_eq!(skipped_paths_from_source(source), vec!["a.b"]);
     }
 
     #[test]
-    fn detects_registry_in_let_binding() {
-        let source = r#"let x = registry.users.alice; in x"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["users.alice"]);
+    fn skips_rec_attrset_shadowing() {
+        let source = r#"rec { registry = { }; y = registry.a.b; }"#;
+        assert_eq!(skipped_paths_from_source(source), vec!["a.b"]);
     }
 
     #[test]
-    fn detects_registry_in_import_list() {
-        let source = r#"{ imports = [ registry.hosts.desktop registry.modules.base ]; }"#;
-        let refs = extract_paths_from_source(source, "registry");
-        assert_eq!(refs, vec!["hosts.desktop", "modules.base"]);
+    fn plain_attrset_key_does_not_shadow() {
+        let source = r#"{ registry, ... }: { registry = { }; y = registry.a.b; }"#;
+        assert_eq!(extract_paths_from_source(source, "registry"), vec!["a.b"]);
+    }
+
+    #[test]
+    fn shadowing_ends_with_scope() {
+        let source = r#"{ registry, ... }: [ (let registry = 1; in registry.x) registry.a.b ]"#;
+        assert_eq!(extract_paths_from_source(source, "registry"), vec!["a.b"]);
+        assert_eq!(skipped_paths_from_source(source), vec!["x"]);
+    }
+
+    #[test]
+    fn inherit_from_shadows_registry() {
+        let source = r#"let inherit (inputs) registry; in registry.from.inputs"#;
+        assert_eq!(skipped_paths_from_source(source), vec!["from.inputs"]);
+    }
+
+    #[test]
+    fn plain_inherit_keeps_registry() {
+        let source = r#"{ registry, ... }: let inherit registry; in registry.users.alice"#;
+        assert_eq!(
+            extract_paths_from_source(source, "registry"),
+            vec!["users.alice"]
+        );
     }
 
     // =========================================================================
@@ -635,7 +3489,7 @@ mod tests {
     fn fixture_should_ignore_finds_no_refs() {
         let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests/fixtures/false-positives/should-ignore.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
         assert!(
             refs.is_empty(),
             "Expected no refs in should-ignore.nix, found: {:?}",
@@ -644,40 +3498,35 @@ mod tests {
     }
 
     #[test]
-    fn fixture_known_limitations_documents_false_positives() {
-        // This test documents a known limitation: function parameter shadowing.
-        // When a function has a parameter named `registry`, any attribute access
-        // on that parameter will be detected as a registry reference.
-        // This is expected behavior given static analysis constraints.
+    fn fixture_shadowed_params_skips_local_bindings() {
+        // Function parameters named `registry` shadow the module argument, so
+        // selects on them are reported as skipped instead of as references.
         let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("tests/fixtures/false-positives/known-limitations.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
-        let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
-
-        // These WILL be detected even though they're local params, not imp registry
-        assert!(
-            paths.contains(&"endpoint"),
-            "Expected false positive: endpoint"
-        );
-        assert!(
-            paths.contains(&"settings.base"),
-            "Expected false positive: settings.base"
-        );
+            .join("tests/fixtures/false-positives/shadowed-params.nix");
+        let scan = scan_file(&fixture, &["registry"]).unwrap();
         assert!(
-            paths.contains(&"data.items"),
-            "Expected false positive: data.items"
+            scan.refs.is_empty(),
+            "Expected no refs, found: {:?}",
+            scan.refs.iter().map(|r| &r.path).collect::<Vec<_>>()
         );
-        assert!(
-            paths.contains(&"nested.value"),
-            "Expected false positive: nested.value"
+
+        let skipped: Vec<_> = scan
+            .skipped
+            .iter()
+            .map(|s| s.reference.path.as_str())
+            .collect();
+        assert_eq!(
+            skipped,
+            vec!["endpoint", "settings.base", "data.items", "nested.value"]
         );
+        assert!(scan.skipped.iter().all(|s| s.reason == "local binding"));
     }
 
     #[test]
     fn fixture_should_detect_finds_expected_refs() {
         let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests/fixtures/false-positives/should-detect.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
         let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
 
         // Should find all the actual registry references
@@ -700,6 +3549,10 @@ mod tests {
             paths.contains(&"modules.nixos.base"),
             "Missing modules.nixos.base"
         );
+        assert!(
+            paths.contains(&"users.bob.shell"),
+            "Missing users.bob.shell through a pattern default"
+        );
 
         // Should have a reasonable number of refs (not too many false positives)
         assert!(
@@ -718,7 +3571,7 @@ mod tests {
     fn fixture_edge_cases_correct_detection() {
         let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("tests/fixtures/false-positives/edge-cases.nix");
-        let refs = extract_registry_refs(&fixture, "registry").unwrap();
+        let refs = extract_registry_refs(&fixture, &["registry"]).unwrap();
         let paths: Vec<_> = refs.iter().map(|r| r.path.as_str()).collect();
 
         // SHOULD detect these (registry is the base ident being selected from)
@@ -0,0 +1,178 @@
+//! Where the registry's attribute tree comes from.
+//!
+//! Every backend yields the same JSON shape as [`registry::evaluate`]: nested
+//! objects for attrsets, and for leaves either a `{ "__kind": ... }` marker or
+//! any other value, as in plain `nix eval --json .#registry` output. Evaluating the
+//! flake is the reference, but needs Nix and is slow; CI can evaluate once,
+//! commit or pass around the output, and let every other job read the
+//! snapshot instead.
+
+use crate::registry;
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::PathBuf;
+
+/// A backend the registry tree can be loaded from.
+pub trait RegistrySource: Send + Sync {
+    /// Loads the registry tree.
+    fn load(&self) -> Result<serde_json::Value>;
+
+    /// Loads a registry tree that is only guaranteed to be complete for
+    /// `paths`, their parents and their siblings.
+    ///
+    /// Backends that can cheaply produce the whole tree do so.
+    fn load_paths(&self, paths: &[Vec<String>]) -> Result<serde_json::Value> {
+        let _ = paths;
+        self.load()
+    }
+
+    /// Short description for diagnostics, such as `nix eval .#registry`.
+    fn describe(&self) -> String;
+}
+
+/// `nix eval` of the flake in the current directory.
+#[derive(Debug, Clone)]
+pub struct FlakeEval {
+    /// Registry name as written in source; see [`registry::flake_attr`].
+    pub name: String,
+}
+
+impl RegistrySource for FlakeEval {
+    fn load(&self) -> Result<serde_json::Value> {
+        registry::evaluate(&self.name, None)
+    }
+
+    fn load_paths(&self, paths: &[Vec<String>]) -> Result<serde_json::Value> {
+        registry::evaluate_paths(&self.name, None, paths)
+    }
+
+    fn describe(&self) -> String {
+        format!("nix eval .#{}", registry::flake_attr(&self.name))
+    }
+}
+
+/// `nix eval` of the flake as committed at a git ref.
+#[derive(Debug, Clone)]
+pub struct GitRefEval {
+    pub name: String,
+    pub git_ref: String,
+}
+
+impl RegistrySource for GitRefEval {
+    fn load(&self) -> Result<serde_json::Value> {
+        registry::evaluate(&self.name, Some(&self.git_ref))
+    }
+
+    fn load_paths(&self, paths: &[Vec<String>]) -> Result<serde_json::Value> {
+        registry::evaluate_paths(&self.name, Some(&self.git_ref), paths)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "nix eval .#{} at '{}'",
+            registry::flake_attr(&self.name),
+            self.git_ref
+        )
+    }
+}
+
+/// The registry directory mapped by imp's rules, from the working tree or a
+/// git ref; see [`registry::build`].
+#[derive(Debug, Clone)]
+pub struct FsLayout {
+    pub dir: PathBuf,
+    pub git_ref: Option<String>,
+}
+
+impl RegistrySource for FsLayout {
+    fn load(&self) -> Result<serde_json::Value> {
+        registry::build(&self.dir, self.git_ref.as_deref())
+    }
+
+    fn describe(&self) -> String {
+        match &self.git_ref {
+            Some(r) => format!("directory {} at '{}'", self.dir.display(), r),
+            None => format!("directory {}", self.dir.display()),
+        }
+    }
+}
+
+/// A JSON snapshot file, such as the saved output of
+/// `nix eval --json .#registry`.
+#[derive(Debug, Clone)]
+pub struct JsonFile {
+    pub path: PathBuf,
+}
+
+impl RegistrySource for JsonFile {
+    fn load(&self) -> Result<serde_json::Value> {
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read registry snapshot {}", self.path.display()))?;
+        parse_snapshot(&text)
+            .with_context(|| format!("Invalid registry snapshot {}", self.path.display()))
+    }
+
+    fn describe(&self) -> String {
+        format!("snapshot {}", self.path.display())
+    }
+}
+
+/// A JSON snapshot piped to standard input.
+#[derive(Debug, Clone, Copy)]
+pub struct Stdin;
+
+impl RegistrySource for Stdin {
+    fn load(&self) -> Result<serde_json::Value> {
+        let mut text = String::new();
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .context("Failed to read registry snapshot from stdin")?;
+        parse_snapshot(&text).context("Invalid registry snapshot on stdin")
+    }
+
+    fn describe(&self) -> String {
+        "snapshot on stdin".to_string()
+    }
+}
+
+/// Parses a snapshot, which must be a JSON object like the evaluated
+/// registry.
+fn parse_snapshot(text: &str) -> Result<serde_json::Value> {
+    let value: serde_json::Value =
+        serde_json::from_str(text).context("Failed to parse registry JSON")?;
+    if !value.is_object() {
+        anyhow::bail!("expected a JSON object at the top level");
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_file_loads_snapshot() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("registry.json");
+        std::fs::write(&path, r#"{ "home": { "alice": "/nix/store/x" } }"#).unwrap();
+        let reg = JsonFile { path }.load().unwrap();
+        let paths = registry::flatten_paths(&reg, "");
+        assert!(paths.contains("home.alice"));
+    }
+
+    #[test]
+    fn snapshot_must_be_an_object() {
+        assert!(parse_snapshot("[1, 2]").is_err());
+        assert!(parse_snapshot("{").is_err());
+        assert!(parse_snapshot("{}").is_ok());
+    }
+
+    #[test]
+    fn missing_snapshot_names_the_file() {
+        let source = JsonFile {
+            path: PathBuf::from("no/such/registry.json"),
+        };
+        let err = source.load().unwrap_err();
+        assert!(format!("{:#}", err).contains("no/such/registry.json"));
+    }
+}
@@ -0,0 +1,121 @@
+//! Line and column positions for byte offsets.
+//!
+//! References and parse errors are located by byte offset during extraction.
+//! A [`LineIndex`] records where each line starts, so converting an offset
+//! only scans the line it falls on instead of the whole file.
+//!
+//! Columns are given in three units: Unicode scalar values (what terminals
+//! and `file:line:col` output use), bytes, and UTF-16 code units (what LSP
+//! and SARIF consumers expect). They differ only on lines with non-ASCII text.
+
+use serde::{Deserialize, Serialize};
+
+/// A location in source text. Lines and columns are 1-indexed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    /// Column in Unicode scalar values.
+    pub column: usize,
+    /// Column in bytes.
+    pub byte_column: usize,
+    /// Column in UTF-16 code units.
+    pub utf16_column: usize,
+}
+
+/// A range of source text. `end` is the position just past the last
+/// character.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// Byte offsets of the start of every line in a source text.
+#[derive(Debug)]
+pub struct LineIndex<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    /// Position of the byte `offset`. Offsets past the end of the source are
+    /// clamped to it.
+    pub fn position(&self, offset: usize) -> Position {
+        let offset = offset.min(self.source.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let text = self
+            .source
+            .get(self.line_starts[line]..offset)
+            .unwrap_or_default();
+        Position {
+            line: line + 1,
+            column: text.chars().count() + 1,
+            byte_column: text.len() + 1,
+            utf16_column: text.encode_utf16().count() + 1,
+        }
+    }
+
+    /// Span of the byte range `start..end`.
+    pub fn span(&self, start: usize, end: usize) -> Span {
+        Span {
+            start: self.position(start),
+            end: self.position(end),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_across_lines() {
+        let index = LineIndex::new("ab\ncd\n\nef");
+        let pos = |offset| {
+            let p = index.position(offset);
+            (p.line, p.column)
+        };
+        assert_eq!(pos(0), (1, 1));
+        assert_eq!(pos(2), (1, 3));
+        assert_eq!(pos(3), (2, 1));
+        assert_eq!(pos(6), (3, 1));
+        assert_eq!(pos(8), (4, 2));
+        assert_eq!(pos(100), (4, 3));
+    }
+
+    #[test]
+    fn column_units_differ_on_non_ascii_lines() {
+        // `é` is 2 bytes and 1 UTF-16 unit, `𝕏` is 4 bytes and 2 UTF-16 units
+        let source = "x\n\"é𝕏\" registry.a";
+        let index = LineIndex::new(source);
+        let offset = source.find("registry").unwrap();
+        assert_eq!(
+            index.position(offset),
+            Position {
+                line: 2,
+                column: 6,
+                byte_column: 10,
+                utf16_column: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn span_end_is_exclusive() {
+        let source = "{ a = registry.users; }";
+        let start = source.find("registry").unwrap();
+        let span = LineIndex::new(source).span(start, start + "registry.users".len());
+        assert_eq!(span.start.column, 7);
+        assert_eq!(span.end.column, 21);
+    }
+}
@@ -0,0 +1,35 @@
+# Function parameter shadowing.
+# A local parameter or binding named `registry` shadows the module
+# argument. The scanner tracks lexical scopes, so selects on these are
+# reported as skipped ("local binding") rather than checked against the
+# imp registry.
+{ ... }:
+
+let
+  # Function with registry as a parameter
+  mkService =
+    { registry, port }:
+    {
+      url = registry.endpoint; # SKIPPED: registry.endpoint
+      config = registry.settings.base; # SKIPPED: registry.settings.base
+    };
+
+  # Lambda with registry parameter
+  processRegistry = registry: registry.data.items; # SKIPPED: registry.data.items
+
+  # Nested function with shadowing
+  outer =
+    { registry, ... }:
+    {
+      inner = registry.nested.value; # SKIPPED
+    };
+in
+{
+  # None of these refer to the imp registry
+  service = mkService {
+    registry = {
+      endpoint = "http://localhost";
+    };
+    port = 8080;
+  };
+}
@@ -31,6 +31,9 @@ let
 
   # Multi-line attribute access (should still work)
   multiLine = registry.deeply.nested.path;
+
+  # Alias through a destructured argument's default
+  mkShell = { users ? registry.users }: users.bob.shell;
 in
 {
   imports = [